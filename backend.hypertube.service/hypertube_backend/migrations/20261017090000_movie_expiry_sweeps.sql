@@ -0,0 +1,12 @@
+-- Add migration script here
+ALTER TABLE movie_torrent ADD COLUMN last_watched_at timestamptz NOT NULL DEFAULT NOW();
+
+CREATE TABLE movie_expiry_sweeps(
+  id uuid NOT NULL,
+  PRIMARY KEY (id),
+  started_at timestamptz NOT NULL,
+  finished_at timestamptz NOT NULL,
+  expired_count INT NOT NULL,
+  deleted_count INT NOT NULL,
+  errors TEXT[] NOT NULL
+);
@@ -0,0 +1,21 @@
+-- Add migration script here
+CREATE TYPE transcode_job_status AS ENUM ('QUEUED', 'RUNNING', 'DONE', 'FAILED');
+
+CREATE TABLE transcode_jobs(
+  id uuid NOT NULL,
+  PRIMARY KEY (id),
+  movie_source movie_source_type NOT NULL,
+  movie_id VARCHAR(30) NOT NULL,
+  torrent_id INT NOT NULL,
+  input_path TEXT NOT NULL,
+  output_dir TEXT NOT NULL,
+  output_path TEXT,
+  status transcode_job_status NOT NULL DEFAULT 'QUEUED',
+  progress REAL NOT NULL DEFAULT 0,
+  error TEXT,
+  run_after timestamptz NOT NULL DEFAULT NOW(),
+  created_at timestamptz NOT NULL,
+  updated_at timestamptz NOT NULL
+);
+
+ALTER TABLE movie_torrent ADD COLUMN transcoded_path TEXT;
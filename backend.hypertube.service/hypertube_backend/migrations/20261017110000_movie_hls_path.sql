@@ -0,0 +1,2 @@
+-- Add migration script here
+ALTER TABLE movie_torrent ADD COLUMN hls_path TEXT;
@@ -0,0 +1,10 @@
+-- Add migration script here
+-- keep the oldest row of movies that were started more than once
+DELETE FROM movie_torrent newer
+USING movie_torrent older
+WHERE newer.movie_source = older.movie_source
+  AND newer.movie_id = older.movie_id
+  AND (newer.created_at, newer.id) > (older.created_at, older.id);
+
+ALTER TABLE movie_torrent
+  ADD CONSTRAINT movie_torrent_source_movie_key UNIQUE (movie_source, movie_id);
@@ -0,0 +1,5 @@
+-- Add migration script here
+-- the file of a multi-file torrent that is streamed
+ALTER TABLE movie_torrent ADD COLUMN file_index INT;
+ALTER TABLE movie_torrent ADD COLUMN file_name TEXT;
+ALTER TABLE movie_torrent ADD COLUMN file_size BIGINT;
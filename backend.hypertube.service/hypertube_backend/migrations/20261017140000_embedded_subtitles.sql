@@ -0,0 +1,15 @@
+-- Add migration script here
+-- text subtitle tracks extracted from the downloaded video as WebVTT
+CREATE TABLE embedded_subtitles(
+  id uuid NOT NULL,
+  PRIMARY KEY (id),
+  movie_source movie_source_type NOT NULL,
+  movie_id VARCHAR(30) NOT NULL,
+  stream_index INT NOT NULL,
+  language TEXT NOT NULL,
+  title TEXT,
+  codec TEXT NOT NULL,
+  path TEXT NOT NULL,
+  created_at timestamptz NOT NULL DEFAULT NOW(),
+  UNIQUE (movie_source, movie_id, stream_index)
+);
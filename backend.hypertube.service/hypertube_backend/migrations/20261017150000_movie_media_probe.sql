@@ -0,0 +1,14 @@
+-- Add migration script here
+CREATE TYPE playback_mode AS ENUM ('DIRECT_PLAY', 'REMUX', 'TRANSCODE');
+
+-- what ffprobe found in the downloaded file
+ALTER TABLE movie_torrent ADD COLUMN container TEXT;
+ALTER TABLE movie_torrent ADD COLUMN video_codec TEXT;
+ALTER TABLE movie_torrent ADD COLUMN audio_codec TEXT;
+ALTER TABLE movie_torrent ADD COLUMN duration_seconds DOUBLE PRECISION;
+ALTER TABLE movie_torrent ADD COLUMN width INT;
+ALTER TABLE movie_torrent ADD COLUMN height INT;
+ALTER TABLE movie_torrent ADD COLUMN bit_rate BIGINT;
+ALTER TABLE movie_torrent ADD COLUMN audio_tracks JSON;
+ALTER TABLE movie_torrent ADD COLUMN playback_mode playback_mode;
+ALTER TABLE movie_torrent ADD COLUMN probed_at timestamptz;
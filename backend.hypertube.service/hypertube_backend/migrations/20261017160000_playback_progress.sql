@@ -0,0 +1,17 @@
+-- Add migration script here
+-- where each user stopped in the movies they haven't finished
+CREATE TABLE playback_progress(
+  user_id uuid NOT NULL,
+  movie_source movie_source_type NOT NULL,
+  movie_id VARCHAR(50) NOT NULL,
+  position_seconds DOUBLE PRECISION NOT NULL,
+  duration_seconds DOUBLE PRECISION,
+  title TEXT NOT NULL,
+  poster_src TEXT NOT NULL,
+  movie_imdb_code VARCHAR(50),
+  updated_at timestamptz NOT NULL,
+  PRIMARY KEY (user_id, movie_source, movie_id),
+  FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+
+CREATE INDEX playback_progress_recent ON playback_progress (user_id, updated_at DESC);
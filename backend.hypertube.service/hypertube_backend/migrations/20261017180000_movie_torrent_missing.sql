@@ -0,0 +1,3 @@
+-- Add migration script here
+-- set by the reconciliation when the movie file is gone from the disk and the torrent client
+ALTER TABLE movie_torrent ADD COLUMN missing_since timestamptz;
@@ -1,10 +1,12 @@
 use dotenv::dotenv;
 use hypertube_backend::configuration::get_configuration;
+use hypertube_backend::routes::movies::torrent::RqbitWrapper;
 use hypertube_backend::startup;
 use hypertube_backend::telemetry::{get_subscriber, init_subscriber};
 use hypertube_backend::util::check_for_necessary_env;
 use sqlx::PgPool;
 use std::net::TcpListener;
+use std::sync::Arc;
 // use std::process::{Command, Stdio};
 
 #[actix_web::main]
@@ -36,5 +38,7 @@ async fn main() -> std::io::Result<()> {
         .expect("Failed to connect to database");
     // m.run(&connection_pool).await;
 
-    startup::run_server(listener, connection_pool)?.await
+    let torrent_client = Arc::new(RqbitWrapper::from_env()?);
+
+    startup::run_server(listener, connection_pool, torrent_client)?.await
 }
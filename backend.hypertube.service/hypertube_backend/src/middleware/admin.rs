@@ -0,0 +1,116 @@
+use actix_web::{
+    body::EitherBody,
+    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
+    Error, HttpMessage, HttpResponse,
+};
+
+use futures_util::{future::LocalBoxFuture, FutureExt};
+
+use serde_json::json;
+use sqlx::{PgPool, Row};
+use std::{
+    future::{ready, Ready},
+    rc::Rc,
+};
+
+use super::User;
+
+// Lets only admins through, has to sit inside `Authentication` which puts the
+// user in the request extensions.
+pub struct AdminOnly {
+    db_pool: PgPool,
+}
+
+impl AdminOnly {
+    pub fn new(db_pool: PgPool) -> Self {
+        AdminOnly { db_pool }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for AdminOnly
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = AdminOnlyMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(AdminOnlyMiddleware {
+            service: Rc::new(service),
+            db_pool: self.db_pool.clone(),
+        }))
+    }
+}
+
+pub struct AdminOnlyMiddleware<S> {
+    service: Rc<S>,
+    db_pool: PgPool,
+}
+
+impl<S, B> Service<ServiceRequest> for AdminOnlyMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+    type Error = Error;
+    type Response = ServiceResponse<EitherBody<B>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let user_id = req.extensions().get::<Rc<User>>().map(|user| user.id);
+        let db_connection = self.db_pool.clone();
+        let service = self.service.clone();
+        async move {
+            let user_id = match user_id {
+                Some(user_id) => user_id,
+                None => {
+                    tracing::error!("Admin route reached without a user");
+                    let http_res = HttpResponse::Unauthorized().json(json!({
+                        "Error" : "user not found"
+                    }));
+                    let (http_req, _) = req.into_parts();
+                    let response = ServiceResponse::new(http_req, http_res);
+                    return Ok(response.map_into_right_body());
+                }
+            };
+
+            let is_admin = match sqlx::query("SELECT is_admin FROM users WHERE id = $1")
+                .bind(user_id)
+                .fetch_one(&db_connection)
+                .await
+            {
+                Ok(row) => row.get::<bool, &str>("is_admin"),
+                Err(err) => {
+                    tracing::error!("Database error {}", err);
+                    let http_res = HttpResponse::BadRequest().json(json!({
+                        "Error" : "Database Error"
+                    }));
+                    let (http_req, _) = req.into_parts();
+                    let response = ServiceResponse::new(http_req, http_res);
+                    return Ok(response.map_into_right_body());
+                }
+            };
+            if !is_admin {
+                tracing::warn!("User {} is not an admin", user_id);
+                let http_res = HttpResponse::Forbidden().json(json!({
+                    "Error" : "Admin access required"
+                }));
+                let (http_req, _) = req.into_parts();
+                let response = ServiceResponse::new(http_req, http_res);
+                return Ok(response.map_into_right_body());
+            }
+
+            let res: ServiceResponse<B> = service.call(req).await?;
+            Ok(res.map_into_left_body())
+        }
+        .boxed_local()
+    }
+}
@@ -1,3 +1,5 @@
+pub mod admin;
 pub mod authentication;
 
+pub use admin::*;
 pub use authentication::*;
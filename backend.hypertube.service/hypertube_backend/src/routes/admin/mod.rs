@@ -0,0 +1,5 @@
+mod torrents;
+mod util;
+
+pub use torrents::*;
+pub use util::*;
@@ -0,0 +1,313 @@
+use actix_web::{
+    web::{Data, Json, Path},
+    HttpResponse,
+};
+use chrono::{DateTime, Utc};
+use futures_util::future::join_all;
+use serde::Deserialize;
+use serde_json::json;
+use sqlx::{PgPool, Row};
+use std::path::{Path as FsPath, PathBuf};
+use uuid::Uuid;
+
+use crate::routes::movies::torrent::TorrentClient;
+use crate::routes::movies::transcode::{remove_transcoded_output, TranscodeQueue};
+use crate::routes::{reconcile, InFlightDownloads, Source};
+
+#[derive(Deserialize)]
+pub struct AdminTorrentInfo {
+    pub source: Source,
+    pub movie_id: String,
+}
+
+#[derive(Deserialize)]
+pub struct TorrentFilesUpdate {
+    // indexes of the files to keep downloading, rqbit has no finer priorities
+    pub only_files: Vec<usize>,
+}
+
+struct TorrentRecord {
+    id: Uuid,
+    torrent_id: i32,
+    movie_path: String,
+}
+
+async fn find_torrent(
+    connection: &PgPool,
+    info: &AdminTorrentInfo,
+) -> Result<TorrentRecord, HttpResponse> {
+    match sqlx::query(
+        r#"
+            SELECT id, torrent_id, movie_path FROM movie_torrent WHERE movie_id = $1 AND movie_source = $2
+        "#,
+    )
+    .bind(info.movie_id.clone())
+    .bind(info.source.clone() as Source)
+    .fetch_optional(connection)
+    .await
+    {
+        Ok(Some(row)) => Ok(TorrentRecord {
+            id: row.get("id"),
+            torrent_id: row.get("torrent_id"),
+            movie_path: row.get("movie_path"),
+        }),
+        Ok(None) => Err(HttpResponse::NotFound().json(json!({
+            "error": "Torrent not found"
+        }))),
+        Err(err) => {
+            tracing::error!("Database Error {:#?}", err);
+            Err(HttpResponse::BadRequest().json(json!({
+                "error": "Database error"
+            })))
+        }
+    }
+}
+
+// every movie torrent with what the torrent client currently says about it
+pub async fn list_torrents(
+    connection: Data<PgPool>,
+    torrent_client: Data<dyn TorrentClient>,
+) -> HttpResponse {
+    let rows = match sqlx::query(
+        r#"
+            SELECT * FROM movie_torrent ORDER BY created_at DESC
+        "#,
+    )
+    .fetch_all(connection.as_ref())
+    .await
+    {
+        Ok(rows) => rows,
+        Err(err) => {
+            tracing::error!("Database Error {:#?}", err);
+            return HttpResponse::BadRequest().json(json!({
+                "error": "Database error"
+            }));
+        }
+    };
+
+    // one request per torrent, all in flight at once
+    let statuses = join_all(
+        rows.iter()
+            .map(|row| torrent_client.torrent_status(row.get::<i32, &str>("torrent_id"))),
+    )
+    .await;
+
+    let mut torrents = Vec::with_capacity(rows.len());
+    for (row, status) in rows.iter().zip(statuses) {
+        let torrent_id = row.get::<i32, &str>("torrent_id");
+        // a torrent rqbit lost is still listed so it can be deleted
+        let (status, client_error) = match status {
+            Ok(status) => (Some(status), None),
+            Err(err) => (None, Some(err)),
+        };
+        torrents.push(json!({
+            "movie_id": row.get::<String, &str>("movie_id"),
+            "source": row.get::<Source, &str>("movie_source").to_string(),
+            "torrent_id": torrent_id,
+            "movie_path": row.get::<String, &str>("movie_path"),
+            "file_size": row.get::<Option<i64>, &str>("file_size"),
+            "transcoded": row.get::<Option<String>, &str>("transcoded_path").is_some(),
+            "created_at": row.get::<DateTime<Utc>, &str>("created_at"),
+            "last_watched_at": row.get::<DateTime<Utc>, &str>("last_watched_at"),
+            "missing_since": row.get::<Option<DateTime<Utc>>, &str>("missing_since"),
+            "download_state": status.as_ref().map(|status| status.download_state()),
+            "status": status,
+            "client_error": client_error,
+        }));
+    }
+    HttpResponse::Ok().json(json!({ "data": torrents }))
+}
+
+fn client_response(result: Result<(), String>) -> HttpResponse {
+    match result {
+        Ok(_) => HttpResponse::Ok().finish(),
+        Err(err) => {
+            tracing::error!("Torrent client error {}", err);
+            HttpResponse::BadGateway().json(json!({
+                "error": err
+            }))
+        }
+    }
+}
+
+pub async fn pause_torrent(
+    info: Path<AdminTorrentInfo>,
+    connection: Data<PgPool>,
+    torrent_client: Data<dyn TorrentClient>,
+) -> HttpResponse {
+    match find_torrent(connection.as_ref(), &info).await {
+        Ok(record) => client_response(torrent_client.pause_torrent(record.torrent_id).await),
+        Err(response) => response,
+    }
+}
+
+pub async fn resume_torrent(
+    info: Path<AdminTorrentInfo>,
+    connection: Data<PgPool>,
+    torrent_client: Data<dyn TorrentClient>,
+) -> HttpResponse {
+    match find_torrent(connection.as_ref(), &info).await {
+        Ok(record) => client_response(torrent_client.resume_torrent(record.torrent_id).await),
+        Err(response) => response,
+    }
+}
+
+pub async fn reannounce_torrent(
+    info: Path<AdminTorrentInfo>,
+    connection: Data<PgPool>,
+    torrent_client: Data<dyn TorrentClient>,
+) -> HttpResponse {
+    match find_torrent(connection.as_ref(), &info).await {
+        Ok(record) => client_response(torrent_client.reannounce_torrent(record.torrent_id).await),
+        Err(response) => response,
+    }
+}
+
+pub async fn get_torrent_files(
+    info: Path<AdminTorrentInfo>,
+    connection: Data<PgPool>,
+    torrent_client: Data<dyn TorrentClient>,
+) -> HttpResponse {
+    let record = match find_torrent(connection.as_ref(), &info).await {
+        Ok(record) => record,
+        Err(response) => return response,
+    };
+    match torrent_client.list_files(record.torrent_id).await {
+        Ok(files) => HttpResponse::Ok().json(json!({ "data": files })),
+        Err(err) => {
+            tracing::error!("Torrent client error {}", err);
+            HttpResponse::BadGateway().json(json!({
+                "error": err
+            }))
+        }
+    }
+}
+
+pub async fn update_torrent_files(
+    info: Path<AdminTorrentInfo>,
+    body: Json<TorrentFilesUpdate>,
+    connection: Data<PgPool>,
+    torrent_client: Data<dyn TorrentClient>,
+) -> HttpResponse {
+    let record = match find_torrent(connection.as_ref(), &info).await {
+        Ok(record) => record,
+        Err(response) => return response,
+    };
+    let files = match torrent_client.list_files(record.torrent_id).await {
+        Ok(files) => files,
+        Err(err) => {
+            tracing::error!("Torrent client error {}", err);
+            return HttpResponse::BadGateway().json(json!({
+                "error": err
+            }));
+        }
+    };
+    if body.only_files.is_empty() || body.only_files.iter().any(|index| *index >= files.len()) {
+        return HttpResponse::BadRequest().json(json!({
+            "error": "Invalid file selection"
+        }));
+    }
+    // the movie is streamed from this file, it can't be dropped
+    if let Some(feature) = files
+        .iter()
+        .find(|file| record.movie_path.ends_with(file.name.as_str()))
+    {
+        if !body.only_files.contains(&feature.index) {
+            return HttpResponse::BadRequest().json(json!({
+                "error": "The movie file can't be excluded"
+            }));
+        }
+    }
+    client_response(
+        torrent_client
+            .update_files(record.torrent_id, body.only_files.clone())
+            .await,
+    )
+}
+
+// The folder of the movie file, only when it resolves strictly inside the
+// download root. Relative paths are relative to the root, not the process.
+async fn movie_folder(download_root: &FsPath, movie_path: &str) -> Option<PathBuf> {
+    let root = tokio::fs::canonicalize(download_root).await.ok()?;
+    let folder = download_root.join(movie_path).parent()?.to_path_buf();
+    let folder = tokio::fs::canonicalize(folder).await.ok()?;
+    (folder != root && folder.starts_with(&root)).then_some(folder)
+}
+
+// Removes the torrent even when the client doesn't know it anymore, the files
+// and the database rows go either way.
+pub async fn force_delete_torrent(
+    info: Path<AdminTorrentInfo>,
+    connection: Data<PgPool>,
+    torrent_client: Data<dyn TorrentClient>,
+) -> HttpResponse {
+    let info = info.into_inner();
+    let record = match find_torrent(connection.as_ref(), &info).await {
+        Ok(record) => record,
+        Err(response) => return response,
+    };
+
+    if let Err(err) = torrent_client
+        .delete_torrent(record.torrent_id, record.movie_path.clone())
+        .await
+    {
+        tracing::warn!(
+            "Torrent client failed to delete {}, removing the files anyway: {}",
+            record.torrent_id,
+            err
+        );
+        let download_root = torrent_client.download_path();
+        if let Some(folder) =
+            movie_folder(download_root.as_path(), record.movie_path.as_str()).await
+        {
+            if let Err(err) = tokio::fs::remove_dir_all(folder).await {
+                tracing::error!("Failed to delete dir {:#?}", err);
+            }
+        }
+    }
+
+    if let Err(err) =
+        remove_transcoded_output(connection.as_ref(), info.movie_id, info.source).await
+    {
+        tracing::error!("Failed to remove transcoded movie {:#?}", err);
+    }
+
+    match sqlx::query(
+        r#"
+            DELETE FROM movie_torrent WHERE id = $1
+        "#,
+    )
+    .bind(record.id)
+    .execute(connection.as_ref())
+    .await
+    {
+        Ok(_) => {
+            tracing::info!("Movie record deleted from the database");
+            HttpResponse::Ok().finish()
+        }
+        Err(err) => {
+            tracing::error!("Database Error {:#?}", err);
+            HttpResponse::BadRequest().json(json!({
+                "error": "Database error"
+            }))
+        }
+    }
+}
+
+// the startup reconciliation, run again on request
+pub async fn reconcile_torrents(
+    connection: Data<PgPool>,
+    torrent_client: Data<dyn TorrentClient>,
+    in_flight_downloads: Data<InFlightDownloads>,
+    transcode_queue: Data<TranscodeQueue>,
+) -> HttpResponse {
+    let report = reconcile(
+        connection.as_ref(),
+        torrent_client.get_ref(),
+        in_flight_downloads.as_ref(),
+        transcode_queue.as_ref(),
+    )
+    .await;
+    tracing::info!("Reconciliation finished {:?}", report);
+    HttpResponse::Ok().json(json!({ "data": report }))
+}
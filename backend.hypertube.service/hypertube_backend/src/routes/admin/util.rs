@@ -0,0 +1,42 @@
+use actix_web::{dev::HttpServiceFactory, web};
+use sqlx::PgPool;
+
+use crate::middleware::{AdminOnly, Authentication};
+
+use super::{
+    force_delete_torrent, get_torrent_files, list_torrents, pause_torrent, reannounce_torrent,
+    reconcile_torrents, resume_torrent, update_torrent_files,
+};
+
+// `AdminOnly` needs the user `Authentication` finds, the last wrap runs first
+pub fn admin_source(db_pool: &PgPool) -> impl HttpServiceFactory {
+    web::scope("/admin")
+        .route("/torrents", web::get().to(list_torrents))
+        .route("/torrents/reconcile", web::post().to(reconcile_torrents))
+        .route(
+            "/torrents/{source}/{movie_id}",
+            web::delete().to(force_delete_torrent),
+        )
+        .route(
+            "/torrents/{source}/{movie_id}/files",
+            web::get().to(get_torrent_files),
+        )
+        .route(
+            "/torrents/{source}/{movie_id}/files",
+            web::put().to(update_torrent_files),
+        )
+        .route(
+            "/torrents/{source}/{movie_id}/pause",
+            web::post().to(pause_torrent),
+        )
+        .route(
+            "/torrents/{source}/{movie_id}/resume",
+            web::post().to(resume_torrent),
+        )
+        .route(
+            "/torrents/{source}/{movie_id}/reannounce",
+            web::post().to(reannounce_torrent),
+        )
+        .wrap(AdminOnly::new(db_pool.clone()))
+        .wrap(Authentication::new(db_pool.clone()))
+}
@@ -1,3 +1,4 @@
+pub mod admin;
 pub mod comments;
 pub mod hello_world;
 pub mod movies;
@@ -5,6 +6,7 @@ pub mod subtitles;
 pub mod password_rest;
 pub mod user;
 
+pub use admin::*;
 pub use comments::*;
 pub use hello_world::*;
 pub use movies::*;
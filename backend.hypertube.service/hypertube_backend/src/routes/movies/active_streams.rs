@@ -0,0 +1,77 @@
+use actix_web::web::Data;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use tokio::time::{Duration, Instant};
+
+// HLS players fetch a segment every few seconds, a movie counts as watched
+// for this long after its last request
+const ACTIVE_STREAM_GRACE: Duration = Duration::from_secs(120);
+
+struct StreamActivity {
+    open_responses: usize,
+    last_seen: Instant,
+}
+
+// Which movies are being played right now, keyed by `CronJobScheduler::build_job_id`
+pub struct ActiveStreams {
+    streams: Mutex<HashMap<String, StreamActivity>>,
+}
+
+impl Default for ActiveStreams {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ActiveStreams {
+    pub fn new() -> Self {
+        Self {
+            streams: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn touch(&self, job_id: &str) {
+        let mut streams = self.streams.lock().unwrap();
+        streams
+            .entry(job_id.to_string())
+            .and_modify(|activity| activity.last_seen = Instant::now())
+            .or_insert(StreamActivity {
+                open_responses: 0,
+                last_seen: Instant::now(),
+            });
+    }
+
+    // the movie stays active until the returned guard is dropped
+    pub fn open(streams: &Data<ActiveStreams>, job_id: String) -> StreamGuard {
+        streams.touch(job_id.as_str());
+        if let Some(activity) = streams.streams.lock().unwrap().get_mut(&job_id) {
+            activity.open_responses += 1;
+        }
+        StreamGuard {
+            streams: streams.clone(),
+            job_id,
+        }
+    }
+
+    pub fn is_active(&self, job_id: &str) -> bool {
+        let mut streams = self.streams.lock().unwrap();
+        streams.retain(|_, activity| {
+            activity.open_responses > 0 || activity.last_seen.elapsed() < ACTIVE_STREAM_GRACE
+        });
+        streams.contains_key(job_id)
+    }
+}
+
+pub struct StreamGuard {
+    streams: Data<ActiveStreams>,
+    job_id: String,
+}
+
+impl Drop for StreamGuard {
+    fn drop(&mut self) {
+        if let Some(activity) = self.streams.streams.lock().unwrap().get_mut(&self.job_id) {
+            activity.open_responses = activity.open_responses.saturating_sub(1);
+            activity.last_seen = Instant::now();
+        }
+    }
+}
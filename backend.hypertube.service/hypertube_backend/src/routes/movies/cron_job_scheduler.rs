@@ -1,112 +1,221 @@
-use super::torrent::RqbitWrapper;
+use super::torrent::TorrentClient;
+use super::transcode::remove_transcoded_output;
 use super::Source;
 use actix_web::web::Data;
+use chrono::{DateTime, Utc};
 use sqlx::{PgPool, Row};
-use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
+use std::env;
 use tokio::task::JoinHandle;
 use tokio::time::{self, Duration};
+use uuid::Uuid;
 
+const DEFAULT_EXPIRE_AFTER_DAYS: u64 = 30;
+const DEFAULT_SWEEP_INTERVAL_SECS: u64 = 3600;
+
+// Movies nobody watched for `expire_after` are removed by a single sweeper
+// that runs every `sweep_interval`. Everything it needs lives in
+// `movie_torrent.last_watched_at`, so restarts don't lose pending deletions.
 pub struct CronJobScheduler {
-    scheduled_jobs: Arc<Mutex<HashMap<String, JoinHandle<()>>>>,
+    expire_after: Duration,
+    sweep_interval: Duration,
+}
+
+#[derive(Debug, Default)]
+pub struct SweepReport {
+    pub expired: i32,
+    pub deleted: i32,
+    pub errors: Vec<String>,
 }
 
 impl Default for CronJobScheduler {
     fn default() -> Self {
-        Self::new()
+        Self::new(
+            Duration::from_secs(DEFAULT_EXPIRE_AFTER_DAYS * 24 * 3600),
+            Duration::from_secs(DEFAULT_SWEEP_INTERVAL_SECS),
+        )
     }
 }
 
 impl CronJobScheduler {
-    pub fn new() -> Self {
+    pub fn new(expire_after: Duration, sweep_interval: Duration) -> Self {
         Self {
-            scheduled_jobs: Arc::new(Mutex::new(HashMap::new())),
+            expire_after,
+            sweep_interval,
         }
     }
+
+    // MOVIE_EXPIRE_AFTER_DAYS and MOVIE_SWEEP_INTERVAL_SECS are optional
+    pub fn from_env() -> Self {
+        let read_var = |name: &str, default: u64| {
+            env::var(name)
+                .ok()
+                .and_then(|value| value.parse::<u64>().ok())
+                .unwrap_or(default)
+        };
+        Self::new(
+            Duration::from_secs(
+                read_var("MOVIE_EXPIRE_AFTER_DAYS", DEFAULT_EXPIRE_AFTER_DAYS) * 24 * 3600,
+            ),
+            Duration::from_secs(read_var(
+                "MOVIE_SWEEP_INTERVAL_SECS",
+                DEFAULT_SWEEP_INTERVAL_SECS,
+            )),
+        )
+    }
+
     pub fn build_job_id(movie_id: String, source: Source) -> String {
         format!("{}_{}", movie_id, source)
     }
-}
 
-async fn delete_movie_handler(
-    job_list: Data<CronJobScheduler>,
-    job_id: String,
-    connection: Data<PgPool>,
-) {
-    let interval = time::interval(Duration::from_secs(2_628_000)); // one month in seconds
-    let mut interval = interval;
+    pub fn start(
+        scheduler: Data<CronJobScheduler>,
+        connection: Data<PgPool>,
+        torrent_client: Data<dyn TorrentClient>,
+    ) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut interval = time::interval(scheduler.sweep_interval);
+            loop {
+                interval.tick().await;
+                let report = scheduler
+                    .sweep(connection.as_ref(), torrent_client.get_ref())
+                    .await;
+                tracing::info!("Movie expiry sweep finished {:?}", report);
+            }
+        })
+    }
 
-    interval.tick().await;
-    interval.tick().await;
+    pub async fn sweep(
+        &self,
+        connection: &PgPool,
+        torrent_client: &dyn TorrentClient,
+    ) -> SweepReport {
+        let started_at = Utc::now();
+        let cutoff = match chrono::Duration::from_std(self.expire_after) {
+            Ok(expire_after) => started_at - expire_after,
+            Err(err) => {
+                tracing::error!("Invalid movie expiry period {}", err);
+                return SweepReport::default();
+            }
+        };
+        let mut report = SweepReport::default();
 
-    {
-        let mut job_list = job_list.scheduled_jobs.lock().unwrap();
-        job_list.remove(&job_id);
-    }
+        let expired = match sqlx::query(
+            r#"
+                SELECT id FROM movie_torrent WHERE last_watched_at < $1
+            "#,
+        )
+        .bind(cutoff)
+        .fetch_all(connection)
+        .await
+        {
+            Ok(rows) => rows,
+            Err(err) => {
+                tracing::error!("Database Error Failed to fetch expired movies {}", err);
+                report.errors.push(err.to_string());
+                Self::record(connection, started_at, &report).await;
+                return report;
+            }
+        };
+        report.expired = expired.len() as i32;
 
-    let movie_identifier: Vec<String> = job_id.split('_').map(|s| s.to_string()).collect();
-    // let src: Source = movie_identifier[1];
-    let (torrent_id, torrent_path) = match sqlx::query(
-        r#"
-            DELETE FROM movie_torrent WHERE movie_id = $1 AND movie_source = $2
-            RETURNING *
-        "#,
-    )
-    .bind(movie_identifier[0].parse::<i32>().unwrap())
-    .bind::<Source>(movie_identifier[1].clone().into())
-    .fetch_one(connection.as_ref())
-    .await
-    {
-        Ok(row) => {
-            tracing::info!("Deleted movie record form database successfully {}", job_id);
-            (row.get("torrent_id"), row.get("movie_path"))
-        }
-        Err(err) => {
-            tracing::error!("Database Error Failed to delete Movie {err}");
-            return;
+        for row in expired {
+            let record_id: Uuid = row.get("id");
+            // the movie may have been watched since the select, only claim it if it's still stale
+            match evict_movie(connection, torrent_client, record_id, Some(cutoff)).await {
+                Ok(true) => {
+                    tracing::info!("Deleted expired movie {}", record_id);
+                    report.deleted += 1;
+                }
+                Ok(false) => {}
+                Err(err) => report.errors.push(err),
+            }
         }
-    };
-    let torrent_client = RqbitWrapper::default();
-    match torrent_client
-        .delete_torrent(torrent_id, torrent_path)
-        .await
-    {
-        Ok(_) => tracing::info!("Deleted Movie from file system"),
-        Err(err) => tracing::error!("Cant delete Movie form file system: {}", err),
-    };
-}
 
-pub async fn schedule_handler<'a>(
-    job_list: &'a Data<CronJobScheduler>,
-    job_id: String,
-    connection: &'a Data<PgPool>,
-) -> Result<(), Box<dyn std::error::Error + 'a>> {
-    let job_handle = tokio::spawn(delete_movie_handler(
-        job_list.clone(),
-        job_id.clone(),
-        connection.clone(),
-    ));
-    {
-        let mut jobs = job_list.scheduled_jobs.lock()?;
-        jobs.insert(job_id, job_handle);
+        Self::record(connection, started_at, &report).await;
+        report
+    }
+
+    async fn record(connection: &PgPool, started_at: DateTime<Utc>, report: &SweepReport) {
+        if let Err(err) = sqlx::query(
+            r#"
+                INSERT INTO movie_expiry_sweeps (id, started_at, finished_at, expired_count, deleted_count, errors)
+                VALUES ($1, $2, $3, $4, $5, $6)
+            "#,
+        )
+        .bind(Uuid::new_v4())
+        .bind(started_at)
+        .bind(Utc::now())
+        .bind(report.expired)
+        .bind(report.deleted)
+        .bind(&report.errors)
+        .execute(connection)
+        .await
+        {
+            tracing::error!("Database Error Failed to record expiry sweep {}", err);
+        }
     }
-    tracing::debug!(
-        "Movie scheduled for deletion in : {:#?}",
-        Duration::from_secs(60)
-    );
+}
 
+pub async fn mark_movie_watched(
+    connection: &PgPool,
+    movie_id: String,
+    source: Source,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        r#"
+            UPDATE movie_torrent SET last_watched_at = $1 WHERE movie_id = $2 AND movie_source = $3
+        "#,
+    )
+    .bind(Utc::now())
+    .bind(movie_id)
+    .bind(source as Source)
+    .execute(connection)
+    .await?;
     Ok(())
 }
 
-pub async fn cancel_job<'a>(
-    job_list: &'a mut Data<CronJobScheduler>,
-    job_id: String,
-) -> Result<(), Box<dyn std::error::Error + 'a>> {
-    let mut jobs = job_list.scheduled_jobs.lock()?;
-    if let Some(job_handle) = jobs.remove(&job_id) {
-        tracing::debug!("REMOVED JOB HANDLER FOR JOB: {}", job_id);
-        job_handle.abort();
+// Removes a movie row, its transcoded renditions and the torrent. With
+// `stale_before` set the movie is only removed if nobody watched it since,
+// returns whether it was removed.
+pub async fn evict_movie(
+    connection: &PgPool,
+    torrent_client: &dyn TorrentClient,
+    record_id: Uuid,
+    stale_before: Option<DateTime<Utc>>,
+) -> Result<bool, String> {
+    let (torrent_id, movie_path, movie_id, source): (i32, String, String, Source) =
+        match sqlx::query(
+            r#"
+                DELETE FROM movie_torrent
+                WHERE id = $1 AND ($2::timestamptz IS NULL OR last_watched_at < $2)
+                RETURNING torrent_id, movie_path, movie_id, movie_source
+            "#,
+        )
+        .bind(record_id)
+        .bind(stale_before)
+        .fetch_optional(connection)
+        .await
+        {
+            Ok(Some(row)) => (
+                row.get("torrent_id"),
+                row.get("movie_path"),
+                row.get("movie_id"),
+                row.get("movie_source"),
+            ),
+            Ok(None) => return Ok(false),
+            Err(err) => {
+                tracing::error!("Database Error Failed to delete Movie {err}");
+                return Err(err.to_string());
+            }
+        };
+    if let Err(err) = remove_transcoded_output(connection, movie_id, source).await {
+        tracing::error!("Failed to remove transcoded movie {}", err);
+    }
+    match torrent_client.delete_torrent(torrent_id, movie_path).await {
+        Ok(_) => Ok(true),
+        Err(err) => {
+            tracing::error!("Cant delete Movie form file system: {}", err);
+            Err(format!("torrent {}: {}", torrent_id, err))
+        }
     }
-
-    Ok(())
 }
@@ -8,17 +8,22 @@ use sqlx::{PgPool, Row};
 use tracing::Instrument;
 use uuid::Uuid;
 
-use crate::routes::movies::torrent::RqbitWrapper;
+use crate::routes::movies::torrent::TorrentClient;
+use crate::routes::movies::transcode::remove_transcoded_output;
 
 use super::Source;
 
 #[derive(Deserialize)]
 pub struct MovieData {
-    pub movie_id: i32,
+    pub movie_id: String,
     pub source: Source,
 }
 
-pub async fn delete_torrent(movie_data: Path<MovieData>, connection: Data<PgPool>) -> HttpResponse {
+pub async fn delete_torrent(
+    movie_data: Path<MovieData>,
+    connection: Data<PgPool>,
+    torrent_client: Data<dyn TorrentClient>,
+) -> HttpResponse {
     let query_span = tracing::info_span!("Deleting torrent");
     let movie_info = movie_data.into_inner();
 
@@ -27,8 +32,8 @@ pub async fn delete_torrent(movie_data: Path<MovieData>, connection: Data<PgPool
       SELECT * FROM movie_torrent WHERE movie_id = $1 AND movie_source = $2
     "#,
     )
-    .bind(movie_info.movie_id)
-    .bind(movie_info.source as Source)
+    .bind(movie_info.movie_id.clone())
+    .bind(movie_info.source.clone() as Source)
     .fetch_one(connection.as_ref())
     .instrument(query_span.clone())
     .await
@@ -53,7 +58,6 @@ pub async fn delete_torrent(movie_data: Path<MovieData>, connection: Data<PgPool
         }
     };
 
-    let torrent_client = RqbitWrapper::default();
     match torrent_client.delete_torrent(torrent_id, movie_path).await {
         Ok(_) => {}
         Err(err) => {
@@ -63,6 +67,16 @@ pub async fn delete_torrent(movie_data: Path<MovieData>, connection: Data<PgPool
         }
     }
 
+    if let Err(err) = remove_transcoded_output(
+        connection.as_ref(),
+        movie_info.movie_id,
+        movie_info.source,
+    )
+    .await
+    {
+        tracing::error!("Failed to remove transcoded movie {:#?}", err);
+    }
+
     match sqlx::query(
         r#"
         DELETE FROM movie_torrent WHERE id = $1
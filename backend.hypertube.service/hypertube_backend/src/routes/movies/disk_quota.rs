@@ -0,0 +1,193 @@
+use sqlx::{types::Uuid, PgPool, Row};
+use std::collections::HashSet;
+use std::env;
+use std::ffi::CString;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use tokio::sync::Mutex;
+
+use super::active_streams::ActiveStreams;
+use super::torrent::TorrentClient;
+use super::{evict_movie, CronJobScheduler, Source};
+
+const BYTES_PER_GB: u64 = 1024 * 1024 * 1024;
+
+#[derive(Debug)]
+pub enum QuotaError {
+    InsufficientSpace { needed: u64, available: u64 },
+    Database(sqlx::Error),
+}
+
+// Keeps the downloads folder within a storage budget. Before a torrent is
+// added the movies nobody watched for the longest are evicted until the new
+// one fits, movies that are being streamed are never touched.
+pub struct DiskQuota {
+    // `None` only bounds downloads by the free space of the volume
+    budget_bytes: Option<u64>,
+    // two downloads must not both count the same free bytes
+    admission: Mutex<()>,
+}
+
+impl DiskQuota {
+    pub fn new(budget_bytes: Option<u64>) -> Self {
+        Self {
+            budget_bytes,
+            admission: Mutex::new(()),
+        }
+    }
+
+    pub fn from_env() -> Self {
+        let budget_bytes = env::var("DOWNLOADS_BUDGET_GB")
+            .ok()
+            .and_then(|value| value.parse::<u64>().ok())
+            .map(|gb| gb * BYTES_PER_GB);
+        Self::new(budget_bytes)
+    }
+
+    pub async fn make_room(
+        &self,
+        needed: u64,
+        connection: &PgPool,
+        torrent_client: &dyn TorrentClient,
+        active_streams: &ActiveStreams,
+    ) -> Result<(), QuotaError> {
+        let _admission = self.admission.lock().await;
+        let mut tried = HashSet::new();
+        loop {
+            let available = self.available_bytes(connection, torrent_client).await?;
+            if available >= needed {
+                return Ok(());
+            }
+
+            let candidates = sqlx::query(
+                r#"
+                    SELECT id, movie_id, movie_source FROM movie_torrent
+                    ORDER BY last_watched_at ASC
+                "#,
+            )
+            .fetch_all(connection)
+            .await
+            .map_err(QuotaError::Database)?;
+            let victim = candidates.into_iter().find_map(|row| {
+                let record_id: Uuid = row.get("id");
+                let job_id = CronJobScheduler::build_job_id(
+                    row.get("movie_id"),
+                    row.get::<Source, &str>("movie_source"),
+                );
+                if tried.contains(&record_id) || active_streams.is_active(job_id.as_str()) {
+                    None
+                } else {
+                    Some(record_id)
+                }
+            });
+            let record_id = match victim {
+                Some(record_id) => record_id,
+                None => {
+                    tracing::warn!(
+                        "Can't make room for {} bytes, only {} bytes left",
+                        needed,
+                        available
+                    );
+                    return Err(QuotaError::InsufficientSpace { needed, available });
+                }
+            };
+
+            tried.insert(record_id);
+            match evict_movie(connection, torrent_client, record_id, None).await {
+                Ok(true) => tracing::info!("Evicted movie {} to free disk space", record_id),
+                Ok(false) => {}
+                Err(err) => tracing::error!("Failed to evict movie {}: {}", record_id, err),
+            }
+        }
+    }
+
+    // what a new download may still use: the free space on the volume and the
+    // budget left, minus the bytes the running downloads are about to write
+    async fn available_bytes(
+        &self,
+        connection: &PgPool,
+        torrent_client: &dyn TorrentClient,
+    ) -> Result<u64, QuotaError> {
+        let download_path = torrent_client.download_path();
+        let pending = pending_download_bytes(connection, torrent_client).await?;
+
+        let free = match free_space(download_path.as_path()) {
+            Ok(free) => free,
+            Err(err) => {
+                tracing::warn!(
+                    "Can't read free space of {}: {}",
+                    download_path.display(),
+                    err
+                );
+                u64::MAX
+            }
+        };
+        let mut available = free.saturating_sub(pending);
+
+        if let Some(budget) = self.budget_bytes {
+            let used = tokio::task::spawn_blocking(move || dir_size(download_path.as_path()))
+                .await
+                .unwrap_or_default();
+            available = available.min(budget.saturating_sub(used + pending));
+        }
+        Ok(available)
+    }
+}
+
+async fn pending_download_bytes(
+    connection: &PgPool,
+    torrent_client: &dyn TorrentClient,
+) -> Result<u64, QuotaError> {
+    let rows = sqlx::query("SELECT torrent_id FROM movie_torrent")
+        .fetch_all(connection)
+        .await
+        .map_err(QuotaError::Database)?;
+    let mut pending = 0;
+    for row in rows {
+        let torrent_id: i32 = row.get("torrent_id");
+        match torrent_client.torrent_status(torrent_id).await {
+            Ok(status) if !status.finished => {
+                pending += status.total_bytes.saturating_sub(status.progress_bytes);
+            }
+            Ok(_) => {}
+            Err(err) => tracing::warn!("Can't get status of torrent {}: {}", torrent_id, err),
+        }
+    }
+    Ok(pending)
+}
+
+// the closest existing folder is checked when the downloads folder was not
+// created yet, the statvfs field widths differ between platforms
+#[allow(clippy::unnecessary_cast)]
+fn free_space(path: &Path) -> io::Result<u64> {
+    let path = path
+        .ancestors()
+        .find(|ancestor| ancestor.exists())
+        .unwrap_or(path);
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+fn dir_size(path: &Path) -> u64 {
+    let mut size = 0;
+    let mut folders: Vec<PathBuf> = vec![path.to_path_buf()];
+    while let Some(folder) = folders.pop() {
+        let entries = match std::fs::read_dir(folder.as_path()) {
+            Ok(entries) => entries,
+            Err(_) => continue,
+        };
+        for entry in entries.flatten() {
+            match entry.metadata() {
+                Ok(metadata) if metadata.is_dir() => folders.push(entry.path()),
+                Ok(metadata) => size += metadata.len(),
+                Err(_) => {}
+            }
+        }
+    }
+    size
+}
@@ -0,0 +1,277 @@
+use actix_web::{
+    web::{Bytes, Data, Path},
+    HttpResponse,
+};
+use futures_util::{stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::{PgPool, Row};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use tokio::sync::broadcast::{self, error::RecvError, Sender};
+use tokio::time::{self, Duration};
+use tracing::Instrument;
+
+use super::torrent::{DownloadState, TorrentClient, TorrentStatus};
+use super::{CronJobScheduler, Source};
+
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+const EVENT_CHANNEL_CAPACITY: usize = 16;
+// enough of the file on disk for the player to start buffering
+const READY_TO_STREAM_BYTES: u64 = 20 * 1024 * 1024;
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum MovieEvent {
+    Progress {
+        state: DownloadState,
+        bytes_downloaded: u64,
+        total_size: u64,
+        download_speed: u64,
+        peers: u32,
+        eta: Option<u64>,
+    },
+    ReadyToStream,
+    TranscodeFinished,
+    Error {
+        message: String,
+    },
+}
+
+impl MovieEvent {
+    fn progress(status: &TorrentStatus) -> Self {
+        MovieEvent::Progress {
+            state: status.download_state(),
+            bytes_downloaded: status.progress_bytes,
+            total_size: status.total_bytes,
+            download_speed: status.download_speed,
+            peers: status.peers,
+            eta: status.eta_secs,
+        }
+    }
+
+    fn is_ready_to_stream(status: &TorrentStatus) -> bool {
+        status.finished || status.progress_bytes >= READY_TO_STREAM_BYTES
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            MovieEvent::Progress { .. } => "progress",
+            MovieEvent::ReadyToStream => "ready_to_stream",
+            MovieEvent::TranscodeFinished => "transcode_finished",
+            MovieEvent::Error { .. } => "error",
+        }
+    }
+
+    fn to_sse(&self) -> Bytes {
+        let data = serde_json::to_string(self).unwrap_or_default();
+        Bytes::from(format!("event: {}\ndata: {}\n\n", self.name(), data))
+    }
+}
+
+// One broadcast channel per movie. The first subscriber spawns the poller,
+// every later viewer of the same movie just attaches to the channel.
+pub struct DownloadEventHub {
+    channels: Mutex<HashMap<String, Sender<MovieEvent>>>,
+}
+
+impl Default for DownloadEventHub {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DownloadEventHub {
+    pub fn new() -> Self {
+        Self {
+            channels: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn publish(&self, job_id: &str, event: MovieEvent) {
+        if let Some(sender) = self.channels.lock().unwrap().get(job_id) {
+            let _ = sender.send(event);
+        }
+    }
+
+    fn subscribe(
+        hub: &Data<DownloadEventHub>,
+        job_id: String,
+        torrent_id: i32,
+        torrent_client: &Data<dyn TorrentClient>,
+    ) -> broadcast::Receiver<MovieEvent> {
+        let mut channels = hub.channels.lock().unwrap();
+        if let Some(sender) = channels.get(&job_id) {
+            return sender.subscribe();
+        }
+        let (sender, receiver) = broadcast::channel(EVENT_CHANNEL_CAPACITY);
+        channels.insert(job_id.clone(), sender);
+        tokio::spawn(poll_torrent(
+            hub.clone(),
+            job_id,
+            torrent_id,
+            torrent_client.clone(),
+        ));
+        receiver
+    }
+
+    // hands back the sender while someone is still listening, otherwise drops the channel
+    fn active_sender(&self, job_id: &str) -> Option<Sender<MovieEvent>> {
+        let mut channels = self.channels.lock().unwrap();
+        match channels.get(job_id) {
+            Some(sender) if sender.receiver_count() > 0 => Some(sender.clone()),
+            Some(_) => {
+                channels.remove(job_id);
+                None
+            }
+            None => None,
+        }
+    }
+
+    fn close(&self, job_id: &str) {
+        self.channels.lock().unwrap().remove(job_id);
+    }
+}
+
+// What a viewer joining late already missed: where the download is, and the
+// one off events the poller and the transcode worker sent before it came
+async fn snapshot(
+    torrent_client: &dyn TorrentClient,
+    torrent_id: i32,
+    transcoded: bool,
+) -> Vec<MovieEvent> {
+    let mut events = Vec::new();
+    match torrent_client.torrent_status(torrent_id).await {
+        Ok(status) => {
+            events.push(MovieEvent::progress(&status));
+            if MovieEvent::is_ready_to_stream(&status) {
+                events.push(MovieEvent::ReadyToStream);
+            }
+        }
+        // the poller reports it
+        Err(err) => tracing::warn!("No snapshot of torrent {}: {}", torrent_id, err),
+    }
+    if transcoded {
+        events.push(MovieEvent::TranscodeFinished);
+    }
+    events
+}
+
+async fn poll_torrent(
+    hub: Data<DownloadEventHub>,
+    job_id: String,
+    torrent_id: i32,
+    torrent_client: Data<dyn TorrentClient>,
+) {
+    let mut interval = time::interval(POLL_INTERVAL);
+    let mut ready_sent = false;
+
+    loop {
+        interval.tick().await;
+        let sender = match hub.active_sender(&job_id) {
+            Some(sender) => sender,
+            None => {
+                tracing::info!("No more listeners for {}, stopping poller", job_id);
+                return;
+            }
+        };
+
+        let status = match torrent_client.torrent_status(torrent_id).await {
+            Ok(status) => status,
+            Err(err) => {
+                tracing::error!("Failed to poll torrent {}: {}", torrent_id, err);
+                let _ = sender.send(MovieEvent::Error { message: err });
+                hub.close(&job_id);
+                return;
+            }
+        };
+
+        let _ = sender.send(MovieEvent::progress(&status));
+        if status.download_state() == DownloadState::Error {
+            let _ = sender.send(MovieEvent::Error {
+                message: status
+                    .error
+                    .unwrap_or_else(|| "Torrent client error".to_string()),
+            });
+            hub.close(&job_id);
+            return;
+        }
+        if !ready_sent && MovieEvent::is_ready_to_stream(&status) {
+            let _ = sender.send(MovieEvent::ReadyToStream);
+            ready_sent = true;
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct DownloadEventsInfo {
+    pub source: Source,
+    pub movie_id: String,
+}
+
+pub async fn stream_download_events(
+    connection: Data<PgPool>,
+    info: Path<DownloadEventsInfo>,
+    event_hub: Data<DownloadEventHub>,
+    torrent_client: Data<dyn TorrentClient>,
+) -> HttpResponse {
+    let query_span = tracing::info_span!("Download events handler");
+    let path_info = info.into_inner();
+
+    let (torrent_id, transcoded): (i32, bool) = match sqlx::query(
+        r#"
+            SELECT torrent_id, EXISTS (
+                SELECT 1 FROM transcode_jobs
+                WHERE movie_id = $1 AND movie_source = $2 AND status = 'DONE'
+            ) AS transcoded
+            FROM movie_torrent WHERE movie_id = $1 AND movie_source = $2
+        "#,
+    )
+    .bind(path_info.movie_id.clone())
+    .bind(path_info.source.clone() as Source)
+    .fetch_one(connection.as_ref())
+    .instrument(query_span)
+    .await
+    {
+        Ok(row) => (row.get("torrent_id"), row.get("transcoded")),
+        Err(sqlx::Error::RowNotFound) => {
+            tracing::error!("Torrent Not downloaded");
+            return HttpResponse::NotFound().json(json!({
+                "error": "Torrent not found"
+            }));
+        }
+        Err(err) => {
+            tracing::error!("Database Error {:#?}", err);
+            return HttpResponse::BadRequest().json(json!({
+                "error": "Database error"
+            }));
+        }
+    };
+
+    let receiver = DownloadEventHub::subscribe(
+        &event_hub,
+        CronJobScheduler::build_job_id(path_info.movie_id, path_info.source),
+        torrent_id,
+        &torrent_client,
+    );
+    // subscribed first, an event sent meanwhile arrives twice rather than never
+    let snapshot = snapshot(torrent_client.get_ref(), torrent_id, transcoded)
+        .await
+        .into_iter()
+        .map(|event| Ok::<_, actix_web::Error>(event.to_sse()));
+
+    let events = stream::unfold(receiver, |mut receiver| async move {
+        loop {
+            match receiver.recv().await {
+                Ok(event) => return Some((Ok::<_, actix_web::Error>(event.to_sse()), receiver)),
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    });
+
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(("Cache-Control", "no-cache"))
+        .streaming(stream::iter(snapshot).chain(events))
+}
@@ -1,55 +1,112 @@
-use std::process::Command;
-use std::env;
-use std::path::PathBuf;
+use crate::routes::movies::transcode::TranscodeQueue;
 
-use crate::routes::{schedule_handler, CronJobScheduler};
-
-use super::torrent::RqbitWrapper;
-use super::Source;
+use super::torrent::{select_files, MagnetTrackers, TorrentClient};
+use super::{
+    find_catalog_torrent, ActiveStreams, CatalogError, CronJobScheduler, DiskQuota,
+    InFlightDownloads, MovieQuality, QuotaError, Source,
+};
 use actix_web::web::Json;
 use actix_web::{web::Data, HttpResponse};
 use chrono::Utc;
 use serde::Deserialize;
 use serde_json::json;
 use sqlx::types::Uuid;
-use sqlx::PgPool;
+use sqlx::{PgPool, Row};
 use tracing::Instrument; // Import the missing type
 
 #[derive(Deserialize)]
 pub struct MovieInfo {
     pub movie_id: String,
     pub source: Source,
-    pub magnet_url: String,
-}
-
-
-fn convert_video(input_path: &str, output_path: &str, format: &str) -> Result<(), Box<dyn std::error::Error>> {
-    // Convert the video using FFmpeg
-    let output_file = format!("{}.{}", output_path, format);
-    Command::new("ffmpeg")
-        .arg("-i")
-        .arg(input_path)
-        .arg(&output_file)
-        .status()?;
-    Ok(())
-}
-
-fn get_download_folder() -> Result<PathBuf, String> {
-    let current_dir = env::current_dir().map_err(|err| format!("failed to get current directory{}", err))?;
-    let parent_dir = current_dir.parent().ok_or("Failed to get parrent Directory")?;
-    let target_folder = parent_dir.join("Downloads");
-
-    Ok(target_folder)
+    pub quality: MovieQuality,
 }
 
+#[allow(clippy::too_many_arguments)]
 pub async fn download_torrent(
     connection: Data<PgPool>,
     body: Json<MovieInfo>,
-    corn_job_handler: Data<CronJobScheduler>,
+    torrent_client: Data<dyn TorrentClient>,
+    transcode_queue: Data<TranscodeQueue>,
+    disk_quota: Data<DiskQuota>,
+    active_streams: Data<ActiveStreams>,
+    in_flight_downloads: Data<InFlightDownloads>,
+    magnet_trackers: Data<MagnetTrackers>,
 ) -> HttpResponse {
     let query_span = tracing::trace_span!("Start torrent Download Handler");
 
-    let torrent_client = RqbitWrapper::default();
+    // held until the row is inserted, a second click on play meanwhile is told
+    // the download is already in progress
+    let _in_flight = match InFlightDownloads::begin(
+        &in_flight_downloads,
+        CronJobScheduler::build_job_id(body.movie_id.clone(), body.source.clone()),
+    ) {
+        Some(in_flight) => in_flight,
+        None => return download_in_progress(),
+    };
+    match find_torrent_id(connection.as_ref(), &body).await {
+        Ok(Some(torrent_id)) => return existing_download(torrent_client.get_ref(), torrent_id).await,
+        Ok(None) => {}
+        Err(err) => {
+            tracing::error!("Database error {:#?}", err);
+            return HttpResponse::BadRequest().json(json!({
+              "error" : "Database error"
+            }));
+        }
+    }
+
+    // the magnet is built from the catalog hash, never taken from the client.
+    // A quality nobody seeds falls back to the healthiest one that has seeds
+    let (magnet, quality) = match find_catalog_torrent(
+        connection.as_ref(),
+        body.movie_id.as_str(),
+        &body.source,
+        body.quality,
+    )
+    .await
+    {
+        Ok(torrent) => {
+            let quality = torrent.quality.unwrap_or(body.quality);
+            if quality != body.quality {
+                tracing::info!(
+                    "No seeds for {} of {}, downloading {} instead",
+                    body.quality.label(),
+                    body.movie_id,
+                    quality.label()
+                );
+            }
+            match magnet_trackers.magnet_for(torrent.info_hash.as_str(), torrent.name) {
+                Ok(magnet) => (magnet.to_string(), quality),
+                Err(err) => {
+                    tracing::error!("Catalog has an invalid torrent hash {}", err);
+                    return HttpResponse::BadRequest().json(json!({
+                      "error" : "Invalid torrent hash"
+                    }));
+                }
+            }
+        }
+        Err(CatalogError::MovieNotFound) => {
+            return HttpResponse::NotFound().json(json!({
+              "error" : "Movie not found"
+            }));
+        }
+        Err(CatalogError::QualityNotFound) => {
+            return HttpResponse::NotFound().json(json!({
+              "error" : "No torrent for the requested quality"
+            }));
+        }
+        Err(CatalogError::Upstream(err)) => {
+            tracing::error!("Failed to get movie torrents {}", err);
+            return HttpResponse::BadRequest().json(json!({
+              "error" : "Failed to get movie torrents"
+            }));
+        }
+        Err(CatalogError::Database(err)) => {
+            tracing::error!("Database error {:#?}", err);
+            return HttpResponse::BadRequest().json(json!({
+              "error" : "Database error"
+            }));
+        }
+    };
 
     // let output_folder = format!("");
     let download_path = {
@@ -63,8 +120,49 @@ pub async fn download_torrent(
 
     tracing::info!("DOWNLOAD PATH: {}", download_path);
 
+    // only the feature and its subtitles are downloaded, not samples or extras
+    let selection = match torrent_client.inspect_magnet(magnet.clone()).await {
+        Ok(files) => match select_files(&files) {
+            Some(selection) => selection,
+            None => {
+                tracing::error!("No video file in torrent {}", magnet);
+                return HttpResponse::BadRequest().json(json!({
+                  "error" : "Torrent has no playable video"
+                }));
+            }
+        },
+        Err(err) => {
+            tracing::error!("Failed to read torrent files {}", err);
+            return HttpResponse::BadRequest().json(json!({
+              "error" : "Failed to start torrent"
+            }));
+        }
+    };
+    match disk_quota
+        .make_room(
+            selection.total_bytes(),
+            connection.as_ref(),
+            torrent_client.get_ref(),
+            active_streams.as_ref(),
+        )
+        .await
+    {
+        Ok(_) => {}
+        Err(QuotaError::InsufficientSpace { .. }) => {
+            return HttpResponse::InsufficientStorage().json(json!({
+              "error" : "Not enough disk space to download the movie"
+            }));
+        }
+        Err(QuotaError::Database(err)) => {
+            tracing::error!("Database error while checking disk space {:#?}", err);
+            return HttpResponse::BadRequest().json(json!({
+              "error" : "Database error"
+            }));
+        }
+    }
+
     let meta_data = match torrent_client
-        .download_torrent(&body.magnet_url, Some(download_path))
+        .add_torrent(magnet, Some(download_path), Some(selection.file_indexes()))
         .await
     {
         Ok(movie_info) => {
@@ -79,62 +177,74 @@ pub async fn download_torrent(
         }
     };
 
-    if (meta_data.file_type != "mp4" || meta_data.file_type != "webm"){
-        tracing::info!("--------HERE START CONVERT TO MKV---------");
-        let converted_path = match get_download_folder() {
-            Ok(path) => format!("{}/{}", path.display(), "videos/converted/video"),
-            Err(e) => return HttpResponse::InternalServerError().finish(),
-        };
-        // let converted_path = format!("{}/{}",, "videos/converted/video");
-        convert_video(&meta_data.path, &converted_path, "mkv");
-        convert_video(&meta_data.path, &converted_path, "m3u8");
-
-        // let client = reqwest::Client::new();
-        // let res = client.post("localhost:3001/hls")
-        //                             .json(&json!({
-        //                                 "video_path": meta_data.path,
-        //                                 "id": body.movie_id
-        //                             })).send().await;
-        // let response = match res {
-        //     Ok(val) => {
-        //         val
-        //     }
-        //     Err(err) => {
-        //         // use the error response from the server
-        //         return  HttpResponse::InternalServerError().json(json!({"error":"couldn't convert file"}));
-        //     }
-        // };
-
-        tracing::info!("--------HERE END CONVERT TO MKV---------");
-    }
-
+    let torrent_id = match meta_data.id.parse::<i32>() {
+        Ok(torrent_id) => torrent_id,
+        Err(err) => {
+            tracing::error!("Invalid torrent id {:?} {}", meta_data.id, err);
+            return HttpResponse::BadRequest().json(json!({
+              "error" : "Failed to start torrent"
+            }));
+        }
+    };
+    let movie_file = meta_data.file.clone().unwrap_or(selection.video);
     let query_res = sqlx::query(
     r#"
-        INSERT INTO movie_torrent (id, movie_source, movie_id, created_at, movie_path, torrent_id, file_type, available_subs)
-        VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+        INSERT INTO movie_torrent (id, movie_source, movie_id, created_at, movie_path, torrent_id, file_type, available_subs, file_index, file_name, file_size)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)
+        ON CONFLICT (movie_source, movie_id) DO NOTHING
     "#)
     .bind(Uuid::new_v4())
     .bind(body.source.clone() as Source)
     .bind(body.movie_id.clone())
     .bind(Utc::now())
     .bind(meta_data.path.clone())
-    .bind(meta_data.id.parse::<i32>().unwrap())
+    .bind(torrent_id)
     .bind(meta_data.file_type.clone())
     .bind(&meta_data.available_subs)
+    .bind(movie_file.index as i32)
+    .bind(movie_file.name)
+    .bind(movie_file.length as i64)
     .execute(connection.as_ref())
     .instrument(query_span)
     .await;
 
     match query_res {
+        // another server instance started the same movie in the meantime
+        Ok(res) if res.rows_affected() == 0 => {
+            if let Ok(Some(existing_id)) = find_torrent_id(connection.as_ref(), &body).await {
+                if existing_id != torrent_id {
+                    if let Err(err) = torrent_client
+                        .delete_torrent(torrent_id, meta_data.path.clone())
+                        .await
+                    {
+                        tracing::error!("Failed to remove duplicate torrent {}", err);
+                    }
+                }
+            }
+            download_in_progress()
+        }
         Ok(_) => {
             tracing::info!("torrent created successfully!");
-            let _ = schedule_handler(
-                &corn_job_handler,
-                CronJobScheduler::build_job_id(body.movie_id.clone(), body.source.clone()),
-                &connection,
-            )
-            .await;
-            HttpResponse::Ok().finish()
+            // the worker probes the file once it's downloaded and only
+            // transcodes what browsers can't play
+            if let Err(err) = transcode_queue
+                .enqueue(
+                    connection.as_ref(),
+                    body.movie_id.clone(),
+                    body.source.clone(),
+                    torrent_id,
+                    meta_data.path.clone(),
+                )
+                .await
+            {
+                tracing::error!("Failed to queue transcode job {:#?}", err);
+            }
+            HttpResponse::Ok().json(json!({
+                "data": {
+                    "quality": quality.label(),
+                    "requested_quality": body.quality.label(),
+                }
+            }))
         }
         Err(err) => {
             tracing::error!("Failed to create torrent in database {:#?}", err);
@@ -142,3 +252,39 @@ pub async fn download_torrent(
         }
     }
 }
+
+fn download_in_progress() -> HttpResponse {
+    HttpResponse::Accepted().json(json!({
+        "message": "Download already in progress"
+    }))
+}
+
+// the movie was downloaded before, it's ready unless the torrent still runs
+async fn existing_download(torrent_client: &dyn TorrentClient, torrent_id: i32) -> HttpResponse {
+    match torrent_client.torrent_status(torrent_id).await {
+        Ok(status) if status.finished => HttpResponse::Ok().json(json!({
+            "message": "Movie already downloaded"
+        })),
+        Ok(_) => download_in_progress(),
+        Err(err) => {
+            tracing::warn!("No status for torrent {}: {}", torrent_id, err);
+            download_in_progress()
+        }
+    }
+}
+
+async fn find_torrent_id(
+    connection: &PgPool,
+    movie: &MovieInfo,
+) -> Result<Option<i32>, sqlx::Error> {
+    let row = sqlx::query(
+        r#"
+            SELECT torrent_id FROM movie_torrent WHERE movie_id = $1 AND movie_source = $2
+        "#,
+    )
+    .bind(movie.movie_id.clone())
+    .bind(movie.source.clone() as Source)
+    .fetch_optional(connection)
+    .await?;
+    Ok(row.map(|row| row.get("torrent_id")))
+}
@@ -6,7 +6,11 @@ use tracing::Span;
 
 use crate::routes::movies::types::ImdbMovieDetails;
 
-use super::{map_movie_bd_genre_code_with_value, Source};
+use super::transcode::media_probe::get_media_info;
+use super::{
+    map_movie_bd_genre_code_with_value, movie_db_catalog_torrents, torrent_health,
+    yts_catalog_torrents, Source,
+};
 // https://trakt.tv
 // https://trakt.docs.apiary.io/#introduction/standard-media-objects
 pub async fn get_movie_info(
@@ -18,6 +22,15 @@ pub async fn get_movie_info(
     let (movie_id, source_provider) = path.into_inner();
     // let query_span = tracing::Span::new(meta, values)
 
+    // codecs and playback mode of the file, once it's downloaded
+    let media = match get_media_info(connection.get_ref(), movie_id.clone(), source_provider.clone()).await {
+        Ok(media) => media,
+        Err(err) => {
+            tracing::error!("Failed to get media info {}", err);
+            None
+        }
+    };
+
     if source_provider == Source::YTS {
 
         let movie_id: u32 = match movie_id.parse() {
@@ -48,6 +61,8 @@ pub async fn get_movie_info(
                 }));
             }
         };
+        // seeds and peers per quality, so dead ones can be greyed out
+        let health = torrent_health(&yts_catalog_torrents(&movie_details.movie));
         let client = reqwest::Client::new();
         let similar_movies_suggestions = match client
             .get(format!(
@@ -72,12 +87,16 @@ pub async fn get_movie_info(
             Ok(res) => {
                 return HttpResponse::Ok().json(json!({
                     "data": movie_details,
-                    "movie_suggestions" : res
+                    "movie_suggestions" : res,
+                    "media": media,
+                    "torrent_health": health
                 }));
             }
             Err(_) => {
                 return HttpResponse::Ok().json(json!({
                     "data": movie_details,
+                    "media": media,
+                    "torrent_health": health
                 }));
             }
         }
@@ -132,7 +151,14 @@ pub async fn get_movie_info(
     };
         tracing::info!("THE QUERIED MOVIE: {:#?}", imdb_movie_details);
 
-        return HttpResponse::Ok().json(imdb_movie_details);
+        let health = torrent_health(&movie_db_catalog_torrents(
+            &imdb_movie_details.torrents,
+            Some(imdb_movie_details.primary_title.clone()),
+        ));
+        let mut body = json!(imdb_movie_details);
+        body["media"] = json!(media);
+        body["torrent_health"] = health;
+        return HttpResponse::Ok().json(body);
     }
     HttpResponse::BadRequest().finish()
 }
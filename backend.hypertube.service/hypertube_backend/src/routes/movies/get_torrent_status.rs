@@ -0,0 +1,74 @@
+use actix_web::{
+    web::{Data, Path},
+    HttpResponse,
+};
+use serde::Deserialize;
+use serde_json::json;
+use sqlx::{PgPool, Row};
+use tracing::Instrument;
+
+use super::torrent::TorrentClient;
+use super::Source;
+
+#[derive(Deserialize)]
+pub struct TorrentStatusInfo {
+    pub source: Source,
+    pub movie_id: String,
+}
+
+pub async fn get_torrent_status(
+    connection: Data<PgPool>,
+    info: Path<TorrentStatusInfo>,
+    torrent_client: Data<dyn TorrentClient>,
+) -> HttpResponse {
+    let query_span = tracing::info_span!("Get torrent status handler");
+    let path_info = info.into_inner();
+
+    let torrent_id: i32 = match sqlx::query(
+        r#"
+            SELECT torrent_id FROM movie_torrent WHERE movie_id = $1 AND movie_source = $2
+        "#,
+    )
+    .bind(path_info.movie_id.clone())
+    .bind(path_info.source.clone() as Source)
+    .fetch_one(connection.as_ref())
+    .instrument(query_span)
+    .await
+    {
+        Ok(row) => row.get("torrent_id"),
+        Err(sqlx::Error::RowNotFound) => {
+            tracing::error!("Torrent Not downloaded");
+            return HttpResponse::NotFound().json(json!({
+                "error": "Torrent not found"
+            }));
+        }
+        Err(err) => {
+            tracing::error!("Database Error {:#?}", err);
+            return HttpResponse::BadRequest().json(json!({
+                "error": "Database error"
+            }));
+        }
+    };
+
+    let status = match torrent_client.torrent_status(torrent_id).await {
+        Ok(status) => status,
+        Err(err) => {
+            tracing::error!("Failed to get torrent stats {}", err);
+            return HttpResponse::BadGateway().json(json!({
+                "error": "Failed to get torrent status"
+            }));
+        }
+    };
+
+    HttpResponse::Ok().json(json!({
+        "data": {
+            "state": status.download_state(),
+            "bytes_downloaded": status.progress_bytes,
+            "total_size": status.total_bytes,
+            "download_speed": status.download_speed,
+            "peers": status.peers,
+            "eta": status.eta_secs,
+            "error": status.error,
+        }
+    }))
+}
@@ -0,0 +1,62 @@
+use actix_web::{
+    web::{Data, Path},
+    HttpResponse,
+};
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use serde_json::json;
+use sqlx::{PgPool, Row};
+use tracing::Instrument;
+
+use super::transcode::TranscodeJobStatus;
+use super::Source;
+
+#[derive(Deserialize)]
+pub struct TranscodeStatusInfo {
+    pub source: Source,
+    pub movie_id: String,
+}
+
+pub async fn get_transcode_status(
+    connection: Data<PgPool>,
+    info: Path<TranscodeStatusInfo>,
+) -> HttpResponse {
+    let query_span = tracing::info_span!("Get transcode status handler");
+    let path_info = info.into_inner();
+
+    match sqlx::query(
+        r#"
+            SELECT * FROM transcode_jobs WHERE movie_id = $1 AND movie_source = $2
+            ORDER BY created_at DESC
+            LIMIT 1
+        "#,
+    )
+    .bind(path_info.movie_id)
+    .bind(path_info.source as Source)
+    .fetch_one(connection.as_ref())
+    .instrument(query_span)
+    .await
+    {
+        Ok(row) => HttpResponse::Ok().json(json!({
+            "data": {
+                "id": row.get::<uuid::Uuid, &str>("id").to_string(),
+                "status": row.get::<TranscodeJobStatus, &str>("status"),
+                "progress": row.get::<f32, &str>("progress"),
+                "error": row.get::<Option<String>, &str>("error"),
+                "updated_at": row.get::<DateTime<Utc>, &str>("updated_at"),
+            }
+        })),
+        Err(sqlx::Error::RowNotFound) => {
+            tracing::error!("No transcode job for this movie");
+            HttpResponse::NotFound().json(json!({
+                "error": "Transcode job not found"
+            }))
+        }
+        Err(err) => {
+            tracing::error!("Database Error {:#?}", err);
+            HttpResponse::BadRequest().json(json!({
+                "error": "Database error"
+            }))
+        }
+    }
+}
@@ -0,0 +1,50 @@
+use actix_web::web::Data;
+use std::collections::HashSet;
+use std::sync::Mutex;
+
+// Torrents that are being started right now, keyed by
+// `CronJobScheduler::build_job_id`. Only the first request for a movie gets
+// to talk to the torrent client, the others are told it is already on its way.
+pub struct InFlightDownloads {
+    starting: Mutex<HashSet<String>>,
+}
+
+impl Default for InFlightDownloads {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl InFlightDownloads {
+    pub fn new() -> Self {
+        Self {
+            starting: Mutex::new(HashSet::new()),
+        }
+    }
+
+    // `None` when another request is already starting this movie
+    pub fn begin(downloads: &Data<InFlightDownloads>, job_id: String) -> Option<InFlightDownload> {
+        if !downloads.starting.lock().unwrap().insert(job_id.clone()) {
+            return None;
+        }
+        Some(InFlightDownload {
+            downloads: downloads.clone(),
+            job_id,
+        })
+    }
+
+    pub fn contains(&self, job_id: &str) -> bool {
+        self.starting.lock().unwrap().contains(job_id)
+    }
+}
+
+pub struct InFlightDownload {
+    downloads: Data<InFlightDownloads>,
+    job_id: String,
+}
+
+impl Drop for InFlightDownload {
+    fn drop(&mut self) {
+        self.downloads.starting.lock().unwrap().remove(&self.job_id);
+    }
+}
@@ -1,13 +1,30 @@
+mod active_streams;
 mod cron_job_scheduler;
 mod delete_torrent;
+mod disk_quota;
+mod download_events;
 pub mod download_movie_content;
 mod get_movie_info;
 mod get_movie_subtitles;
+mod get_torrent_status;
 mod get_watched_movies;
 mod get_yts_top_movies;
+mod in_flight_downloads;
+mod playback_progress;
+mod range_responder;
+mod reconciliation;
 mod search_movies;
+mod stream_availability;
+mod stream_hls;
+mod stream_limits;
+mod stream_token;
 mod stream_video_content;
-mod torrent;
+pub mod torrent;
+mod torrent_catalog;
+mod torrent_indexer;
+mod torznab;
+pub mod transcode;
+mod get_transcode_status;
 mod util;
 mod types;
 pub mod get_favorite_movies;
@@ -15,14 +32,28 @@ mod set_favorite_movie;
 mod remove_favorite_movie;
 mod set_watched_movie;
 
+pub use active_streams::*;
 pub use cron_job_scheduler::*;
 pub use delete_torrent::*;
+pub use disk_quota::*;
+pub use download_events::*;
 pub use download_movie_content::*;
 use get_movie_info::*;
 pub use get_movie_subtitles::*;
+pub use get_torrent_status::*;
+pub use get_transcode_status::*;
 pub use get_yts_top_movies::*;
+pub use in_flight_downloads::*;
+pub use playback_progress::*;
+pub use reconciliation::*;
 use search_movies::*;
+use stream_hls::*;
+pub use stream_limits::*;
+pub use stream_token::*;
 use stream_video_content::*;
+pub use torrent_catalog::*;
+pub use torrent_indexer::*;
+pub use torznab::*;
 pub use util::*;
 pub  use get_favorite_movies::*;
 pub use set_favorite_movie::*;
@@ -0,0 +1,300 @@
+use actix_web::{
+    web::{Data, Json, Path},
+    HttpMessage, HttpRequest, HttpResponse,
+};
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use serde_json::json;
+use sqlx::{PgPool, Row};
+use std::env;
+use std::rc::Rc;
+use uuid::Uuid;
+
+use super::Source;
+use crate::middleware::User;
+
+const DEFAULT_WATCHED_PERCENT: f64 = 90.0;
+
+// How far into a movie a user has to get for it to count as watched, it then
+// leaves "continue watching" for the watch history.
+pub struct WatchedThreshold {
+    ratio: f64,
+}
+
+impl WatchedThreshold {
+    pub fn new(percent: f64) -> Self {
+        Self {
+            ratio: percent.clamp(1.0, 100.0) / 100.0,
+        }
+    }
+
+    // WATCHED_THRESHOLD_PERCENT is optional
+    pub fn from_env() -> Self {
+        let percent = env::var("WATCHED_THRESHOLD_PERCENT")
+            .ok()
+            .and_then(|value| value.parse::<f64>().ok())
+            .unwrap_or(DEFAULT_WATCHED_PERCENT);
+        Self::new(percent)
+    }
+
+    pub fn is_watched(&self, position: f64, duration: f64) -> bool {
+        position >= duration * self.ratio
+    }
+}
+
+#[derive(Deserialize)]
+pub struct PlaybackPosition {
+    pub movie_id: String,
+    pub source: Source,
+    // seconds into the movie
+    pub position: f64,
+    // what the player knows, used until the downloaded file is probed
+    pub duration: Option<f64>,
+    pub title: String,
+    pub poster_src: String,
+    pub movie_imdb_code: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct PlaybackProgressInfo {
+    pub source: Source,
+    pub movie_id: String,
+}
+
+fn visitor_id(req: &HttpRequest) -> Option<Uuid> {
+    req.extensions().get::<Rc<User>>().map(|user| user.id)
+}
+
+async fn probed_duration(
+    connection: &PgPool,
+    movie_id: String,
+    source: Source,
+) -> Result<Option<f64>, sqlx::Error> {
+    let row = sqlx::query(
+        r#"
+            SELECT duration_seconds FROM movie_torrent WHERE movie_id = $1 AND movie_source = $2
+        "#,
+    )
+    .bind(movie_id)
+    .bind(source as Source)
+    .fetch_optional(connection)
+    .await?;
+    Ok(row.and_then(|row| row.get::<Option<f64>, &str>("duration_seconds")))
+}
+
+// the player reports where it is every few seconds
+pub async fn set_playback_progress(
+    connection: Data<PgPool>,
+    req: HttpRequest,
+    body: Json<PlaybackPosition>,
+    threshold: Data<WatchedThreshold>,
+) -> HttpResponse {
+    let user_id = match visitor_id(&req) {
+        Some(user_id) => user_id,
+        None => {
+            tracing::info!("User field not found in req object");
+            return HttpResponse::NotFound().json(json!({
+                "error": "user not found"
+            }));
+        }
+    };
+    if !body.position.is_finite() || body.position < 0.0 {
+        return HttpResponse::BadRequest().json(json!({
+            "error": "Invalid position"
+        }));
+    }
+
+    let duration = match probed_duration(
+        connection.as_ref(),
+        body.movie_id.clone(),
+        body.source.clone(),
+    )
+    .await
+    {
+        Ok(probed) => probed
+            .or(body.duration)
+            .filter(|duration| duration.is_finite() && *duration > 0.0),
+        Err(err) => {
+            tracing::error!("Database error {:#?}", err);
+            return HttpResponse::BadRequest().json(json!({
+                "error": "Database error"
+            }));
+        }
+    };
+    let watched = duration
+        .map(|duration| threshold.is_watched(body.position, duration))
+        .unwrap_or(false);
+
+    let result = if watched {
+        move_to_history(connection.as_ref(), user_id, &body).await
+    } else {
+        sqlx::query(
+            r#"
+                INSERT INTO playback_progress (user_id, movie_source, movie_id, position_seconds, duration_seconds, title, poster_src, movie_imdb_code, updated_at)
+                VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+                ON CONFLICT (user_id, movie_source, movie_id)
+                DO UPDATE SET position_seconds = $4, duration_seconds = $5, title = $6, poster_src = $7, movie_imdb_code = $8, updated_at = $9
+            "#,
+        )
+        .bind(user_id)
+        .bind(body.source.clone() as Source)
+        .bind(body.movie_id.clone())
+        .bind(body.position)
+        .bind(duration)
+        .bind(body.title.clone())
+        .bind(body.poster_src.clone())
+        .bind(body.movie_imdb_code.clone())
+        .bind(Utc::now())
+        .execute(connection.as_ref())
+        .await
+        .map(|_| ())
+    };
+
+    match result {
+        Ok(_) => HttpResponse::Ok().json(json!({
+            "data": {
+                "position": body.position,
+                "duration": duration,
+                "watched": watched,
+            }
+        })),
+        Err(err) => {
+            tracing::error!("Failed to save playback progress {:#?}", err);
+            HttpResponse::BadRequest().json(json!({
+                "error": "Database error"
+            }))
+        }
+    }
+}
+
+async fn move_to_history(
+    connection: &PgPool,
+    user_id: Uuid,
+    movie: &PlaybackPosition,
+) -> Result<(), sqlx::Error> {
+    let mut transaction = connection.begin().await?;
+    sqlx::query(
+        r#"
+            INSERT INTO watched_movies (user_id, poster_src, title, movie_id, movie_imdb_code, movie_source, created_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7)
+            ON CONFLICT (user_id, movie_id) DO NOTHING
+        "#,
+    )
+    .bind(user_id)
+    .bind(movie.poster_src.clone())
+    .bind(movie.title.clone())
+    .bind(movie.movie_id.clone())
+    .bind(movie.movie_imdb_code.clone())
+    .bind(movie.source.to_string())
+    .bind(Utc::now())
+    .execute(&mut *transaction)
+    .await?;
+    sqlx::query(
+        r#"
+            DELETE FROM playback_progress WHERE user_id = $1 AND movie_source = $2 AND movie_id = $3
+        "#,
+    )
+    .bind(user_id)
+    .bind(movie.source.clone() as Source)
+    .bind(movie.movie_id.clone())
+    .execute(&mut *transaction)
+    .await?;
+    transaction.commit().await
+}
+
+// where the player should resume a movie
+pub async fn get_playback_progress(
+    connection: Data<PgPool>,
+    req: HttpRequest,
+    info: Path<PlaybackProgressInfo>,
+) -> HttpResponse {
+    let user_id = match visitor_id(&req) {
+        Some(user_id) => user_id,
+        None => {
+            tracing::info!("User field not found in req object");
+            return HttpResponse::NotFound().json(json!({
+                "error": "user not found"
+            }));
+        }
+    };
+    let info = info.into_inner();
+    match sqlx::query(
+        r#"
+            SELECT position_seconds, duration_seconds, updated_at FROM playback_progress
+            WHERE user_id = $1 AND movie_source = $2 AND movie_id = $3
+        "#,
+    )
+    .bind(user_id)
+    .bind(info.source as Source)
+    .bind(info.movie_id)
+    .fetch_optional(connection.as_ref())
+    .await
+    {
+        Ok(Some(row)) => HttpResponse::Ok().json(json!({
+            "data": {
+                "position": row.get::<f64, &str>("position_seconds"),
+                "duration": row.get::<Option<f64>, &str>("duration_seconds"),
+                "updated_at": row.get::<DateTime<Utc>, &str>("updated_at"),
+            }
+        })),
+        Ok(None) => HttpResponse::NotFound().json(json!({
+            "error": "No playback progress for this movie"
+        })),
+        Err(err) => {
+            tracing::error!("Database Error {:#?}", err);
+            HttpResponse::BadRequest().json(json!({
+                "error": "Database error"
+            }))
+        }
+    }
+}
+
+// unfinished movies, the most recently played first
+pub async fn get_continue_watching(connection: Data<PgPool>, req: HttpRequest) -> HttpResponse {
+    let user_id = match visitor_id(&req) {
+        Some(user_id) => user_id,
+        None => {
+            tracing::info!("User field not found in req object");
+            return HttpResponse::NotFound().json(json!({
+                "error": "user not found"
+            }));
+        }
+    };
+    match sqlx::query(
+        r#"
+            SELECT * FROM playback_progress WHERE user_id = $1 ORDER BY updated_at DESC
+        "#,
+    )
+    .bind(user_id)
+    .fetch_all(connection.as_ref())
+    .await
+    {
+        Ok(rows) => {
+            let movies: Vec<_> = rows
+                .iter()
+                .map(|row| {
+                    let position = row.get::<f64, &str>("position_seconds");
+                    let duration = row.get::<Option<f64>, &str>("duration_seconds");
+                    json!({
+                        "movie_id": row.get::<String, &str>("movie_id"),
+                        "source": row.get::<Source, &str>("movie_source").to_string(),
+                        "title": row.get::<String, &str>("title"),
+                        "poster_src": row.get::<String, &str>("poster_src"),
+                        "movie_imdb_code": row.get::<Option<String>, &str>("movie_imdb_code"),
+                        "position": position,
+                        "duration": duration,
+                        "progress": duration.map(|duration| (position / duration * 100.0).min(100.0)),
+                        "updated_at": row.get::<DateTime<Utc>, &str>("updated_at"),
+                    })
+                })
+                .collect();
+            HttpResponse::Ok().json(json!({ "data": movies }))
+        }
+        Err(err) => {
+            tracing::error!("Database Error {:#?}", err);
+            HttpResponse::BadRequest().json(json!({
+                "error": "Database error"
+            }))
+        }
+    }
+}
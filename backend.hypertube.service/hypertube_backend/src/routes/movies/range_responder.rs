@@ -0,0 +1,367 @@
+use actix_web::{
+    body::SizedStream,
+    http::{
+        header::{self, EntityTag, Header, HttpDate, IfRange},
+        Method,
+    },
+    web::Bytes,
+    HttpRequest, HttpResponse,
+};
+use futures_util::{future::BoxFuture, stream, Stream};
+use rand::distributions::{Alphanumeric, DistString};
+use serde_json::json;
+use std::io::{self, SeekFrom};
+use std::ops::Range;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+use super::stream_availability::RangeWaitError;
+use super::stream_limits::{throttle, TokenBucket};
+
+const READ_CHUNK_BYTES: u64 = 64 * 1024;
+// more ranges than this in one request are ignored and the whole file is sent
+const MAX_RANGES: usize = 16;
+
+// Lets a file that is still being written be streamed: `ready` resolves once
+// the byte at `range.start` is on disk and returns where the readable part
+// starting there ends.
+pub trait ReadGate: Send + Sync {
+    fn ready(&self, range: Range<u64>) -> BoxFuture<'_, Result<u64, RangeWaitError>>;
+}
+
+#[derive(Debug, PartialEq)]
+pub enum RangeHeaderError {
+    Invalid,
+    Unsatisfiable,
+}
+
+// Parses `bytes=` range sets, suffix (`-500`) and open ended (`100-`) specs
+// included. The returned ranges are clamped to `size`.
+pub fn parse_range_header(value: &str, size: u64) -> Result<Vec<Range<u64>>, RangeHeaderError> {
+    let specs = value
+        .trim()
+        .strip_prefix("bytes=")
+        .ok_or(RangeHeaderError::Invalid)?;
+    let mut ranges = Vec::new();
+    let mut any_valid = false;
+    for spec in specs
+        .split(',')
+        .map(str::trim)
+        .filter(|spec| !spec.is_empty())
+    {
+        let (start, end) = spec.split_once('-').ok_or(RangeHeaderError::Invalid)?;
+        let (start, end) = (start.trim(), end.trim());
+        let range = if start.is_empty() {
+            let suffix: u64 = end.parse().map_err(|_| RangeHeaderError::Invalid)?;
+            any_valid = true;
+            if suffix == 0 {
+                continue;
+            }
+            size.saturating_sub(suffix)..size
+        } else {
+            let start: u64 = start.parse().map_err(|_| RangeHeaderError::Invalid)?;
+            let end = if end.is_empty() {
+                u64::MAX
+            } else {
+                let end: u64 = end.parse().map_err(|_| RangeHeaderError::Invalid)?;
+                if end < start {
+                    return Err(RangeHeaderError::Invalid);
+                }
+                end
+            };
+            any_valid = true;
+            if start >= size {
+                continue;
+            }
+            start..end.saturating_add(1).min(size)
+        };
+        if !range.is_empty() {
+            ranges.push(range);
+        }
+    }
+    if !any_valid {
+        return Err(RangeHeaderError::Invalid);
+    }
+    if ranges.is_empty() {
+        return Err(RangeHeaderError::Unsatisfiable);
+    }
+    Ok(ranges)
+}
+
+enum BodyPart {
+    Bytes(Bytes),
+    File(Range<u64>),
+}
+
+struct BodyState {
+    path: PathBuf,
+    gate: Option<Arc<dyn ReadGate>>,
+    parts: std::vec::IntoIter<BodyPart>,
+    current: Option<Range<u64>>,
+    // what the gate last said is on disk, it's asked again once the body
+    // reads past it
+    readable: Range<u64>,
+    file: Option<File>,
+    _guards: Vec<Box<dyn Send>>,
+}
+
+// Serves a file, or the parts of it a request asks for, straight from disk
+// without blocking the worker. Handles HEAD, `If-Range`, single and
+// `multipart/byteranges` responses and 416s.
+pub struct RangeResponder {
+    path: PathBuf,
+    size: u64,
+    content_type: String,
+    etag: EntityTag,
+    last_modified: Option<HttpDate>,
+    gate: Option<Arc<dyn ReadGate>>,
+    guards: Vec<Box<dyn Send>>,
+    bucket: Option<TokenBucket>,
+}
+
+impl RangeResponder {
+    // for files that are completely on disk
+    pub async fn open(path: impl Into<PathBuf>, content_type: &str) -> io::Result<Self> {
+        let path = path.into();
+        let metadata = tokio::fs::metadata(path.as_path()).await?;
+        let modified = metadata.modified()?;
+        let modified_secs = modified
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs())
+            .unwrap_or_default();
+        Ok(Self::new(
+            path,
+            metadata.len(),
+            content_type,
+            EntityTag::new_strong(format!("{:x}-{:x}", modified_secs, metadata.len())),
+        )
+        .last_modified(modified))
+    }
+
+    pub fn new(path: impl Into<PathBuf>, size: u64, content_type: &str, etag: EntityTag) -> Self {
+        Self {
+            path: path.into(),
+            size,
+            content_type: content_type.to_string(),
+            etag,
+            last_modified: None,
+            gate: None,
+            guards: Vec::new(),
+            bucket: None,
+        }
+    }
+
+    pub fn last_modified(mut self, modified: SystemTime) -> Self {
+        self.last_modified = Some(modified.into());
+        self
+    }
+
+    pub fn gate(mut self, gate: Arc<dyn ReadGate>) -> Self {
+        self.gate = Some(gate);
+        self
+    }
+
+    // kept alive until the body is fully sent or the client goes away
+    pub fn hold(mut self, guard: impl Send + 'static) -> Self {
+        self.guards.push(Box::new(guard));
+        self
+    }
+
+    // the body is sent no faster than the bucket refills
+    pub fn throttle(mut self, bucket: Option<TokenBucket>) -> Self {
+        self.bucket = bucket;
+        self
+    }
+
+    // a stale `If-Range` validator means the client gets the whole file again
+    fn if_range_matches(&self, req: &HttpRequest) -> bool {
+        if !req.headers().contains_key(header::IF_RANGE) {
+            return true;
+        }
+        match IfRange::parse(req) {
+            Ok(IfRange::EntityTag(tag)) => tag.strong_eq(&self.etag),
+            Ok(IfRange::Date(date)) => self.last_modified == Some(date),
+            Err(_) => false,
+        }
+    }
+
+    fn requested_ranges(
+        &self,
+        req: &HttpRequest,
+    ) -> Result<Option<Vec<Range<u64>>>, RangeHeaderError> {
+        let value = match req.headers().get(header::RANGE) {
+            Some(value) => value,
+            None => return Ok(None),
+        };
+        if !self.if_range_matches(req) {
+            return Ok(None);
+        }
+        let value = match value.to_str() {
+            Ok(value) => value,
+            Err(_) => return Ok(None),
+        };
+        match parse_range_header(value, self.size) {
+            Ok(ranges) if ranges.len() > MAX_RANGES => Ok(None),
+            Ok(ranges) => Ok(Some(ranges)),
+            Err(RangeHeaderError::Invalid) => {
+                tracing::warn!("Ignoring invalid range header {}", value);
+                Ok(None)
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    pub async fn respond(mut self, req: &HttpRequest) -> HttpResponse {
+        let is_head = req.method() == Method::HEAD;
+        let ranges = match self.requested_ranges(req) {
+            Ok(ranges) => ranges,
+            Err(_) => {
+                tracing::error!("Unsatisfiable range for a {} bytes file", self.size);
+                return HttpResponse::RangeNotSatisfiable()
+                    .insert_header((header::CONTENT_RANGE, format!("bytes */{}", self.size)))
+                    .insert_header((header::ACCEPT_RANGES, "bytes"))
+                    .finish();
+            }
+        };
+
+        let mut response = match ranges {
+            None => HttpResponse::Ok(),
+            Some(_) => HttpResponse::PartialContent(),
+        };
+        response
+            .insert_header((header::ACCEPT_RANGES, "bytes"))
+            .insert_header((header::ETAG, self.etag.clone()));
+        if let Some(last_modified) = self.last_modified {
+            response.insert_header((header::LAST_MODIFIED, last_modified));
+        }
+
+        let mut ranges = match ranges {
+            None => {
+                response.content_type(self.content_type.as_str());
+                let parts = vec![BodyPart::File(0..self.size)];
+                return response.body(self.body(self.size, parts, is_head));
+            }
+            Some(ranges) => ranges,
+        };
+
+        // only promise the bytes that are already on disk
+        if let (Some(gate), false) = (&self.gate, is_head) {
+            for range in ranges.iter_mut() {
+                match gate.ready(range.clone()).await {
+                    Ok(available_end) => range.end = range.end.min(available_end),
+                    Err(RangeWaitError::Timeout) => {
+                        tracing::warn!("Range {:?} still not downloaded", range);
+                        return HttpResponse::ServiceUnavailable()
+                            .insert_header((header::RETRY_AFTER, "5"))
+                            .json(json!({
+                                "error": "Requested range is still downloading"
+                            }));
+                    }
+                    Err(RangeWaitError::Client(err)) => {
+                        tracing::error!("Torrent client error {}", err);
+                        return HttpResponse::BadRequest().json(json!({
+                            "error": "Failed to get download progress"
+                        }));
+                    }
+                }
+            }
+        }
+
+        if ranges.len() == 1 {
+            let range = ranges.remove(0);
+            response
+                .content_type(self.content_type.as_str())
+                .insert_header((
+                    header::CONTENT_RANGE,
+                    format!("bytes {}-{}/{}", range.start, range.end - 1, self.size),
+                ));
+            let length = range.end - range.start;
+            return response.body(self.body(length, vec![BodyPart::File(range)], is_head));
+        }
+
+        let boundary = Alphanumeric.sample_string(&mut rand::thread_rng(), 24);
+        let mut parts = Vec::new();
+        let mut length = 0;
+        for range in ranges {
+            let part_head = format!(
+                "\r\n--{}\r\nContent-Type: {}\r\nContent-Range: bytes {}-{}/{}\r\n\r\n",
+                boundary,
+                self.content_type,
+                range.start,
+                range.end - 1,
+                self.size
+            );
+            length += part_head.len() as u64 + (range.end - range.start);
+            parts.push(BodyPart::Bytes(Bytes::from(part_head)));
+            parts.push(BodyPart::File(range));
+        }
+        let closing = format!("\r\n--{}--\r\n", boundary);
+        length += closing.len() as u64;
+        parts.push(BodyPart::Bytes(Bytes::from(closing)));
+
+        response.content_type(format!("multipart/byteranges; boundary={}", boundary));
+        response.body(self.body(length, parts, is_head))
+    }
+
+    fn body(
+        &mut self,
+        length: u64,
+        parts: Vec<BodyPart>,
+        is_head: bool,
+    ) -> SizedStream<impl Stream<Item = Result<Bytes, io::Error>>> {
+        let parts = if is_head { Vec::new() } else { parts };
+        let state = BodyState {
+            path: self.path.clone(),
+            gate: self.gate.clone(),
+            parts: parts.into_iter(),
+            current: None,
+            readable: 0..0,
+            file: None,
+            _guards: std::mem::take(&mut self.guards),
+        };
+        let body = Box::pin(stream::try_unfold(state, next_chunk));
+        SizedStream::new(length, Box::pin(throttle(body, self.bucket.take())))
+    }
+}
+
+async fn next_chunk(mut state: BodyState) -> Result<Option<(Bytes, BodyState)>, io::Error> {
+    loop {
+        let range = match state.current.take() {
+            Some(range) if !range.is_empty() => range,
+            _ => match state.parts.next() {
+                Some(BodyPart::Bytes(bytes)) => return Ok(Some((bytes, state))),
+                Some(BodyPart::File(range)) => range,
+                None => return Ok(None),
+            },
+        };
+        if range.is_empty() {
+            continue;
+        }
+
+        let mut chunk_end = range.end.min(range.start + READ_CHUNK_BYTES);
+        if let Some(gate) = &state.gate {
+            if !state.readable.contains(&range.start) {
+                let available_end = gate
+                    .ready(range.start..range.end)
+                    .await
+                    .map_err(|err| io::Error::other(format!("{:?}", err)))?;
+                state.readable = range.start..available_end;
+            }
+            chunk_end = chunk_end.min(state.readable.end);
+        }
+
+        let file = match state.file.as_mut() {
+            Some(file) => file,
+            None => state.file.insert(File::open(state.path.as_path()).await?),
+        };
+        file.seek(SeekFrom::Start(range.start)).await?;
+        let mut buffer = vec![0; (chunk_end - range.start) as usize];
+        file.read_exact(&mut buffer).await?;
+
+        state.current = Some(chunk_end..range.end);
+        return Ok(Some((Bytes::from(buffer), state)));
+    }
+}
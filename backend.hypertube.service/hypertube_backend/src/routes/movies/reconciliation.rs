@@ -0,0 +1,420 @@
+use actix_web::web::Data;
+use chrono::{NaiveDate, Utc};
+use serde::Serialize;
+use sqlx::{PgPool, Row};
+use std::collections::HashSet;
+use std::io;
+use std::path::{Path, PathBuf};
+use tokio::task::JoinHandle;
+use uuid::Uuid;
+
+use super::torrent::{
+    file_extension, select_files, sidecar_subtitles, ClientTorrent, TorrentClient,
+};
+use super::transcode::TranscodeQueue;
+use super::{CronJobScheduler, InFlightDownloads, Source};
+
+#[derive(Debug, Default, Serialize)]
+pub struct ReconcileReport {
+    // job ids of the torrents that got a movie row back
+    pub adopted: Vec<String>,
+    pub removed_torrents: Vec<i32>,
+    pub removed_dirs: Vec<String>,
+    // job ids of the rows whose file is gone
+    pub missing: Vec<String>,
+    // job ids of the rows whose file showed up again
+    pub restored: Vec<String>,
+    pub errors: Vec<String>,
+}
+
+// `download_torrent` names a movie's folder `{movie_id}_{source}_{date}`,
+// anything else in the downloads folder isn't ours to touch
+pub fn parse_movie_folder(name: &str) -> Option<(String, Source)> {
+    let mut parts = name.rsplitn(3, '_');
+    let date = parts.next()?;
+    let source = match parts.next()? {
+        "YTS" => Source::YTS,
+        "MovieDb" => Source::MovieDb,
+        _ => return None,
+    };
+    let movie_id = parts.next().filter(|movie_id| !movie_id.is_empty())?;
+    NaiveDate::parse_from_str(date, "%Y-%m-%d").ok()?;
+    Some((movie_id.to_string(), source))
+}
+
+fn folder_name(path: &Path) -> Option<String> {
+    path.file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+}
+
+struct MovieRow {
+    id: Uuid,
+    job_id: String,
+    torrent_id: i32,
+    movie_path: String,
+    missing: bool,
+}
+
+// Brings `movie_torrent`, the torrent client and the downloads folder back in
+// line after crashes and half finished deletions. Movies that are being
+// started right now are left alone.
+pub async fn reconcile(
+    connection: &PgPool,
+    torrent_client: &dyn TorrentClient,
+    in_flight: &InFlightDownloads,
+    transcode_queue: &TranscodeQueue,
+) -> ReconcileReport {
+    let mut report = ReconcileReport::default();
+    let rows = match sqlx::query(
+        r#"
+            SELECT id, movie_id, movie_source, torrent_id, movie_path, missing_since FROM movie_torrent
+        "#,
+    )
+    .fetch_all(connection)
+    .await
+    {
+        Ok(rows) => rows
+            .iter()
+            .map(|row| MovieRow {
+                id: row.get("id"),
+                job_id: CronJobScheduler::build_job_id(
+                    row.get("movie_id"),
+                    row.get("movie_source"),
+                ),
+                torrent_id: row.get("torrent_id"),
+                movie_path: row.get("movie_path"),
+                missing: row
+                    .get::<Option<chrono::DateTime<Utc>>, &str>("missing_since")
+                    .is_some(),
+            })
+            .collect::<Vec<_>>(),
+        Err(err) => {
+            tracing::error!("Database Error {:#?}", err);
+            report.errors.push(err.to_string());
+            return report;
+        }
+    };
+    // listed after the rows, a torrent started in between then looks like an
+    // orphan and is checked against the database again before it is touched.
+    // Without the list every torrent would look like an orphan.
+    let torrents = match torrent_client.list_torrents().await {
+        Ok(torrents) => torrents,
+        Err(err) => {
+            tracing::error!("Failed to list the client's torrents {}", err);
+            report.errors.push(err);
+            return report;
+        }
+    };
+    let download_root = torrent_client.download_path();
+
+    mark_missing_files(connection, &rows, &torrents, &download_root, &mut report).await;
+    reconcile_torrents(
+        connection,
+        torrent_client,
+        in_flight,
+        transcode_queue,
+        &rows,
+        &torrents,
+        &download_root,
+        &mut report,
+    )
+    .await;
+    remove_orphan_dirs(
+        connection,
+        torrent_client,
+        in_flight,
+        &download_root,
+        &mut report,
+    )
+    .await;
+    report
+}
+
+// runs once when the server boots, in the background
+pub fn reconcile_on_startup(
+    connection: Data<PgPool>,
+    torrent_client: Data<dyn TorrentClient>,
+    in_flight: Data<InFlightDownloads>,
+    transcode_queue: Data<TranscodeQueue>,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let report = reconcile(
+            connection.as_ref(),
+            torrent_client.get_ref(),
+            in_flight.as_ref(),
+            transcode_queue.as_ref(),
+        )
+        .await;
+        tracing::info!("Startup reconciliation finished {:?}", report);
+    })
+}
+
+async fn has_row(connection: &PgPool, torrent_id: i32) -> Result<bool, String> {
+    sqlx::query("SELECT id FROM movie_torrent WHERE torrent_id = $1")
+        .bind(torrent_id)
+        .fetch_optional(connection)
+        .await
+        .map(|row| row.is_some())
+        .map_err(|err| err.to_string())
+}
+
+// folders a movie row or a torrent of the client still points to
+async fn used_folders(
+    connection: &PgPool,
+    torrent_client: &dyn TorrentClient,
+) -> Result<HashSet<String>, String> {
+    let rows = sqlx::query("SELECT movie_path FROM movie_torrent")
+        .fetch_all(connection)
+        .await
+        .map_err(|err| err.to_string())?;
+    let mut folders: HashSet<String> = rows
+        .iter()
+        .filter_map(|row| {
+            Path::new(row.get::<&str, &str>("movie_path"))
+                .parent()
+                .and_then(folder_name)
+        })
+        .collect();
+    folders.extend(
+        torrent_client
+            .list_torrents()
+            .await?
+            .iter()
+            .filter_map(|torrent| torrent.output_folder.as_deref().and_then(folder_name)),
+    );
+    Ok(folders)
+}
+
+// a row only counts as missing once neither the disk nor the client has it,
+// a torrent that just started may not have created its file yet
+async fn mark_missing_files(
+    connection: &PgPool,
+    rows: &[MovieRow],
+    torrents: &[ClientTorrent],
+    download_root: &Path,
+    report: &mut ReconcileReport,
+) {
+    for row in rows {
+        // rqbit is handed folders relative to its own download path
+        let path = download_root.join(row.movie_path.as_str());
+        let exists = tokio::fs::try_exists(&path).await.unwrap_or(false);
+        let tracked = torrents.iter().any(|torrent| torrent.id == row.torrent_id);
+        let missing_since = match (exists, tracked, row.missing) {
+            (false, false, false) => Some(Utc::now()),
+            (true, _, true) => None,
+            _ => continue,
+        };
+        match sqlx::query("UPDATE movie_torrent SET missing_since = $2 WHERE id = $1")
+            .bind(row.id)
+            .bind(missing_since)
+            .execute(connection)
+            .await
+        {
+            Ok(_) if missing_since.is_some() => {
+                tracing::warn!("Movie file of {} is gone", row.job_id);
+                report.missing.push(row.job_id.clone());
+            }
+            Ok(_) => report.restored.push(row.job_id.clone()),
+            Err(err) => report.errors.push(err.to_string()),
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn reconcile_torrents(
+    connection: &PgPool,
+    torrent_client: &dyn TorrentClient,
+    in_flight: &InFlightDownloads,
+    transcode_queue: &TranscodeQueue,
+    rows: &[MovieRow],
+    torrents: &[ClientTorrent],
+    download_root: &Path,
+    report: &mut ReconcileReport,
+) {
+    for torrent in torrents {
+        if rows.iter().any(|row| row.torrent_id == torrent.id) {
+            continue;
+        }
+        // torrents outside the downloads folder were added by someone else
+        let folder = match &torrent.output_folder {
+            Some(folder) if folder.parent() == Some(download_root) => folder,
+            _ => continue,
+        };
+        let movie = folder_name(folder)
+            .as_deref()
+            .and_then(parse_movie_folder)
+            .map(|(movie_id, source)| CronJobScheduler::build_job_id(movie_id, source));
+        if let Some(job_id) = &movie {
+            if in_flight.contains(job_id) {
+                continue;
+            }
+        }
+        match has_row(connection, torrent.id).await {
+            Ok(false) => {}
+            Ok(true) => continue,
+            Err(err) => {
+                report.errors.push(err);
+                continue;
+            }
+        }
+        let adoptable = movie
+            .as_ref()
+            .map(|job_id| !rows.iter().any(|row| &row.job_id == job_id))
+            .unwrap_or(false);
+        if adoptable {
+            match adopt_torrent(
+                connection,
+                torrent_client,
+                transcode_queue,
+                torrent.id,
+                folder,
+            )
+            .await
+            {
+                Ok(job_id) => {
+                    tracing::info!("Adopted torrent {} as {}", torrent.id, job_id);
+                    report.adopted.push(job_id);
+                    continue;
+                }
+                Err(err) => {
+                    tracing::warn!("Can't adopt torrent {}, removing it: {}", torrent.id, err);
+                    report.errors.push(err);
+                }
+            }
+        }
+        // the client deletes the files and the folder they are in
+        let torrent_path = folder.join(torrent.name.as_deref().unwrap_or_default());
+        match torrent_client
+            .delete_torrent(torrent.id, torrent_path.display().to_string())
+            .await
+        {
+            Ok(_) => report.removed_torrents.push(torrent.id),
+            Err(err) => report.errors.push(err),
+        }
+    }
+}
+
+// Gives a torrent that lost its row a new one and queues its transcode job,
+// the way `download_torrent` would have
+async fn adopt_torrent(
+    connection: &PgPool,
+    torrent_client: &dyn TorrentClient,
+    transcode_queue: &TranscodeQueue,
+    torrent_id: i32,
+    folder: &Path,
+) -> Result<String, String> {
+    let (movie_id, source) = folder_name(folder)
+        .as_deref()
+        .and_then(parse_movie_folder)
+        .ok_or_else(|| format!("{} isn't a movie folder", folder.display()))?;
+    let included: Vec<_> = torrent_client
+        .list_files(torrent_id)
+        .await?
+        .into_iter()
+        .filter(|file| file.included)
+        .collect();
+    let video = select_files(&included)
+        .ok_or_else(|| format!("Torrent {} has no video file", torrent_id))?
+        .video;
+    let folder = folder.display().to_string();
+    let movie_path = format!("{}/{}", folder, video.name);
+    let file_type = file_extension(video.name.as_str()).unwrap_or_default();
+    let subs = sidecar_subtitles(folder.as_str(), &included);
+    sqlx::query(
+        r#"
+            INSERT INTO movie_torrent (id, movie_source, movie_id, created_at, movie_path, torrent_id, file_type, available_subs, file_index, file_name, file_size)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)
+        "#,
+    )
+    .bind(Uuid::new_v4())
+    .bind(source.clone() as Source)
+    .bind(movie_id.clone())
+    .bind(Utc::now())
+    .bind(movie_path.clone())
+    .bind(torrent_id)
+    .bind(file_type)
+    .bind(&subs)
+    .bind(video.index as i32)
+    .bind(video.name)
+    .bind(video.length as i64)
+    .execute(connection)
+    .await
+    .map_err(|err| err.to_string())?;
+    if let Err(err) = transcode_queue
+        .enqueue(
+            connection,
+            movie_id.clone(),
+            source.clone(),
+            torrent_id,
+            movie_path,
+        )
+        .await
+    {
+        tracing::error!("Failed to queue transcode job {:#?}", err);
+    }
+    Ok(CronJobScheduler::build_job_id(movie_id, source))
+}
+
+async fn remove_orphan_dirs(
+    connection: &PgPool,
+    torrent_client: &dyn TorrentClient,
+    in_flight: &InFlightDownloads,
+    download_root: &Path,
+    report: &mut ReconcileReport,
+) {
+    let mut entries = match tokio::fs::read_dir(download_root).await {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return,
+        Err(err) => {
+            report.errors.push(err.to_string());
+            return;
+        }
+    };
+    let mut orphans: Vec<PathBuf> = Vec::new();
+    loop {
+        let entry = match entries.next_entry().await {
+            Ok(Some(entry)) => entry,
+            Ok(None) => break,
+            Err(err) => {
+                report.errors.push(err.to_string());
+                break;
+            }
+        };
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let movie = match parse_movie_folder(name.as_str()) {
+            Some((movie_id, source)) => CronJobScheduler::build_job_id(movie_id, source),
+            None => continue,
+        };
+        let is_dir = entry
+            .file_type()
+            .await
+            .map(|file_type| file_type.is_dir())
+            .unwrap_or(false);
+        if is_dir && !in_flight.contains(&movie) {
+            orphans.push(entry.path());
+        }
+    }
+    // looked up after the folders were listed, a movie started in between
+    // already shows up here
+    let used_folders = match used_folders(connection, torrent_client).await {
+        Ok(used_folders) => used_folders,
+        Err(err) => {
+            report.errors.push(err);
+            return;
+        }
+    };
+    orphans.retain(|orphan| {
+        folder_name(orphan)
+            .map(|name| !used_folders.contains(&name))
+            .unwrap_or(false)
+    });
+    for orphan in orphans {
+        match tokio::fs::remove_dir_all(&orphan).await {
+            Ok(_) => {
+                tracing::info!("Removed orphan directory {}", orphan.display());
+                report.removed_dirs.push(orphan.display().to_string());
+            }
+            Err(err) => report.errors.push(err.to_string()),
+        }
+    }
+}
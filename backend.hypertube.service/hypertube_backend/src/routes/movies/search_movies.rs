@@ -1,4 +1,4 @@
-use crate::routes::{movie_db_handler, yts_movie_search_handler};
+use crate::routes::{movie_db_handler, yts_movie_search_handler, TorrentIndexer};
 
 use super::{
     validate_title, Genre, MovieQuality, SearchOrder, SearchQueryMetadata, SortBy, Source,
@@ -32,6 +32,7 @@ pub struct Paginate {
 
 pub async fn get_movies_search(
     connection: Data<PgPool>,
+    torrent_indexer: Data<dyn TorrentIndexer>,
     body: Json<SearchBody>,
     info: Query<Paginate>,
 ) -> HttpResponse {
@@ -84,8 +85,13 @@ pub async fn get_movies_search(
         }
     } else if search_metadata.source == Source::MovieDb {
         tracing::info!("Calling the THE MOVIE DB Handler");
-        let result: Result<serde_json::Value, String> =
-            movie_db_handler(&connection, query_span.clone(), &search_metadata).await;
+        let result: Result<serde_json::Value, String> = movie_db_handler(
+            &connection,
+            torrent_indexer.get_ref(),
+            query_span.clone(),
+            &search_metadata,
+        )
+        .await;
         match result {
             Ok(response) => {
                 tracing::info!("Got Movie DB search response");
@@ -0,0 +1,136 @@
+use actix_web::web::Data;
+use futures_util::{future::BoxFuture, FutureExt};
+use std::ops::Range;
+use std::sync::Mutex;
+use tokio::time::{self, Duration, Instant};
+
+use super::range_responder::ReadGate;
+use super::torrent::{TorrentClient, TorrentFile};
+
+const RANGE_WAIT_TIMEOUT: Duration = Duration::from_secs(30);
+const RANGE_POLL_INTERVAL: Duration = Duration::from_millis(500);
+// how far past the playhead the torrent client is asked to fetch ahead
+const READ_AHEAD_BYTES: u64 = 16 * 1024 * 1024;
+
+#[derive(Debug)]
+pub enum RangeWaitError {
+    Timeout,
+    Client(String),
+}
+
+pub async fn locate_torrent_file(
+    torrent_client: &dyn TorrentClient,
+    torrent_id: i32,
+    movie_path: &str,
+) -> Result<TorrentFile, String> {
+    let files = torrent_client.list_files(torrent_id).await?;
+    files
+        .into_iter()
+        .find(|file| !file.name.is_empty() && movie_path.ends_with(file.name.as_str()))
+        .ok_or_else(|| format!("No file of torrent {} matches {}", torrent_id, movie_path))
+}
+
+// Waits until the first byte of `range` is on disk and returns the contiguous
+// chunk of the file that holds it. At most `READ_AHEAD_BYTES` of the range
+// are prioritized, players ask for everything up to the end of the file.
+pub async fn wait_for_range(
+    torrent_client: &dyn TorrentClient,
+    torrent_id: i32,
+    file_index: usize,
+    range: Range<u64>,
+) -> Result<Range<u64>, RangeWaitError> {
+    let deadline = Instant::now() + RANGE_WAIT_TIMEOUT;
+    let mut prioritized = false;
+
+    loop {
+        let available = torrent_client
+            .available_ranges(torrent_id, file_index)
+            .await
+            .map_err(RangeWaitError::Client)?;
+        if let Some(chunk) = available
+            .into_iter()
+            .find(|chunk| chunk.start <= range.start && range.start < chunk.end)
+        {
+            return Ok(chunk);
+        }
+
+        if !prioritized {
+            tracing::info!(
+                "Range {:?} of torrent {} not on disk yet, prioritizing",
+                range,
+                torrent_id
+            );
+            let read_ahead = range.start..std::cmp::min(range.end, range.start + READ_AHEAD_BYTES);
+            torrent_client
+                .prioritize_range(torrent_id, file_index, read_ahead)
+                .await
+                .map_err(RangeWaitError::Client)?;
+            prioritized = true;
+        }
+        if Instant::now() >= deadline {
+            return Err(RangeWaitError::Timeout);
+        }
+        time::sleep(RANGE_POLL_INTERVAL).await;
+    }
+}
+
+// Gate for streaming a file of a torrent that is still downloading, keeps the
+// pieces right after the playhead prioritized while the response is written.
+pub struct TorrentReadGate {
+    torrent_client: Data<dyn TorrentClient>,
+    torrent_id: i32,
+    file: TorrentFile,
+    prioritized_until: Mutex<u64>,
+}
+
+impl TorrentReadGate {
+    pub fn new(
+        torrent_client: Data<dyn TorrentClient>,
+        torrent_id: i32,
+        file: TorrentFile,
+    ) -> Self {
+        Self {
+            torrent_client,
+            torrent_id,
+            file,
+            prioritized_until: Mutex::new(0),
+        }
+    }
+}
+
+impl ReadGate for TorrentReadGate {
+    fn ready(&self, range: Range<u64>) -> BoxFuture<'_, Result<u64, RangeWaitError>> {
+        async move {
+            let available = wait_for_range(
+                self.torrent_client.get_ref(),
+                self.torrent_id,
+                self.file.index,
+                range.clone(),
+            )
+            .await?;
+
+            let read_ahead_end = std::cmp::min(range.start + READ_AHEAD_BYTES, self.file.length);
+            let read_ahead_start = {
+                let mut prioritized_until = self.prioritized_until.lock().unwrap();
+                // asking again for every chunk would flood the torrent client
+                if available.end >= read_ahead_end
+                    || *prioritized_until >= range.start + READ_AHEAD_BYTES / 2
+                {
+                    None
+                } else {
+                    let start = std::cmp::max(available.end, *prioritized_until);
+                    *prioritized_until = read_ahead_end;
+                    Some(start)
+                }
+            };
+            if let Some(start) = read_ahead_start.filter(|start| *start < read_ahead_end) {
+                let _ = self
+                    .torrent_client
+                    .prioritize_range(self.torrent_id, self.file.index, start..read_ahead_end)
+                    .await;
+            }
+            Ok(available.end)
+        }
+        .boxed()
+    }
+}
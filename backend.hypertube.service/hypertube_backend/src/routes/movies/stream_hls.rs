@@ -0,0 +1,301 @@
+use actix_web::{
+    http::header,
+    web::{Data, Path, Query},
+    HttpRequest, HttpResponse,
+};
+use serde::Deserialize;
+use serde_json::json;
+use sqlx::{PgPool, Row};
+use std::path::PathBuf;
+use tracing::Instrument;
+
+use super::active_streams::ActiveStreams;
+use super::range_responder::RangeResponder;
+use super::stream_limits::{too_many_streams, StreamLimits, StreamSlot};
+use super::stream_token::{authorize_stream, StreamTokens};
+use super::transcode::hls::{self, HlsRendition};
+use super::transcode::PlaybackMode;
+use super::{mark_movie_watched, CronJobScheduler, MovieQuality, Source};
+
+#[derive(Deserialize)]
+pub struct HlsMasterInfo {
+    pub source: Source,
+    pub movie_id: String,
+}
+
+#[derive(Deserialize)]
+pub struct HlsFileInfo {
+    pub source: Source,
+    pub movie_id: String,
+    pub quality: HlsRendition,
+    pub file_name: String,
+}
+
+#[derive(Deserialize)]
+pub struct HlsQuery {
+    pub token: Option<String>,
+}
+
+enum HlsPackage {
+    Packaged(PathBuf),
+    // browsers play the file as it is or remuxed, it never gets an HLS
+    // package and goes through the progressive stream instead
+    Progressive,
+}
+
+async fn get_hls_package(
+    connection: &PgPool,
+    movie_id: String,
+    source: Source,
+) -> Result<HlsPackage, HttpResponse> {
+    let query_span = tracing::info_span!("Get hls package");
+    match sqlx::query(
+        r#"
+            SELECT hls_path, playback_mode FROM movie_torrent WHERE movie_id = $1 AND movie_source = $2
+        "#,
+    )
+    .bind(movie_id)
+    .bind(source as Source)
+    .fetch_one(connection)
+    .instrument(query_span)
+    .await
+    {
+        Ok(row) => match (
+            row.get::<Option<String>, &str>("hls_path"),
+            row.get::<Option<PlaybackMode>, &str>("playback_mode"),
+        ) {
+            (Some(hls_path), _) => Ok(HlsPackage::Packaged(PathBuf::from(hls_path))),
+            (None, Some(PlaybackMode::DirectPlay | PlaybackMode::Remux)) => {
+                Ok(HlsPackage::Progressive)
+            }
+            (None, _) => Err(HttpResponse::NotFound().json(json!({
+                "error": "Movie is not packaged for adaptive streaming yet"
+            }))),
+        },
+        Err(sqlx::Error::RowNotFound) => {
+            tracing::error!("Torrent Not downloaded");
+            Err(HttpResponse::NotFound().finish())
+        }
+        Err(err) => {
+            tracing::error!("Database Error {:#?}", err);
+            Err(HttpResponse::BadRequest().json(json!({
+                "error": "Database error"
+            })))
+        }
+    }
+}
+
+// Same check as the progressive stream, the package holds every quality so
+// a token for any of them opens it. The stream counts against the user.
+// Gives the quality the token was issued for.
+async fn authorize_hls(
+    connection: &PgPool,
+    req: &HttpRequest,
+    source: &Source,
+    movie_id: &str,
+    token: Option<&str>,
+    stream_tokens: &StreamTokens,
+    stream_limits: &Data<StreamLimits>,
+) -> Result<(StreamSlot, Option<MovieQuality>), HttpResponse> {
+    let mut token_quality = None;
+    let user_id = authorize_stream(connection, req, token, |token| {
+        stream_tokens
+            .verify_any_quality(token, source, movie_id)
+            .map(|(user_id, quality)| {
+                token_quality = Some(quality);
+                user_id
+            })
+    })
+    .await?;
+    let job_id = CronJobScheduler::build_job_id(movie_id.to_string(), source.clone());
+    StreamLimits::acquire(stream_limits, user_id, job_id)
+        .map(|slot| (slot, token_quality))
+        .map_err(|err| too_many_streams(user_id, err))
+}
+
+// Sends the player to the progressive stream, a token is only good for the
+// quality it was issued for. Any quality serves the same file to a session.
+fn progressive_redirect(
+    source: &Source,
+    movie_id: &str,
+    quality: Option<MovieQuality>,
+    token: Option<&str>,
+) -> HttpResponse {
+    let mut location = format!(
+        "/movies/stream/{}/{}/{:?}",
+        source,
+        movie_id,
+        quality.unwrap_or(MovieQuality::Q720p)
+    );
+    if let Some(token) = token {
+        location.push_str(format!("?token={}", token).as_str());
+    }
+    HttpResponse::TemporaryRedirect()
+        .insert_header((header::LOCATION, location))
+        .finish()
+}
+
+async fn serve_hls_file(
+    req: &HttpRequest,
+    path: PathBuf,
+    file_name: &str,
+    token: Option<&str>,
+    slot: StreamSlot,
+    stream_limits: &StreamLimits,
+) -> HttpResponse {
+    let content_type = hls::content_type(file_name);
+    // the uris in the playlist carry the token on to the segments
+    if let (Some(token), true) = (token, file_name.ends_with(".m3u8")) {
+        return match tokio::fs::read_to_string(path.as_path()).await {
+            Ok(playlist) => HttpResponse::Ok()
+                .content_type(content_type)
+                .body(hls::with_token(playlist.as_str(), token)),
+            Err(err) => {
+                tracing::error!("Can't open {} {}", path.display(), err);
+                HttpResponse::NotFound().json(json!({
+                    "error": "File not found"
+                }))
+            }
+        };
+    }
+    match RangeResponder::open(path.as_path(), content_type).await {
+        Ok(responder) => {
+            responder
+                .hold(slot)
+                .throttle(stream_limits.bucket())
+                .respond(req)
+                .await
+        }
+        Err(err) => {
+            tracing::error!("Can't open {} {}", path.display(), err);
+            HttpResponse::NotFound().json(json!({
+                "error": "File not found"
+            }))
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub async fn stream_hls_master(
+    connection: Data<PgPool>,
+    info: Path<HlsMasterInfo>,
+    query: Query<HlsQuery>,
+    req: HttpRequest,
+    active_streams: Data<ActiveStreams>,
+    stream_tokens: Data<StreamTokens>,
+    stream_limits: Data<StreamLimits>,
+) -> HttpResponse {
+    let path_info = info.into_inner();
+    let (slot, token_quality) = match authorize_hls(
+        connection.as_ref(),
+        &req,
+        &path_info.source,
+        path_info.movie_id.as_str(),
+        query.token.as_deref(),
+        stream_tokens.as_ref(),
+        &stream_limits,
+    )
+    .await
+    {
+        Ok(authorized) => authorized,
+        Err(response) => return response,
+    };
+    let hls_dir = match get_hls_package(
+        connection.as_ref(),
+        path_info.movie_id.clone(),
+        path_info.source.clone(),
+    )
+    .await
+    {
+        Ok(HlsPackage::Packaged(dir)) => dir,
+        Ok(HlsPackage::Progressive) => {
+            return progressive_redirect(
+                &path_info.source,
+                path_info.movie_id.as_str(),
+                token_quality,
+                query.token.as_deref(),
+            )
+        }
+        Err(response) => return response,
+    };
+
+    active_streams.touch(
+        CronJobScheduler::build_job_id(path_info.movie_id.clone(), path_info.source.clone())
+            .as_str(),
+    );
+    // the player asks for the master playlist once per viewing
+    if let Err(err) =
+        mark_movie_watched(connection.as_ref(), path_info.movie_id, path_info.source).await
+    {
+        tracing::error!("Failed to update movie last watched time {}", err);
+    }
+
+    serve_hls_file(
+        &req,
+        hls_dir.join(hls::MASTER_PLAYLIST),
+        hls::MASTER_PLAYLIST,
+        query.token.as_deref(),
+        slot,
+        stream_limits.as_ref(),
+    )
+    .await
+}
+
+#[allow(clippy::too_many_arguments)]
+pub async fn stream_hls_file(
+    connection: Data<PgPool>,
+    info: Path<HlsFileInfo>,
+    query: Query<HlsQuery>,
+    req: HttpRequest,
+    active_streams: Data<ActiveStreams>,
+    stream_tokens: Data<StreamTokens>,
+    stream_limits: Data<StreamLimits>,
+) -> HttpResponse {
+    let path_info = info.into_inner();
+    if !hls::is_hls_file(path_info.file_name.as_str()) {
+        tracing::error!("Rejected hls file name {}", path_info.file_name);
+        return HttpResponse::NotFound().finish();
+    }
+    let slot = match authorize_hls(
+        connection.as_ref(),
+        &req,
+        &path_info.source,
+        path_info.movie_id.as_str(),
+        query.token.as_deref(),
+        stream_tokens.as_ref(),
+        &stream_limits,
+    )
+    .await
+    {
+        Ok((slot, _)) => slot,
+        Err(response) => return response,
+    };
+    // segments keep coming while the movie plays
+    active_streams.touch(
+        CronJobScheduler::build_job_id(path_info.movie_id.clone(), path_info.source.clone())
+            .as_str(),
+    );
+    let hls_dir =
+        match get_hls_package(connection.as_ref(), path_info.movie_id, path_info.source).await {
+            Ok(HlsPackage::Packaged(dir)) => dir,
+            // only the master playlist points the player elsewhere
+            Ok(HlsPackage::Progressive) => {
+                return HttpResponse::NotFound().json(json!({
+                    "error": "Movie is streamed progressively, it has no adaptive stream"
+                }))
+            }
+            Err(response) => return response,
+        };
+
+    serve_hls_file(
+        &req,
+        hls_dir
+            .join(path_info.quality.name())
+            .join(path_info.file_name.as_str()),
+        path_info.file_name.as_str(),
+        query.token.as_deref(),
+        slot,
+        stream_limits.as_ref(),
+    )
+    .await
+}
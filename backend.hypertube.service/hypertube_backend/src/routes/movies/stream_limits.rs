@@ -0,0 +1,172 @@
+use actix_web::{
+    http::header,
+    web::{Bytes, Data},
+    HttpResponse,
+};
+use futures_util::{stream, Stream, StreamExt};
+use serde_json::json;
+use std::collections::HashMap;
+use std::env;
+use std::io;
+use std::sync::Mutex;
+use tokio::time::{self, Duration, Instant};
+use uuid::Uuid;
+
+const DEFAULT_MAX_STREAMS_PER_USER: usize = 3;
+// players send a range request every few seconds while they buffer, a movie
+// stays one of the user's streams for this long after the last one
+const STREAM_ACTIVITY_WINDOW: Duration = Duration::from_secs(30);
+
+#[derive(Debug, PartialEq)]
+pub enum StreamLimitError {
+    TooManyStreams { max: usize },
+}
+
+struct UserStream {
+    open_responses: usize,
+    last_seen: Instant,
+}
+
+// Caps how many movies a user streams at once and how fast each response is
+// sent. Requests for a movie the user already streams don't open a new stream.
+pub struct StreamLimits {
+    max_streams: usize,
+    bytes_per_second: Option<u64>,
+    users: Mutex<HashMap<Uuid, HashMap<String, UserStream>>>,
+}
+
+impl StreamLimits {
+    pub fn new(max_streams: usize, bytes_per_second: Option<u64>) -> Self {
+        Self {
+            max_streams: max_streams.max(1),
+            bytes_per_second: bytes_per_second.filter(|rate| *rate > 0),
+            users: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // MAX_STREAMS_PER_USER and STREAM_BANDWIDTH_KBPS are optional, without a
+    // bandwidth the responses aren't throttled
+    pub fn from_env() -> Self {
+        let max_streams = env::var("MAX_STREAMS_PER_USER")
+            .ok()
+            .and_then(|value| value.parse::<usize>().ok())
+            .unwrap_or(DEFAULT_MAX_STREAMS_PER_USER);
+        let bytes_per_second = env::var("STREAM_BANDWIDTH_KBPS")
+            .ok()
+            .and_then(|value| value.parse::<u64>().ok())
+            .map(|kbps| kbps * 1024);
+        Self::new(max_streams, bytes_per_second)
+    }
+
+    // the stream counts against the user until the returned slot is dropped
+    // and the activity window ran out
+    pub fn acquire(
+        limits: &Data<StreamLimits>,
+        user_id: Uuid,
+        job_id: String,
+    ) -> Result<StreamSlot, StreamLimitError> {
+        let mut users = limits.users.lock().unwrap();
+        let streams = users.entry(user_id).or_default();
+        streams.retain(|_, stream| {
+            stream.open_responses > 0 || stream.last_seen.elapsed() < STREAM_ACTIVITY_WINDOW
+        });
+        if !streams.contains_key(&job_id) && streams.len() >= limits.max_streams {
+            return Err(StreamLimitError::TooManyStreams {
+                max: limits.max_streams,
+            });
+        }
+        let stream = streams.entry(job_id.clone()).or_insert(UserStream {
+            open_responses: 0,
+            last_seen: Instant::now(),
+        });
+        stream.open_responses += 1;
+        stream.last_seen = Instant::now();
+        Ok(StreamSlot {
+            limits: limits.clone(),
+            user_id,
+            job_id,
+        })
+    }
+
+    // a fresh bucket for one response, `None` when bandwidth isn't capped
+    pub fn bucket(&self) -> Option<TokenBucket> {
+        self.bytes_per_second.map(TokenBucket::new)
+    }
+}
+
+// the response to a stream `StreamLimits::acquire` refused
+pub fn too_many_streams(user_id: Uuid, err: StreamLimitError) -> HttpResponse {
+    let StreamLimitError::TooManyStreams { max } = err;
+    tracing::info!("User {} is over the limit of {} streams", user_id, max);
+    HttpResponse::TooManyRequests()
+        .insert_header((header::RETRY_AFTER, "30"))
+        .json(json!({
+            "error": format!("Too many concurrent streams, at most {} movies can be streamed at once", max)
+        }))
+}
+
+pub struct StreamSlot {
+    limits: Data<StreamLimits>,
+    user_id: Uuid,
+    job_id: String,
+}
+
+impl Drop for StreamSlot {
+    fn drop(&mut self) {
+        let mut users = self.limits.users.lock().unwrap();
+        if let Some(stream) = users
+            .get_mut(&self.user_id)
+            .and_then(|streams| streams.get_mut(&self.job_id))
+        {
+            stream.open_responses = stream.open_responses.saturating_sub(1);
+            stream.last_seen = Instant::now();
+        }
+    }
+}
+
+// Refills `rate` bytes a second and holds up to a second of them, a chunk
+// bigger than what is left puts the bucket in debt that is slept off.
+pub struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(bytes_per_second: u64) -> Self {
+        let rate = bytes_per_second.max(1) as f64;
+        Self {
+            rate,
+            tokens: rate,
+            refilled_at: Instant::now(),
+        }
+    }
+
+    pub async fn take(&mut self, bytes: u64) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.refilled_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.refilled_at = now;
+        self.tokens -= bytes as f64;
+        if self.tokens < 0.0 {
+            time::sleep(Duration::from_secs_f64(-self.tokens / self.rate)).await;
+        }
+    }
+}
+
+// Passes `body` through at the pace of `bucket`, as is without one
+pub fn throttle<S>(
+    body: S,
+    bucket: Option<TokenBucket>,
+) -> impl Stream<Item = Result<Bytes, io::Error>>
+where
+    S: Stream<Item = Result<Bytes, io::Error>> + Unpin,
+{
+    stream::unfold((body, bucket), |(mut body, mut bucket)| async move {
+        let chunk = body.next().await?;
+        if let (Ok(bytes), Some(bucket)) = (&chunk, bucket.as_mut()) {
+            bucket.take(bytes.len() as u64).await;
+        }
+        Some((chunk, (body, bucket)))
+    })
+}
@@ -0,0 +1,253 @@
+use actix_web::{
+    web::{Data, Path},
+    HttpMessage, HttpRequest, HttpResponse,
+};
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use serde::Deserialize;
+use serde_json::json;
+use sha2::Sha256;
+use sqlx::{PgPool, Row};
+use std::env;
+use std::rc::Rc;
+use uuid::Uuid;
+
+use super::{MovieQuality, Source};
+use crate::middleware::User;
+
+const DEFAULT_TOKEN_TTL_SECS: i64 = 4 * 60 * 60;
+
+type HmacSha256 = Hmac<Sha256>;
+
+#[derive(Debug, PartialEq)]
+pub enum StreamTokenError {
+    Malformed,
+    InvalidSignature,
+    Expired,
+}
+
+// Signs stream links as `{user_id}.{expires_at}.{signature}`, the signature
+// covers the user, the movie and the quality so a token opens nothing else.
+pub struct StreamTokens {
+    secret: Vec<u8>,
+    ttl: Duration,
+}
+
+impl StreamTokens {
+    pub fn new(secret: impl Into<Vec<u8>>, ttl: Duration) -> Self {
+        Self {
+            secret: secret.into(),
+            ttl,
+        }
+    }
+
+    // STREAM_TOKEN_SECRET and STREAM_TOKEN_TTL_SECS are optional, without a
+    // secret the links die with the server
+    pub fn from_env() -> Self {
+        let secret = match env::var("STREAM_TOKEN_SECRET") {
+            Ok(secret) if !secret.is_empty() => secret.into_bytes(),
+            _ => {
+                tracing::warn!("STREAM_TOKEN_SECRET not set, stream links won't survive a restart");
+                let mut secret = vec![0; 32];
+                rand::thread_rng().fill_bytes(&mut secret);
+                secret
+            }
+        };
+        let ttl = env::var("STREAM_TOKEN_TTL_SECS")
+            .ok()
+            .and_then(|value| value.parse::<i64>().ok())
+            .unwrap_or(DEFAULT_TOKEN_TTL_SECS);
+        Self::new(secret, Duration::seconds(ttl))
+    }
+
+    fn mac(
+        &self,
+        user_id: Uuid,
+        source: &Source,
+        movie_id: &str,
+        quality: MovieQuality,
+        expires_at: i64,
+    ) -> HmacSha256 {
+        let mut mac =
+            HmacSha256::new_from_slice(self.secret.as_slice()).expect("HMAC takes any key size");
+        mac.update(
+            format!(
+                "{}:{}:{}:{}:{}",
+                user_id,
+                source,
+                movie_id,
+                quality.label(),
+                expires_at
+            )
+            .as_bytes(),
+        );
+        mac
+    }
+
+    pub fn issue(
+        &self,
+        user_id: Uuid,
+        source: &Source,
+        movie_id: &str,
+        quality: MovieQuality,
+    ) -> (String, DateTime<Utc>) {
+        let expires_at = Utc::now() + self.ttl;
+        let signature = self
+            .mac(user_id, source, movie_id, quality, expires_at.timestamp())
+            .finalize()
+            .into_bytes();
+        (
+            format!(
+                "{}.{}.{}",
+                user_id,
+                expires_at.timestamp(),
+                hex::encode(signature)
+            ),
+            expires_at,
+        )
+    }
+
+    // the user the token was issued to
+    pub fn verify(
+        &self,
+        token: &str,
+        source: &Source,
+        movie_id: &str,
+        quality: MovieQuality,
+    ) -> Result<Uuid, StreamTokenError> {
+        let mut parts = token.splitn(3, '.');
+        let (user_id, expires_at, signature) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(user_id), Some(expires_at), Some(signature)) => (user_id, expires_at, signature),
+            _ => return Err(StreamTokenError::Malformed),
+        };
+        let user_id = Uuid::parse_str(user_id).map_err(|_| StreamTokenError::Malformed)?;
+        let expires_at: i64 = expires_at
+            .parse()
+            .map_err(|_| StreamTokenError::Malformed)?;
+        let signature = hex::decode(signature).map_err(|_| StreamTokenError::Malformed)?;
+        // the signature is checked first, an expired link may have been edited
+        self.mac(user_id, source, movie_id, quality, expires_at)
+            .verify_slice(signature.as_slice())
+            .map_err(|_| StreamTokenError::InvalidSignature)?;
+        match Utc.timestamp_opt(expires_at, 0).single() {
+            Some(expires_at) if expires_at > Utc::now() => Ok(user_id),
+            _ => Err(StreamTokenError::Expired),
+        }
+    }
+
+    // An HLS package holds every quality of the movie, a token issued for any
+    // of them opens it. Gives the user and the quality the token was issued for
+    pub fn verify_any_quality(
+        &self,
+        token: &str,
+        source: &Source,
+        movie_id: &str,
+    ) -> Result<(Uuid, MovieQuality), StreamTokenError> {
+        for quality in MovieQuality::ALL {
+            match self.verify(token, source, movie_id, quality) {
+                Ok(user_id) => return Ok((user_id, quality)),
+                Err(StreamTokenError::InvalidSignature) => continue,
+                Err(err) => return Err(err),
+            }
+        }
+        Err(StreamTokenError::InvalidSignature)
+    }
+}
+
+#[derive(Deserialize)]
+pub struct StreamTokenInfo {
+    pub source: Source,
+    pub movie_id: String,
+    pub quality: MovieQuality,
+}
+
+// a link the player can use without sending cookies
+pub async fn get_stream_token(
+    req: HttpRequest,
+    info: Path<StreamTokenInfo>,
+    stream_tokens: Data<StreamTokens>,
+) -> HttpResponse {
+    let user_id = match req.extensions().get::<Rc<User>>() {
+        Some(user) => user.id,
+        None => {
+            tracing::info!("User field not found in req object");
+            return HttpResponse::NotFound().json(json!({
+                "error": "user not found"
+            }));
+        }
+    };
+    let info = info.into_inner();
+    let (token, expires_at) =
+        stream_tokens.issue(user_id, &info.source, info.movie_id.as_str(), info.quality);
+    HttpResponse::Ok().json(json!({
+        "data": {
+            "url": format!(
+                "/movies/stream/{}/{}/{:?}?token={}",
+                info.source, info.movie_id, info.quality, token
+            ),
+            "token": token,
+            "expires_at": expires_at,
+        }
+    }))
+}
+
+// the user behind the session cookie, when the request has a live one
+pub async fn session_user(
+    connection: &PgPool,
+    req: &HttpRequest,
+) -> Result<Option<Uuid>, sqlx::Error> {
+    let session = match req
+        .cookie("session")
+        .and_then(|cookie| Uuid::parse_str(cookie.value()).ok())
+    {
+        Some(session) => session,
+        None => return Ok(None),
+    };
+    let row = sqlx::query(
+        r#"
+            SELECT user_id FROM sessions WHERE id = $1 AND expires_at > NOW()
+        "#,
+    )
+    .bind(session)
+    .fetch_optional(connection)
+    .await?;
+    Ok(row.map(|row| row.get("user_id")))
+}
+
+// The user a stream request is for, from its signed token when it has one,
+// `verify` checks the token against what the request opens. Otherwise from
+// the session cookie.
+pub async fn authorize_stream(
+    connection: &PgPool,
+    req: &HttpRequest,
+    token: Option<&str>,
+    verify: impl FnOnce(&str) -> Result<Uuid, StreamTokenError>,
+) -> Result<Uuid, HttpResponse> {
+    if let Some(token) = token {
+        return match verify(token) {
+            Ok(user_id) => Ok(user_id),
+            Err(StreamTokenError::Expired) => Err(HttpResponse::Unauthorized().json(json!({
+                "error": "Stream link expired"
+            }))),
+            Err(err) => {
+                tracing::warn!("Rejected stream token {:?}", err);
+                Err(HttpResponse::Forbidden().json(json!({
+                    "error": "Invalid stream token"
+                })))
+            }
+        };
+    }
+    match session_user(connection, req).await {
+        Ok(Some(user_id)) => Ok(user_id),
+        Ok(None) => Err(HttpResponse::Unauthorized().json(json!({
+            "error": "Stream token or session required"
+        }))),
+        Err(err) => {
+            tracing::error!("Database Error {:#?}", err);
+            Err(HttpResponse::BadRequest().json(json!({
+                "error": "Database error"
+            })))
+        }
+    }
+}
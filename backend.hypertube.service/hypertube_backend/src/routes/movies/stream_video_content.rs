@@ -1,17 +1,27 @@
-use crate::routes::{cancel_job, schedule_handler};
+use crate::routes::{mark_movie_watched, CronJobScheduler};
 
-use super::{CronJobScheduler, MovieQuality, Source};
-use actix_files::HttpRange;
+use super::active_streams::ActiveStreams;
+use super::range_responder::{RangeResponder, ReadGate};
+use super::stream_availability::{locate_torrent_file, TorrentReadGate};
+use super::stream_limits::{throttle, too_many_streams, StreamLimits};
+use super::stream_token::{authorize_stream, StreamTokens};
+use super::torrent::TorrentClient;
+use super::transcode::live::{live_mode, LiveSource};
+use super::transcode::{LiveTranscodes, PlaybackMode};
+use super::{MovieQuality, Source};
 use actix_web::{
-    http::header::{self, ContentRangeSpec},
-    web::{Data, Path},
+    http::{
+        header::{self, EntityTag},
+        Method,
+    },
+    web::{Data, Path, Query},
     HttpRequest, HttpResponse,
 };
+use futures_util::StreamExt;
 use serde::Deserialize;
 use serde_json::json;
 use sqlx::{PgPool, Row};
-use std::fs::File;
-use std::io::{Read, Seek, SeekFrom};
+use std::sync::Arc;
 use tracing::Instrument;
 
 #[derive(Deserialize)]
@@ -19,16 +29,48 @@ pub struct StreamInfo {
     pub movie_id: String,
     pub source: Source,
     // check if the requested quality exist's else start the conversion
-    pub _quality: MovieQuality,
+    pub quality: MovieQuality,
 }
 
+#[derive(Deserialize)]
+pub struct StreamQuery {
+    // where a live transcode starts, in seconds
+    pub start: Option<f64>,
+    // from `get_stream_token`, for players that can't send the session cookie
+    pub token: Option<String>,
+}
+
+#[allow(clippy::too_many_arguments)]
 pub async fn stream_video_content(
     connection: Data<PgPool>,
     info: Path<StreamInfo>,
+    query: Query<StreamQuery>,
     req: HttpRequest,
-    mut corn_job_handler: Data<CronJobScheduler>,
+    torrent_client: Data<dyn TorrentClient>,
+    active_streams: Data<ActiveStreams>,
+    live_transcodes: Data<LiveTranscodes>,
+    stream_tokens: Data<StreamTokens>,
+    stream_limits: Data<StreamLimits>,
 ) -> HttpResponse {
     let path_info = info.into_inner();
+    let user_id = match authorize_stream(
+        connection.as_ref(),
+        &req,
+        query.token.as_deref(),
+        |token| {
+            stream_tokens.verify(
+                token,
+                &path_info.source,
+                path_info.movie_id.as_str(),
+                path_info.quality,
+            )
+        },
+    )
+    .await
+    {
+        Ok(user_id) => user_id,
+        Err(response) => return response,
+    };
     let query_span = tracing::info_span!("Movie stream handler");
 
     let query_res = sqlx::query(
@@ -42,13 +84,26 @@ pub async fn stream_video_content(
     .instrument(query_span)
     .await;
 
-    let (movie_path, file_type) = match query_res {
+    let (movie_path, file_type, torrent_id, transcoded, playback_mode) = match query_res {
         Ok(torrent_info) => {
             tracing::info!("Got torrent row in database");
-            (
-                torrent_info.get::<&str, &str>("movie_path").to_string(),
-                torrent_info.get::<&str, &str>("file_type").to_string(),
-            )
+            // once transcoding finished the rendition is served instead of the torrent file
+            match torrent_info.get::<Option<String>, &str>("transcoded_path") {
+                Some(transcoded_path) => (
+                    transcoded_path,
+                    "mp4".to_string(),
+                    torrent_info.get::<i32, &str>("torrent_id"),
+                    true,
+                    None,
+                ),
+                None => (
+                    torrent_info.get::<&str, &str>("movie_path").to_string(),
+                    torrent_info.get::<&str, &str>("file_type").to_string(),
+                    torrent_info.get::<i32, &str>("torrent_id"),
+                    false,
+                    torrent_info.get::<Option<PlaybackMode>, &str>("playback_mode"),
+                ),
+            }
         }
         Err(sqlx::Error::RowNotFound) => {
             tracing::error!("Torrent Not downloaded");
@@ -62,90 +117,128 @@ pub async fn stream_video_content(
         }
     };
 
-    let movie = match File::open(movie_path.clone()) {
-        Ok(file) => {
-            tracing::info!("Opened file!");
-            file
-        }
-        Err(err) => {
-            tracing::error!("Can't Open file {}", err);
-            tracing::warn!("cant find file: {}", movie_path);
-            return HttpResponse::NotFound().json(json!({
-                "error": "File not found"
-            }));
+    // the file may still be downloading, the torrent client knows its real size
+    // and which parts of it already landed on disk
+    let torrent_file = if transcoded {
+        None
+    } else {
+        match locate_torrent_file(torrent_client.get_ref(), torrent_id, movie_path.as_str()).await {
+            Ok(file) => Some(file),
+            Err(err) => {
+                tracing::warn!("Serving {} without piece information: {}", movie_path, err);
+                None
+            }
         }
     };
 
-    // get file metadata
-    let metadata = match movie.metadata() {
-        Ok(metadata) => {
-            tracing::info!("Got file metadata");
-            metadata
+    // set movie as watched, pushes back its expiry
+    if req.method() != Method::HEAD {
+        if let Err(err) = mark_movie_watched(
+            connection.as_ref(),
+            path_info.movie_id.clone(),
+            path_info.source.clone(),
+        )
+        .await
+        {
+            tracing::error!("Failed to update movie last watched time {}", err);
         }
-        Err(err) => {
-            tracing::error!("Can't get file data {}", err);
-            return HttpResponse::BadRequest().finish();
+    }
+
+    let job_id = CronJobScheduler::build_job_id(path_info.movie_id, path_info.source);
+
+    // a HEAD request doesn't send the movie, it doesn't take a stream
+    let slot = if req.method() == Method::HEAD {
+        None
+    } else {
+        match StreamLimits::acquire(&stream_limits, user_id, job_id.clone()) {
+            Ok(slot) => Some(slot),
+            Err(err) => return too_many_streams(user_id, err),
         }
     };
 
-    // set movie as watched
-    let _ = cancel_job(
-        &mut corn_job_handler,
-        CronJobScheduler::build_job_id(path_info.movie_id.clone(), path_info.source.clone()),
-    )
-    .await;
-    let _ = schedule_handler(
-        &corn_job_handler,
-        CronJobScheduler::build_job_id(path_info.movie_id.clone(), path_info.source.clone()),
-        &connection,
-    )
-    .await;
+    // formats browsers can't play are converted while they stream, until the
+    // transcode worker has the full rendition ready
+    let live = live_mode(playback_mode, file_type.as_str()).filter(|_| !transcoded);
+    if let Some(mode) = live.filter(|_| req.method() != Method::HEAD) {
+        let source = match &torrent_file {
+            Some(file) => Some(LiveSource {
+                job_id: job_id.clone(),
+                input_path: movie_path.clone(),
+                length: file.length,
+                mode,
+                gate: Some(Arc::new(TorrentReadGate::new(
+                    torrent_client.clone(),
+                    torrent_id,
+                    file.clone(),
+                )) as Arc<dyn ReadGate>),
+            }),
+            None => tokio::fs::metadata(movie_path.as_str())
+                .await
+                .ok()
+                .map(|metadata| LiveSource {
+                    job_id: job_id.clone(),
+                    input_path: movie_path.clone(),
+                    length: metadata.len(),
+                    mode,
+                    gate: None,
+                }),
+        };
+        if let Some(source) = source {
+            match LiveTranscodes::join(&live_transcodes, source, query.start.unwrap_or(0.0)).await {
+                Ok(viewer) if viewer.wait_for_output().await => {
+                    let guard = ActiveStreams::open(&active_streams, job_id);
+                    let body = Box::pin(viewer.into_stream()).map(move |chunk| {
+                        let _streaming = (&guard, &slot);
+                        chunk
+                    });
+                    let body = throttle(body, stream_limits.bucket());
+                    // seeking goes through `start`, byte ranges mean nothing here
+                    return HttpResponse::Ok()
+                        .content_type("video/mp4")
+                        .insert_header((header::ACCEPT_RANGES, "none"))
+                        .streaming(body);
+                }
+                Ok(_) => tracing::warn!("Serving {} as it is, ffmpeg can't read it", movie_path),
+                Err(err) => tracing::warn!(
+                    "Serving {} as it is, live transcode failed: {}",
+                    movie_path,
+                    err
+                ),
+            }
+        }
+    }
 
-    let file_size = metadata.len();
-    // Check for Range header
-    if let Some(range_header) = req.headers().get(header::RANGE) {
-        if let Ok(range_str) = range_header.to_str() {
-            if range_str.is_empty() {
-                tracing::error!("empty range header");
-                return HttpResponse::BadRequest().json(json!({
-                    "error": "invalid range header"
+    let content_type = format!("video/{}", file_type);
+    let responder = match torrent_file {
+        Some(file) => {
+            // the torrent content never changes even though the file on disk keeps growing
+            let etag = EntityTag::new_strong(format!("{:x}-{:x}", torrent_id, file.length));
+            let size = file.length;
+            let gate = TorrentReadGate::new(torrent_client.clone(), torrent_id, file);
+            RangeResponder::new(movie_path.as_str(), size, content_type.as_str(), etag)
+                .gate(Arc::new(gate))
+        }
+        None => match RangeResponder::open(movie_path.as_str(), content_type.as_str()).await {
+            Ok(responder) => responder,
+            Err(err) => {
+                tracing::error!("Can't get file data {}", err);
+                tracing::warn!("cant find file: {}", movie_path);
+                return HttpResponse::NotFound().json(json!({
+                    "error": "File not found"
                 }));
             }
-            if let Ok(ranges) = HttpRange::parse(range_str, file_size) {
-                const CHUNK_SIZE_3_MB: u64 = 3_145_728;
-                let first_range = ranges[0];
-                let start = first_range.start;
-                let end =
-                    std::cmp::min(start + first_range.length - 1, start + CHUNK_SIZE_3_MB - 1);
-                let mut buffer = vec![0; (end - start + 1) as usize];
-                let mut file = movie;
-                file.seek(SeekFrom::Start(start)).unwrap();
-                file.read_exact(&mut buffer).unwrap();
+        },
+    };
 
-                let content_range = ContentRangeSpec::Bytes {
-                    range: Some((start, end)),
-                    instance_length: Some(file_size),
-                };
-                return HttpResponse::PartialContent()
-                    .insert_header((header::CONTENT_RANGE, content_range))
-                    .content_type(format!("video/{}", file_type))
-                    .body(buffer);
-            } else {
-                tracing::error!("Cant parse the range header");
-                HttpResponse::BadRequest().json(json!({
-                    "error": "invalid range header"
-                }))
-            }
-        } else {
-            tracing::error!("Invalid header value format");
-            HttpResponse::BadRequest().json(json!({
-                "error": "invalid range header"
-            }))
-        }
-    } else {
-        tracing::error!("Missing range header in the request");
-        HttpResponse::BadRequest().json(json!({
-            "error" : "Missing range header"
-        }))
-    }
+    // the movie can't be evicted while the response is being sent
+    let guard = ActiveStreams::open(&active_streams, job_id);
+    let responder = match slot {
+        Some(slot) => responder.hold(slot),
+        None => responder,
+    };
+    responder
+        .hold(guard)
+        .throttle(stream_limits.bucket())
+        .respond(&req)
+        .await
 }
@@ -0,0 +1,92 @@
+use super::TorrentFile;
+
+// containers we can stream or transcode, the earlier ones win ties
+const VIDEO_CONTAINERS: [&str; 7] = ["mp4", "webm", "m4v", "mkv", "mov", "avi", "wmv"];
+const SUBTITLE_EXTENSIONS: [&str; 5] = ["srt", "vtt", "ass", "ssa", "sub"];
+// words in a path that mark a video as something other than the feature
+const EXCLUDED_WORDS: [&str; 7] = [
+    "sample",
+    "samples",
+    "trailer",
+    "trailers",
+    "extra",
+    "extras",
+    "featurette",
+];
+
+// The files of a torrent worth downloading: the feature and the subtitles
+// shipped next to it.
+#[derive(Debug, Clone)]
+pub struct FileSelection {
+    pub video: TorrentFile,
+    pub subtitles: Vec<TorrentFile>,
+}
+
+impl FileSelection {
+    // what to pass as rqbit's `only_files`
+    pub fn file_indexes(&self) -> Vec<usize> {
+        let mut indexes: Vec<usize> = self.subtitles.iter().map(|file| file.index).collect();
+        indexes.push(self.video.index);
+        indexes.sort_unstable();
+        indexes
+    }
+
+    pub fn total_bytes(&self) -> u64 {
+        self.video.length + self.subtitles.iter().map(|file| file.length).sum::<u64>()
+    }
+}
+
+pub fn file_extension(name: &str) -> Option<String> {
+    let file_name = name.rsplit('/').next().unwrap_or(name);
+    file_name
+        .rsplit_once('.')
+        .map(|(_, ext)| ext.trim().to_ascii_lowercase())
+}
+
+pub fn is_subtitle_file(name: &str) -> bool {
+    file_extension(name)
+        .map(|ext| SUBTITLE_EXTENSIONS.contains(&ext.as_str()))
+        .unwrap_or(false)
+}
+
+fn container_rank(name: &str) -> Option<usize> {
+    let ext = file_extension(name)?;
+    VIDEO_CONTAINERS
+        .iter()
+        .position(|container| *container == ext.as_str())
+}
+
+fn is_extra(name: &str) -> bool {
+    name.to_ascii_lowercase()
+        .split(|c: char| !c.is_ascii_alphanumeric())
+        .any(|word| EXCLUDED_WORDS.contains(&word))
+}
+
+// Picks the largest video in an allowed container that isn't a sample,
+// trailer or extra. When every video looks like an extra the largest one is
+// still taken, release names aren't always honest.
+pub fn select_files(files: &[TorrentFile]) -> Option<FileSelection> {
+    let videos: Vec<&TorrentFile> = files
+        .iter()
+        .filter(|file| container_rank(file.name.as_str()).is_some())
+        .collect();
+    let best = |candidates: &mut dyn Iterator<Item = &&TorrentFile>| {
+        candidates
+            .max_by_key(|file| {
+                (
+                    file.length,
+                    std::cmp::Reverse(container_rank(file.name.as_str())),
+                )
+            })
+            .map(|file| (*file).clone())
+    };
+    let video = best(&mut videos.iter().filter(|file| !is_extra(file.name.as_str())))
+        .or_else(|| best(&mut videos.iter()))?;
+
+    let subtitles = files
+        .iter()
+        .filter(|file| is_subtitle_file(file.name.as_str()))
+        .cloned()
+        .collect();
+    Some(FileSelection { video, subtitles })
+}
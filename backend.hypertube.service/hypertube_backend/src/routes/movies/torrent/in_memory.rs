@@ -0,0 +1,340 @@
+use futures_util::{future::BoxFuture, FutureExt};
+use std::collections::HashMap;
+use std::ops::Range;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicI32, AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use super::{
+    file_extension, select_files, sidecar_subtitles, ClientTorrent, FileInfo, Magnet,
+    TorrentClient, TorrentFile, TorrentState, TorrentStatus,
+};
+
+type MagnetFiles = Vec<(String, Vec<u8>)>;
+
+struct InMemoryTorrent {
+    files: Vec<TorrentFile>,
+    folder: PathBuf,
+    state: TorrentState,
+    progress_bytes: u64,
+}
+
+// Torrent client that never leaves the process: magnets are resolved by info
+// hash against files registered with `register_magnet` and written straight
+// to `download_path`, so the download/stream/delete flow can run without rqbit.
+pub struct InMemoryTorrentClient {
+    download_path: PathBuf,
+    catalog: Mutex<HashMap<String, MagnetFiles>>,
+    torrents: Mutex<HashMap<i32, InMemoryTorrent>>,
+    prioritized: Mutex<Vec<(i32, usize, Range<u64>)>>,
+    reannounced: Mutex<Vec<i32>>,
+    availability_checks: AtomicUsize,
+    next_id: AtomicI32,
+}
+
+impl InMemoryTorrentClient {
+    pub fn new(download_path: impl Into<PathBuf>) -> Self {
+        InMemoryTorrentClient {
+            download_path: download_path.into(),
+            catalog: Mutex::new(HashMap::new()),
+            torrents: Mutex::new(HashMap::new()),
+            prioritized: Mutex::new(Vec::new()),
+            reannounced: Mutex::new(Vec::new()),
+            availability_checks: AtomicUsize::new(0),
+            next_id: AtomicI32::new(1),
+        }
+    }
+
+    pub fn register_magnet(&self, magnet: &str, files: MagnetFiles) {
+        let magnet = Magnet::parse(magnet).expect("Invalid magnet");
+        self.catalog
+            .lock()
+            .unwrap()
+            .insert(magnet.info_hash().to_string(), files);
+    }
+
+    fn resolve(&self, magnet: &str) -> Result<MagnetFiles, String> {
+        let magnet = Magnet::parse(magnet).map_err(|err| format!("Error: {}", err))?;
+        match self.catalog.lock().unwrap().get(magnet.info_hash()) {
+            Some(files) => Ok(files.clone()),
+            None => Err("Error: Unknown magnet".to_string()),
+        }
+    }
+
+    pub fn set_progress(&self, torrent_id: i32, progress_bytes: u64) {
+        if let Some(torrent) = self.torrents.lock().unwrap().get_mut(&torrent_id) {
+            torrent.progress_bytes = progress_bytes;
+        }
+    }
+
+    pub fn prioritized_ranges(&self) -> Vec<(i32, usize, Range<u64>)> {
+        self.prioritized.lock().unwrap().clone()
+    }
+
+    pub fn reannounced(&self) -> Vec<i32> {
+        self.reannounced.lock().unwrap().clone()
+    }
+
+    // how many times `available_ranges` was asked
+    pub fn availability_checks(&self) -> usize {
+        self.availability_checks.load(Ordering::SeqCst)
+    }
+
+    pub fn torrent_ids(&self) -> Vec<i32> {
+        self.torrents.lock().unwrap().keys().copied().collect()
+    }
+
+    fn set_state(&self, torrent_id: i32, state: TorrentState) -> Result<(), String> {
+        match self.torrents.lock().unwrap().get_mut(&torrent_id) {
+            Some(torrent) => {
+                torrent.state = state;
+                Ok(())
+            }
+            None => Err(format!("Torrent {} not found", torrent_id)),
+        }
+    }
+}
+
+impl TorrentClient for InMemoryTorrentClient {
+    fn add_torrent(
+        &self,
+        magnet: String,
+        output_folder: Option<String>,
+        only_files: Option<Vec<usize>>,
+    ) -> BoxFuture<'_, Result<FileInfo, String>> {
+        async move {
+            let content = self.resolve(magnet.as_str())?;
+            let folder = match output_folder {
+                Some(folder) => self.download_path.join(folder),
+                None => self.download_path.clone(),
+            };
+            std::fs::create_dir_all(&folder).map_err(|err| err.to_string())?;
+
+            let mut files = Vec::new();
+            for (index, (name, bytes)) in content.iter().enumerate() {
+                let included = only_files
+                    .as_ref()
+                    .map(|indexes| indexes.contains(&index))
+                    .unwrap_or(true);
+                if included {
+                    let path = folder.join(name);
+                    if let Some(parent) = path.parent() {
+                        std::fs::create_dir_all(parent).map_err(|err| err.to_string())?;
+                    }
+                    std::fs::write(path, bytes).map_err(|err| err.to_string())?;
+                }
+                files.push(TorrentFile {
+                    index,
+                    name: name.clone(),
+                    length: bytes.len() as u64,
+                    included,
+                });
+            }
+            let included: Vec<TorrentFile> =
+                files.iter().filter(|file| file.included).cloned().collect();
+            let video = match select_files(&included) {
+                Some(selection) => selection.video,
+                None => return Err("Error: Found no video file in torrent".to_string()),
+            };
+            let torrent_path = folder.join(video.name.as_str()).display().to_string();
+            let file_type = file_extension(video.name.as_str()).unwrap_or_default();
+            let subs = sidecar_subtitles(folder.display().to_string().as_str(), &included);
+            let total_bytes = included.iter().map(|file| file.length).sum();
+
+            let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+            self.torrents.lock().unwrap().insert(
+                id,
+                InMemoryTorrent {
+                    files,
+                    folder,
+                    state: TorrentState::Live,
+                    progress_bytes: total_bytes,
+                },
+            );
+            Ok(FileInfo::new(id.to_string(), torrent_path, subs, file_type).with_file(video))
+        }
+        .boxed()
+    }
+
+    fn inspect_magnet(&self, magnet: String) -> BoxFuture<'_, Result<Vec<TorrentFile>, String>> {
+        async move {
+            Ok(self
+                .resolve(magnet.as_str())?
+                .iter()
+                .enumerate()
+                .map(|(index, (name, content))| TorrentFile {
+                    index,
+                    name: name.clone(),
+                    length: content.len() as u64,
+                    included: true,
+                })
+                .collect())
+        }
+        .boxed()
+    }
+
+    fn download_path(&self) -> PathBuf {
+        self.download_path.clone()
+    }
+
+    fn torrent_status(&self, torrent_id: i32) -> BoxFuture<'_, Result<TorrentStatus, String>> {
+        async move {
+            let torrents = self.torrents.lock().unwrap();
+            let torrent = match torrents.get(&torrent_id) {
+                Some(torrent) => torrent,
+                None => return Err(format!("Torrent {} not found", torrent_id)),
+            };
+            let total_bytes = torrent
+                .files
+                .iter()
+                .filter(|file| file.included)
+                .map(|file| file.length)
+                .sum();
+            Ok(TorrentStatus {
+                state: torrent.state,
+                progress_bytes: torrent.progress_bytes,
+                total_bytes,
+                finished: torrent.progress_bytes >= total_bytes,
+                error: None,
+                download_speed: 0,
+                peers: 0,
+                eta_secs: None,
+            })
+        }
+        .boxed()
+    }
+
+    fn list_torrents(&self) -> BoxFuture<'_, Result<Vec<ClientTorrent>, String>> {
+        async move {
+            Ok(self
+                .torrents
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|(id, torrent)| ClientTorrent {
+                    id: *id,
+                    name: None,
+                    output_folder: Some(torrent.folder.clone()),
+                })
+                .collect())
+        }
+        .boxed()
+    }
+
+    fn pause_torrent(&self, torrent_id: i32) -> BoxFuture<'_, Result<(), String>> {
+        async move { self.set_state(torrent_id, TorrentState::Paused) }.boxed()
+    }
+
+    fn resume_torrent(&self, torrent_id: i32) -> BoxFuture<'_, Result<(), String>> {
+        async move { self.set_state(torrent_id, TorrentState::Live) }.boxed()
+    }
+
+    fn reannounce_torrent(&self, torrent_id: i32) -> BoxFuture<'_, Result<(), String>> {
+        async move {
+            if !self.torrents.lock().unwrap().contains_key(&torrent_id) {
+                return Err(format!("Torrent {} not found", torrent_id));
+            }
+            self.reannounced.lock().unwrap().push(torrent_id);
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn update_files(
+        &self,
+        torrent_id: i32,
+        only_files: Vec<usize>,
+    ) -> BoxFuture<'_, Result<(), String>> {
+        async move {
+            match self.torrents.lock().unwrap().get_mut(&torrent_id) {
+                Some(torrent) => {
+                    for file in torrent.files.iter_mut() {
+                        file.included = only_files.contains(&file.index);
+                    }
+                    Ok(())
+                }
+                None => Err(format!("Torrent {} not found", torrent_id)),
+            }
+        }
+        .boxed()
+    }
+
+    fn delete_torrent(
+        &self,
+        torrent_id: i32,
+        _torrent_path: String,
+    ) -> BoxFuture<'_, Result<(), String>> {
+        async move {
+            let torrent = match self.torrents.lock().unwrap().remove(&torrent_id) {
+                Some(torrent) => torrent,
+                None => return Err(format!("Torrent {} not found", torrent_id)),
+            };
+            if torrent.folder != self.download_path {
+                std::fs::remove_dir_all(&torrent.folder).map_err(|err| err.to_string())?;
+            }
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn list_files(&self, torrent_id: i32) -> BoxFuture<'_, Result<Vec<TorrentFile>, String>> {
+        async move {
+            match self.torrents.lock().unwrap().get(&torrent_id) {
+                Some(torrent) => Ok(torrent.files.clone()),
+                None => Err(format!("Torrent {} not found", torrent_id)),
+            }
+        }
+        .boxed()
+    }
+
+    // progress fills the included files in order, the same watermark rqbit exposes
+    fn available_ranges(
+        &self,
+        torrent_id: i32,
+        file_index: usize,
+    ) -> BoxFuture<'_, Result<Vec<Range<u64>>, String>> {
+        async move {
+            self.availability_checks.fetch_add(1, Ordering::SeqCst);
+            let torrents = self.torrents.lock().unwrap();
+            let torrent = match torrents.get(&torrent_id) {
+                Some(torrent) => torrent,
+                None => return Err(format!("Torrent {} not found", torrent_id)),
+            };
+            let length = match torrent.files.get(file_index) {
+                Some(file) if file.included => file.length,
+                Some(_) => return Ok(Vec::new()),
+                None => return Err(format!("File {} not found", file_index)),
+            };
+            let offset: u64 = torrent.files[..file_index]
+                .iter()
+                .filter(|file| file.included)
+                .map(|file| file.length)
+                .sum();
+            let available = torrent.progress_bytes.saturating_sub(offset).min(length);
+            if available == 0 {
+                return Ok(Vec::new());
+            }
+            Ok(vec![Range {
+                start: 0,
+                end: available,
+            }])
+        }
+        .boxed()
+    }
+
+    fn prioritize_range(
+        &self,
+        torrent_id: i32,
+        file_index: usize,
+        range: Range<u64>,
+    ) -> BoxFuture<'_, Result<(), String>> {
+        async move {
+            self.prioritized
+                .lock()
+                .unwrap()
+                .push((torrent_id, file_index, range));
+            Ok(())
+        }
+        .boxed()
+    }
+}
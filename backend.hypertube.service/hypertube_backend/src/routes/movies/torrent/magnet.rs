@@ -0,0 +1,252 @@
+use std::{env, fmt};
+
+const BTIH_PREFIX: &str = "urn:btih:";
+const BTMH_PREFIX: &str = "urn:btmh:";
+// sha2-256 multihash header of v2 info hashes
+const BTMH_SHA256_HEADER: &str = "1220";
+const BASE32_ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+// open trackers YTS lists next to its hashes
+const DEFAULT_TRACKERS: [&str; 8] = [
+    "udp://open.demonii.com:1337/announce",
+    "udp://tracker.openbittorrent.com:80",
+    "udp://tracker.coppersurfer.tk:6969",
+    "udp://glotorrents.pw:6969/announce",
+    "udp://tracker.opentrackr.org:1337/announce",
+    "udp://torrent.gresille.org:80/announce",
+    "udp://p4p.arenabg.com:1337",
+    "udp://tracker.leechers-paradise.org:6969",
+];
+
+#[derive(Debug, PartialEq)]
+pub enum MagnetError {
+    NotAMagnet,
+    MissingInfoHash,
+    InvalidInfoHash(String),
+    InvalidEncoding(String),
+}
+
+impl fmt::Display for MagnetError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MagnetError::NotAMagnet => write!(f, "not a magnet uri"),
+            MagnetError::MissingInfoHash => write!(f, "magnet has no info hash"),
+            MagnetError::InvalidInfoHash(hash) => write!(f, "invalid info hash {}", hash),
+            MagnetError::InvalidEncoding(value) => write!(f, "invalid percent encoding {}", value),
+        }
+    }
+}
+
+// A BitTorrent magnet uri. Info hashes are kept as lowercase hex, v1 ones
+// given in base32 are converted.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Magnet {
+    pub info_hash_v1: Option<String>,
+    // multihash, `1220` followed by the sha256 of the info dict
+    pub info_hash_v2: Option<String>,
+    pub display_name: Option<String>,
+    pub trackers: Vec<String>,
+}
+
+impl Magnet {
+    pub fn parse(uri: &str) -> Result<Self, MagnetError> {
+        let query = uri
+            .trim()
+            .strip_prefix("magnet:?")
+            .ok_or(MagnetError::NotAMagnet)?;
+        let mut magnet = Magnet {
+            info_hash_v1: None,
+            info_hash_v2: None,
+            display_name: None,
+            trackers: Vec::new(),
+        };
+        for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            let value = decode_component(value)?;
+            match key {
+                "xt" => {
+                    if let Some(hash) = value.strip_prefix(BTIH_PREFIX) {
+                        magnet.info_hash_v1 = Some(parse_btih(hash)?);
+                    } else if let Some(hash) = value.strip_prefix(BTMH_PREFIX) {
+                        magnet.info_hash_v2 = Some(parse_btmh(hash)?);
+                    }
+                }
+                "dn" => magnet.display_name = Some(value),
+                "tr" => magnet.trackers.push(value),
+                _ => {}
+            }
+        }
+        if magnet.info_hash_v1.is_none() && magnet.info_hash_v2.is_none() {
+            return Err(MagnetError::MissingInfoHash);
+        }
+        Ok(magnet)
+    }
+
+    // `hash` is a v1 (40 hex or 32 base32 chars) or a v2 (btmh multihash) info hash
+    pub fn from_info_hash(
+        hash: &str,
+        display_name: Option<String>,
+        trackers: Vec<String>,
+    ) -> Result<Self, MagnetError> {
+        let hash = hash.trim();
+        let (info_hash_v1, info_hash_v2) = if hash.len() == 68 {
+            (None, Some(parse_btmh(hash)?))
+        } else {
+            (Some(parse_btih(hash)?), None)
+        };
+        Ok(Magnet {
+            info_hash_v1,
+            info_hash_v2,
+            display_name,
+            trackers,
+        })
+    }
+
+    // what identifies the torrent, v1 when both are known
+    pub fn info_hash(&self) -> &str {
+        self.info_hash_v1
+            .as_deref()
+            .or(self.info_hash_v2.as_deref())
+            .unwrap_or_default()
+    }
+}
+
+impl fmt::Display for Magnet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut params = Vec::new();
+        if let Some(hash) = &self.info_hash_v1 {
+            params.push(format!("xt={}{}", BTIH_PREFIX, hash));
+        }
+        if let Some(hash) = &self.info_hash_v2 {
+            params.push(format!("xt={}{}", BTMH_PREFIX, hash));
+        }
+        if let Some(name) = &self.display_name {
+            params.push(format!("dn={}", encode_component(name)));
+        }
+        for tracker in self.trackers.iter() {
+            params.push(format!("tr={}", encode_component(tracker)));
+        }
+        write!(f, "magnet:?{}", params.join("&"))
+    }
+}
+
+// Trackers added to the magnets the server builds from catalog hashes,
+// `TORRENT_TRACKERS` takes a comma separated list
+pub struct MagnetTrackers {
+    trackers: Vec<String>,
+}
+
+impl Default for MagnetTrackers {
+    fn default() -> Self {
+        Self::new(
+            DEFAULT_TRACKERS
+                .iter()
+                .map(|tracker| tracker.to_string())
+                .collect(),
+        )
+    }
+}
+
+impl MagnetTrackers {
+    pub fn new(trackers: Vec<String>) -> Self {
+        Self { trackers }
+    }
+
+    pub fn from_env() -> Self {
+        match env::var("TORRENT_TRACKERS") {
+            Ok(value) if !value.trim().is_empty() => Self::new(
+                value
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|tracker| !tracker.is_empty())
+                    .map(str::to_string)
+                    .collect(),
+            ),
+            _ => Self::default(),
+        }
+    }
+
+    pub fn magnet_for(
+        &self,
+        info_hash: &str,
+        display_name: Option<String>,
+    ) -> Result<Magnet, MagnetError> {
+        Magnet::from_info_hash(info_hash, display_name, self.trackers.clone())
+    }
+}
+
+fn parse_btih(hash: &str) -> Result<String, MagnetError> {
+    if hash.len() == 40 && hash.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Ok(hash.to_ascii_lowercase());
+    }
+    if hash.len() == 32 {
+        if let Some(bytes) = decode_base32(hash) {
+            return Ok(bytes.iter().map(|byte| format!("{:02x}", byte)).collect());
+        }
+    }
+    Err(MagnetError::InvalidInfoHash(hash.to_string()))
+}
+
+fn parse_btmh(hash: &str) -> Result<String, MagnetError> {
+    let hash = hash.to_ascii_lowercase();
+    if hash.len() == 68
+        && hash.starts_with(BTMH_SHA256_HEADER)
+        && hash.chars().all(|c| c.is_ascii_hexdigit())
+    {
+        Ok(hash)
+    } else {
+        Err(MagnetError::InvalidInfoHash(hash))
+    }
+}
+
+fn decode_base32(value: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::new();
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+    for c in value.bytes() {
+        let digit = BASE32_ALPHABET
+            .iter()
+            .position(|letter| *letter == c.to_ascii_uppercase())?;
+        buffer = (buffer << 5) | digit as u32;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((buffer >> bits) as u8);
+            buffer &= (1 << bits) - 1;
+        }
+    }
+    Some(bytes)
+}
+
+fn decode_component(value: &str) -> Result<String, MagnetError> {
+    let mut bytes = Vec::with_capacity(value.len());
+    let mut input = value.bytes();
+    while let Some(byte) = input.next() {
+        match byte {
+            b'+' => bytes.push(b' '),
+            b'%' => {
+                let hex: Vec<u8> = input.by_ref().take(2).collect();
+                let decoded = std::str::from_utf8(&hex)
+                    .ok()
+                    .filter(|hex| hex.len() == 2)
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                    .ok_or_else(|| MagnetError::InvalidEncoding(value.to_string()))?;
+                bytes.push(decoded);
+            }
+            _ => bytes.push(byte),
+        }
+    }
+    String::from_utf8(bytes).map_err(|_| MagnetError::InvalidEncoding(value.to_string()))
+}
+
+fn encode_component(value: &str) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
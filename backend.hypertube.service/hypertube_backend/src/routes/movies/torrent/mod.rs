@@ -1,3 +1,149 @@
+pub mod file_selection;
+pub mod in_memory;
+pub mod magnet;
+pub mod release_name;
 pub mod rqbit_wrapper;
+pub mod sidecar_subtitles;
 
+pub use file_selection::*;
+pub use in_memory::*;
+pub use magnet::*;
+pub use release_name::*;
 pub use rqbit_wrapper::*;
+pub use sidecar_subtitles::*;
+
+use futures_util::future::BoxFuture;
+use serde::Serialize;
+use std::ops::Range;
+use std::path::PathBuf;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TorrentState {
+    Initializing,
+    Live,
+    Paused,
+    Error,
+}
+
+impl From<&str> for TorrentState {
+    fn from(value: &str) -> Self {
+        match value {
+            "initializing" => TorrentState::Initializing,
+            "live" => TorrentState::Live,
+            "paused" => TorrentState::Paused,
+            _ => TorrentState::Error,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DownloadState {
+    Queued,
+    Downloading,
+    Seeding,
+    Error,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TorrentStatus {
+    pub state: TorrentState,
+    pub progress_bytes: u64,
+    pub total_bytes: u64,
+    pub finished: bool,
+    pub error: Option<String>,
+    // bytes per second
+    pub download_speed: u64,
+    pub peers: u32,
+    pub eta_secs: Option<u64>,
+}
+
+impl TorrentStatus {
+    pub fn download_state(&self) -> DownloadState {
+        match self.state {
+            TorrentState::Error => DownloadState::Error,
+            _ if self.error.is_some() => DownloadState::Error,
+            _ if self.finished => DownloadState::Seeding,
+            TorrentState::Live => DownloadState::Downloading,
+            TorrentState::Initializing | TorrentState::Paused => DownloadState::Queued,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TorrentFile {
+    pub index: usize,
+    pub name: String,
+    pub length: u64,
+    pub included: bool,
+}
+
+// A torrent the client knows about, whether or not a movie row points at it
+#[derive(Debug, Clone, Serialize)]
+pub struct ClientTorrent {
+    pub id: i32,
+    pub name: Option<String>,
+    pub output_folder: Option<PathBuf>,
+}
+
+// Every call that reaches the torrent engine goes through this trait so the
+// handlers never depend on rqbit directly. The server registers one instance
+// as `Data<dyn TorrentClient>`.
+pub trait TorrentClient: Send + Sync {
+    fn add_torrent(
+        &self,
+        magnet: String,
+        output_folder: Option<String>,
+        // indexes of the files to download, all of them when `None`
+        only_files: Option<Vec<usize>>,
+    ) -> BoxFuture<'_, Result<FileInfo, String>>;
+
+    // resolves the magnet's file list without downloading anything
+    fn inspect_magnet(&self, magnet: String) -> BoxFuture<'_, Result<Vec<TorrentFile>, String>>;
+
+    // folder the client writes torrents to
+    fn download_path(&self) -> PathBuf;
+
+    fn torrent_status(&self, torrent_id: i32) -> BoxFuture<'_, Result<TorrentStatus, String>>;
+
+    fn list_torrents(&self) -> BoxFuture<'_, Result<Vec<ClientTorrent>, String>>;
+
+    fn pause_torrent(&self, torrent_id: i32) -> BoxFuture<'_, Result<(), String>>;
+
+    fn resume_torrent(&self, torrent_id: i32) -> BoxFuture<'_, Result<(), String>>;
+
+    // makes the client ask the trackers for peers again
+    fn reannounce_torrent(&self, torrent_id: i32) -> BoxFuture<'_, Result<(), String>>;
+
+    // only the files at `only_files` are downloaded from now on
+    fn update_files(
+        &self,
+        torrent_id: i32,
+        only_files: Vec<usize>,
+    ) -> BoxFuture<'_, Result<(), String>>;
+
+    // removes the torrent from the client and the movie dir from the file system
+    fn delete_torrent(
+        &self,
+        torrent_id: i32,
+        torrent_path: String,
+    ) -> BoxFuture<'_, Result<(), String>>;
+
+    fn list_files(&self, torrent_id: i32) -> BoxFuture<'_, Result<Vec<TorrentFile>, String>>;
+
+    // byte ranges of the file that are already on disk, `end` excluded
+    fn available_ranges(
+        &self,
+        torrent_id: i32,
+        file_index: usize,
+    ) -> BoxFuture<'_, Result<Vec<Range<u64>>, String>>;
+
+    // asks the client to fetch the pieces covering `range` before anything else
+    fn prioritize_range(
+        &self,
+        torrent_id: i32,
+        file_index: usize,
+        range: Range<u64>,
+    ) -> BoxFuture<'_, Result<(), String>>;
+}
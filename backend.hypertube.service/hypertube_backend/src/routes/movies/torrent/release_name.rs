@@ -0,0 +1,336 @@
+use serde::Serialize;
+
+use crate::routes::MovieQuality;
+
+// extensions stripped from file names before parsing
+const FILE_EXTENSIONS: [&str; 7] = ["mkv", "mp4", "m4v", "avi", "webm", "mov", "wmv"];
+const LANGUAGES: [&str; 14] = [
+    "multi",
+    "dual",
+    "english",
+    "french",
+    "truefrench",
+    "vostfr",
+    "german",
+    "italian",
+    "spanish",
+    "latino",
+    "russian",
+    "hindi",
+    "japanese",
+    "korean",
+];
+const AUDIO_CODECS: [&str; 12] = [
+    "aac", "ac3", "eac3", "dd", "ddp", "dts", "dts-hd", "dts-x", "truehd", "atmos", "flac", "opus",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReleaseSource {
+    // filmed in the theater
+    Cam,
+    Telesync,
+    Telecine,
+    Screener,
+    HdTv,
+    Dvd,
+    WebRip,
+    Web,
+    BluRay,
+    Remux,
+}
+
+impl ReleaseSource {
+    fn from_token(token: &str) -> Option<ReleaseSource> {
+        let source = match token {
+            "cam" | "camrip" | "cam-rip" | "hdcam" => ReleaseSource::Cam,
+            "ts" | "hdts" | "hd-ts" | "telesync" | "pdvd" => ReleaseSource::Telesync,
+            "tc" | "hdtc" | "telecine" => ReleaseSource::Telecine,
+            "scr" | "screener" | "dvdscr" | "bdscr" => ReleaseSource::Screener,
+            "hdtv" | "pdtv" => ReleaseSource::HdTv,
+            "dvd" | "dvdrip" | "dvd-rip" | "dvdr" => ReleaseSource::Dvd,
+            "webrip" | "web-rip" | "hdrip" => ReleaseSource::WebRip,
+            "web" | "web-dl" | "webdl" => ReleaseSource::Web,
+            "bluray" | "blu-ray" | "bdrip" | "brrip" | "bdrip1080p" => ReleaseSource::BluRay,
+            "remux" | "bdremux" => ReleaseSource::Remux,
+            _ => return None,
+        };
+        Some(source)
+    }
+
+    // how much better the picture usually is, theater recordings are worthless
+    fn rank(&self) -> u32 {
+        match self {
+            ReleaseSource::Cam | ReleaseSource::Telesync | ReleaseSource::Telecine => 0,
+            ReleaseSource::Screener => 1,
+            ReleaseSource::HdTv | ReleaseSource::Dvd => 2,
+            ReleaseSource::WebRip => 3,
+            ReleaseSource::Web => 4,
+            ReleaseSource::BluRay => 5,
+            ReleaseSource::Remux => 6,
+        }
+    }
+}
+
+// What a scene style release name like `Movie.Name.2019.1080p.BluRay.x264-GROUP`
+// tells about the release. Tokens the parser doesn't know are skipped.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct ReleaseName {
+    pub title: String,
+    pub year: Option<u16>,
+    // `720p`, `1080p`, `2160p`...
+    pub resolution: Option<String>,
+    pub source: Option<ReleaseSource>,
+    // normalized, `h264`, `h265`, `av1`...
+    pub codec: Option<String>,
+    pub audio: Option<String>,
+    pub language: Option<String>,
+    pub group: Option<String>,
+    pub hdr: bool,
+    pub three_d: bool,
+}
+
+fn is_year(token: &str) -> Option<u16> {
+    if token.len() != 4 {
+        return None;
+    }
+    token
+        .parse::<u16>()
+        .ok()
+        .filter(|year| (1900..2100).contains(year))
+}
+
+fn resolution(token: &str) -> Option<&'static str> {
+    let resolution = match token {
+        "480p" => "480p",
+        "576p" => "576p",
+        "720p" => "720p",
+        "1080p" | "1080i" => "1080p",
+        "2160p" | "4k" | "uhd" => "2160p",
+        _ => return None,
+    };
+    Some(resolution)
+}
+
+fn codec(token: &str) -> Option<&'static str> {
+    let codec = match token {
+        "x264" | "h264" | "avc" => "h264",
+        "x265" | "h265" | "hevc" => "h265",
+        "av1" => "av1",
+        "vp9" => "vp9",
+        "xvid" => "xvid",
+        "divx" => "divx",
+        _ => return None,
+    };
+    Some(codec)
+}
+
+fn is_audio(token: &str) -> bool {
+    // channels are glued to the codec, `DDP5.1`
+    let codec = token.trim_end_matches(|c: char| c.is_ascii_digit() || c == '.');
+    AUDIO_CODECS.contains(&codec) || AUDIO_CODECS.contains(&token)
+}
+
+fn is_hdr(token: &str) -> bool {
+    matches!(
+        token,
+        "hdr" | "hdr10" | "hdr10+" | "hdr10plus" | "dv" | "dovi" | "dolbyvision"
+    )
+}
+
+fn is_three_d(token: &str) -> bool {
+    matches!(token, "3d" | "hsbs" | "h-sbs" | "hou" | "h-ou")
+}
+
+// A `.` splits tokens unless it sits in audio channels like `5.1`: a digit
+// before it and a single digit after it
+fn tokenize(name: &str) -> Vec<String> {
+    let chars: Vec<char> = name.chars().collect();
+    let mut tokens = Vec::new();
+    let mut token = String::new();
+    for (index, c) in chars.iter().enumerate() {
+        let separator = match c {
+            ' ' | '_' | '[' | ']' | '(' | ')' | '{' | '}' | ',' => true,
+            '.' => {
+                let before = index
+                    .checked_sub(1)
+                    .map(|before| chars[before].is_ascii_digit())
+                    .unwrap_or(false);
+                let after = chars
+                    .get(index + 1)
+                    .map(char::is_ascii_digit)
+                    .unwrap_or(false);
+                let after_next = chars
+                    .get(index + 2)
+                    .map(char::is_ascii_digit)
+                    .unwrap_or(false);
+                !(before && after && !after_next)
+            }
+            _ => false,
+        };
+        if separator {
+            if !token.is_empty() {
+                tokens.push(std::mem::take(&mut token));
+            }
+        } else {
+            token.push(*c);
+        }
+    }
+    if !token.is_empty() {
+        tokens.push(token);
+    }
+    tokens
+}
+
+// the release group trails the name after a `-`, `x264-GROUP`
+fn split_group(name: &str) -> (&str, Option<String>) {
+    let (rest, group) = match name.rsplit_once('-') {
+        Some(split) => split,
+        None => return (name, None),
+    };
+    let is_group = !group.is_empty()
+        // `Spider-Man` is a title
+        && rest.contains(['.', ' ', '_'])
+        && group.chars().all(|c| c.is_ascii_alphanumeric())
+        // `WEB-DL` and `DTS-HD` end in a tag, not a group
+        && !matches!(
+            group.to_ascii_lowercase().as_str(),
+            "dl" | "hd" | "x" | "rip" | "ray" | "ts" | "sbs" | "ou"
+        );
+    if is_group {
+        (rest, Some(group.to_string()))
+    } else {
+        (name, None)
+    }
+}
+
+impl ReleaseName {
+    pub fn parse(name: &str) -> ReleaseName {
+        let mut name = name.trim();
+        // file names from the torrent, with their folders
+        if let Some((_, file_name)) = name.rsplit_once('/') {
+            name = file_name;
+        }
+        if let Some((stem, extension)) = name.rsplit_once('.') {
+            if FILE_EXTENSIONS.contains(&extension.to_ascii_lowercase().as_str()) {
+                name = stem;
+            }
+        }
+        // site tags in front, `[YTS.MX] Movie...`
+        if name.starts_with('[') {
+            if let Some((_, rest)) = name.split_once(']') {
+                name = rest.trim_start_matches([' ', '-', '.']);
+            }
+        }
+        let (name, group) = split_group(name.trim_end_matches([']', ' ']));
+
+        let mut release = ReleaseName {
+            group,
+            ..ReleaseName::default()
+        };
+        let mut title: Vec<String> = Vec::new();
+        let mut in_title = true;
+        // the year closest to the tags, `Blade.Runner.2049.2017...`
+        let mut year: Option<(u16, String)> = None;
+        let tokens = tokenize(name);
+        let mut index = 0;
+        while index < tokens.len() {
+            let original = tokens[index].as_str();
+            let mut token = original.to_ascii_lowercase();
+            // `H.264` is split by the tokenizer
+            if token == "h" {
+                if let Some(next @ ("264" | "265")) = tokens.get(index + 1).map(String::as_str) {
+                    token = format!("h{}", next);
+                    index += 1;
+                }
+            }
+            index += 1;
+
+            // a title is never only tags, `Cam.2018...` or `1917.2019...`
+            if title.is_empty() {
+                title.push(original.to_string());
+                continue;
+            }
+            if in_title {
+                if let Some(found) = is_year(token.as_str()) {
+                    if let Some((_, word)) = year.replace((found, original.to_string())) {
+                        title.push(word);
+                    }
+                    continue;
+                }
+            }
+            let after_title = !in_title || year.is_some();
+            let tagged = if let Some(resolution) = resolution(token.as_str()) {
+                release.resolution.get_or_insert(resolution.to_string());
+                true
+            } else if let Some(source) = ReleaseSource::from_token(token.as_str()) {
+                release.source.get_or_insert(source);
+                true
+            } else if let Some(codec) = codec(token.as_str()) {
+                release.codec.get_or_insert(codec.to_string());
+                true
+            } else if is_hdr(token.as_str()) {
+                release.hdr = true;
+                true
+            } else if is_three_d(token.as_str()) {
+                release.three_d = true;
+                true
+            } else if after_title && is_audio(token.as_str()) {
+                release.audio.get_or_insert(original.to_ascii_uppercase());
+                true
+            } else if after_title && LANGUAGES.contains(&token.as_str()) {
+                release
+                    .language
+                    .get_or_insert(original.to_ascii_uppercase());
+                true
+            } else {
+                false
+            };
+            // the first tag ends the title
+            if tagged {
+                in_title = false;
+            } else if in_title {
+                // a year followed by more title was part of it
+                if let Some((_, word)) = year.take() {
+                    title.push(word);
+                }
+                title.push(original.to_string());
+            }
+        }
+        release.year = year.map(|(year, _)| year);
+        release.title = title.join(" ");
+        release
+    }
+
+    // theater recordings, never downloaded
+    pub fn is_cam_rip(&self) -> bool {
+        matches!(
+            self.source,
+            Some(ReleaseSource::Cam | ReleaseSource::Telesync | ReleaseSource::Telecine)
+        )
+    }
+
+    pub fn quality(&self) -> Option<MovieQuality> {
+        if self.three_d {
+            return Some(MovieQuality::Q3D);
+        }
+        match self.resolution.as_deref()? {
+            "720p" => Some(MovieQuality::Q720p),
+            "1080p" => Some(MovieQuality::Q1080p),
+            "2160p" => Some(MovieQuality::Q2160p),
+            _ => None,
+        }
+    }
+
+    // Higher is better among releases of the same quality: the source first,
+    // then codecs browsers play without a transcode
+    pub fn rank(&self) -> u32 {
+        let source = self.source.map(|source| source.rank()).unwrap_or(2);
+        let codec = match self.codec.as_deref() {
+            Some("h264") | Some("av1") | Some("vp9") => 2,
+            Some("h265") => 1,
+            _ => 0,
+        };
+        source * 10 + codec
+    }
+}
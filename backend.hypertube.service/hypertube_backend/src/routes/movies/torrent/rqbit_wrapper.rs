@@ -1,15 +1,30 @@
+use futures_util::{future::BoxFuture, FutureExt};
 use reqwest::Client;
 use serde_json::Value;
-use std::{env, path::PathBuf};
+use std::{collections::HashMap, env, ops::Range, path::PathBuf, sync::Mutex};
+use tokio::task::AbortHandle;
+use tokio::time::{self, Duration};
+
+use super::{
+    file_extension, select_files, sidecar_subtitles, ClientTorrent, TorrentClient, TorrentFile,
+    TorrentState, TorrentStatus,
+};
+
 pub struct RqbitWrapper {
     pub origin: String,
     pub download_path: String,
+    // they never change, fetched once per torrent
+    piece_lengths: Mutex<HashMap<i32, u64>>,
+    // one read-ahead per file, a seek replaces it
+    read_aheads: Mutex<HashMap<(i32, usize), AbortHandle>>,
 }
 
 #[derive(Debug, serde::Serialize, serde::Deserialize)]
 pub struct SubInfo {
+    // ISO 639-1 code, `und` when the file names don't tell
     pub language: String,
     pub path: String,
+    pub format: String,
 }
 
 #[derive(Debug)]
@@ -18,10 +33,12 @@ pub struct FileInfo {
     pub path: String,
     pub available_subs: Option<Vec<Value>>,
     pub file_type: String,
+    // the torrent file `path` points to
+    pub file: Option<TorrentFile>,
 }
 
 impl FileInfo {
-    fn new(
+    pub fn new(
         id: impl Into<String>,
         path: impl Into<String>,
         available_subs: Option<Vec<Value>>,
@@ -32,33 +49,93 @@ impl FileInfo {
             path: path.into(),
             available_subs,
             file_type: file_type.into(),
+            file: None,
         }
     }
+
+    pub fn with_file(mut self, file: TorrentFile) -> Self {
+        self.file = Some(file);
+        self
+    }
 }
 
 fn get_download_folder() -> Result<PathBuf, String> {
-    let current_dir = env::current_dir().map_err(|err| format!("failed to get current directory{}", err))?;
-    let parent_dir = current_dir.parent().ok_or("Failed to get parrent Directory")?;
+    let current_dir =
+        env::current_dir().map_err(|err| format!("failed to get current directory{}", err))?;
+    let parent_dir = current_dir
+        .parent()
+        .ok_or("Failed to get parrent Directory")?;
     let target_folder = parent_dir.join("Downloads");
 
     Ok(target_folder)
 }
 
-impl Default for RqbitWrapper {
-    fn default() -> Self {
-        // let current_working_dir = match env::current_dir() {
-            // Ok(dir) => dir.display().to_string(),
-            // Err(_err) => "/tmp/Download".to_string(),
-        // };
-        let current_working_dir = "/home/rqbit/downloads";
-        tracing::info!("RQBIT WORKING DIR: {}", current_working_dir);
-        RqbitWrapper::new(env::var("RQBIT_HOST").unwrap().as_str(), current_working_dir)
+const RQBIT_DOWNLOAD_PATH: &str = "/home/rqbit/downloads";
+// a prioritized range that didn't arrive by then is given up on, the stream
+// asks again if it still needs it
+const PRIORITIZE_TIMEOUT: Duration = Duration::from_secs(120);
+
+// The parts of a file whose pieces are verified, from the torrent's have
+// bitfield (most significant bit first). `file_offset` is where the file
+// starts in the torrent, pieces span file boundaries.
+pub fn have_ranges(
+    bitfield: &[u8],
+    piece_length: u64,
+    file_offset: u64,
+    file_length: u64,
+) -> Vec<Range<u64>> {
+    let mut ranges: Vec<Range<u64>> = Vec::new();
+    if piece_length == 0 || file_length == 0 {
+        return ranges;
+    }
+    let file_end = file_offset + file_length;
+    for piece in file_offset / piece_length..=(file_end - 1) / piece_length {
+        let have = bitfield
+            .get((piece / 8) as usize)
+            .map(|byte| byte & (0x80 >> (piece % 8)) != 0)
+            .unwrap_or(false);
+        if !have {
+            continue;
+        }
+        let start = (piece * piece_length).max(file_offset) - file_offset;
+        let end = ((piece + 1) * piece_length).min(file_end) - file_offset;
+        match ranges.last_mut() {
+            Some(last) if last.end == start => last.end = end,
+            _ => ranges.push(start..end),
+        }
     }
+    ranges
 }
 
-fn is_video_file(file_name: &str) -> bool {
-    let video_extensions = [".mp4", ".mkv", ".flv", ".avi", ".mov", ".wmv"];
-    video_extensions.iter().any(|ext| file_name.ends_with(ext))
+// `12:piece lengthi262144e` in the bencoded metainfo
+fn bencoded_piece_length(metainfo: &[u8]) -> Option<u64> {
+    let key = b"12:piece lengthi";
+    let start = metainfo
+        .windows(key.len())
+        .position(|window| window == key)?
+        + key.len();
+    let end = start + metainfo[start..].iter().position(|byte| *byte == b'e')?;
+    std::str::from_utf8(&metainfo[start..end])
+        .ok()?
+        .parse()
+        .ok()
+}
+
+fn parse_files(files: &Value) -> Result<Vec<TorrentFile>, String> {
+    let files = match files.as_array() {
+        Some(files) => files,
+        None => return Err("Error: Found no files in response".to_string()),
+    };
+    Ok(files
+        .iter()
+        .enumerate()
+        .map(|(index, file)| TorrentFile {
+            index,
+            name: file["name"].as_str().unwrap_or_default().to_string(),
+            length: file["length"].as_u64().unwrap_or(0),
+            included: file["included"].as_bool().unwrap_or(true),
+        })
+        .collect())
 }
 
 impl RqbitWrapper {
@@ -66,140 +143,419 @@ impl RqbitWrapper {
         RqbitWrapper {
             origin: origin.into(),
             download_path: download_path.into(),
+            piece_lengths: Mutex::new(HashMap::new()),
+            read_aheads: Mutex::new(HashMap::new()),
         }
     }
 
-    // delete the remaining movie dir from the file system
-    pub async fn delete_torrent(
-        &self,
-        torrent_id: i32,
-        torrent_path: String,
-    ) -> Result<(), String> {
-        let client: Client = Client::new();
-        let url = format!("{}/torrents/{}/delete", self.origin.as_str(), torrent_id);
+    // RQBIT_DOWNLOAD_PATH is optional and has to match rqbit's own download folder
+    pub fn from_env() -> Result<Self, std::io::Error> {
+        let origin = env::var("RQBIT_HOST")
+            .map_err(|e| std::io::Error::other(format!("RQBIT_HOST {}.", e)))?;
+        let download_path =
+            env::var("RQBIT_DOWNLOAD_PATH").unwrap_or_else(|_| RQBIT_DOWNLOAD_PATH.to_string());
+        tracing::info!("RQBIT WORKING DIR: {}", download_path);
+        Ok(RqbitWrapper::new(origin, download_path))
+    }
+
+    async fn post_action(&self, torrent_id: i32, action: &str) -> Result<(), String> {
+        let client = Client::new();
+        let url = format!(
+            "{}/torrents/{}/{}",
+            self.origin.as_str(),
+            torrent_id,
+            action
+        );
         let response = match client.post(url).send().await {
-            Ok(res) => {
-                tracing::info!("Sent Delete request successfully");
-                res
-            }
+            Ok(res) => res,
             Err(err) => {
-                tracing::info!("Err trying to send delete request to the client");
+                tracing::error!("Err trying to send {} request to the client", action);
                 return Err(err.to_string());
             }
         };
         if !response.status().is_success() {
-            tracing::error!("Failed to delete the torrent from the client");
-            return Err("Failed to delete the torrent from the client".to_string());
+            tracing::error!(
+                "Torrent client refused {} for torrent {}",
+                action,
+                torrent_id
+            );
+            return Err(format!("Failed to {} the torrent", action));
         }
+        Ok(())
+    }
 
-        let path = std::path::Path::new(torrent_path.as_str());
-        if let Some(parent_path) = path.parent() {
-            match std::fs::remove_dir_all(parent_path.to_str().unwrap()) {
-                Ok(_) => {
-                    tracing::info!("Dir deleted successfully")
-                }
-                Err(err) => {
-                    tracing::error!("Failed to delete dir {:#?}", err);
-                    return Err(err.to_string());
-                }
+    async fn get_bytes(&self, url: String) -> Result<Vec<u8>, String> {
+        let response = match Client::new().get(url).send().await {
+            Ok(res) => res,
+            Err(err) => {
+                tracing::error!("{:#?}", err);
+                return Err("Error: Failed to request torrent client".to_string());
+            }
+        };
+        if !response.status().is_success() {
+            return Err(format!(
+                "Error: Torrent client responded with {}",
+                response.status()
+            ));
+        }
+        match response.bytes().await {
+            Ok(body) => Ok(body.to_vec()),
+            Err(err) => {
+                tracing::error!("{:#?}", err);
+                Err("Error: Failed to get request body".to_string())
             }
         }
+    }
 
-        Ok(())
+    async fn piece_length(&self, torrent_id: i32) -> Result<u64, String> {
+        if let Some(length) = self.piece_lengths.lock().unwrap().get(&torrent_id) {
+            return Ok(*length);
+        }
+        let metainfo = self
+            .get_bytes(format!("{}/torrents/{}/metadata", self.origin, torrent_id))
+            .await?;
+        let length = bencoded_piece_length(metainfo.as_slice())
+            .ok_or_else(|| "Error: No piece length in torrent metadata".to_string())?;
+        self.piece_lengths
+            .lock()
+            .unwrap()
+            .insert(torrent_id, length);
+        Ok(length)
+    }
+
+    async fn get_json(&self, url: String) -> Result<Value, String> {
+        let client = Client::new();
+        let response = match client.get(url).send().await {
+            Ok(res) => res,
+            Err(err) => {
+                tracing::error!("{:#?}", err);
+                return Err("Error: Failed to request torrent client".to_string());
+            }
+        };
+        if !response.status().is_success() {
+            return Err(format!(
+                "Error: Torrent client responded with {}",
+                response.status()
+            ));
+        }
+        match response.json::<Value>().await {
+            Ok(body) => Ok(body),
+            Err(err) => {
+                tracing::error!("{:#?}", err);
+                Err("Error: Failed to get request body".to_string())
+            }
+        }
     }
+}
 
+impl TorrentClient for RqbitWrapper {
+    fn delete_torrent(
+        &self,
+        torrent_id: i32,
+        torrent_path: String,
+    ) -> BoxFuture<'_, Result<(), String>> {
+        async move {
+            self.post_action(torrent_id, "delete").await?;
+            self.piece_lengths.lock().unwrap().remove(&torrent_id);
+            self.read_aheads
+                .lock()
+                .unwrap()
+                .retain(|(id, _), read_ahead| {
+                    if *id == torrent_id {
+                        read_ahead.abort();
+                    }
+                    *id != torrent_id
+                });
+            tracing::info!("Sent Delete request successfully");
 
+            let path = std::path::Path::new(torrent_path.as_str());
+            if let Some(parent_path) = path.parent() {
+                match std::fs::remove_dir_all(parent_path) {
+                    Ok(_) => {
+                        tracing::info!("Dir deleted successfully")
+                    }
+                    Err(err) => {
+                        tracing::error!("Failed to delete dir {:#?}", err);
+                        return Err(err.to_string());
+                    }
+                }
+            }
 
-    pub async fn download_torrent(
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn add_torrent(
         &self,
-        magnet: impl Into<String>,
+        magnet: String,
         output_folder: Option<String>,
-    ) -> Result<FileInfo, String> {
-        let client = Client::new();
-        let rqbit_base_path = "/home/rqbit/downloads";
-        let url = {
+        only_files: Option<Vec<usize>>,
+    ) -> BoxFuture<'_, Result<FileInfo, String>> {
+        async move {
+            let client = Client::new();
+            let url = {
+                let mut params = Vec::new();
+                if let Some(folder) = output_folder.as_ref() {
+                    params.push(format!("output_folder={}/{}", self.download_path, folder));
+                }
+                if let Some(indexes) = only_files.as_ref() {
+                    let indexes: Vec<String> =
+                        indexes.iter().map(|index| index.to_string()).collect();
+                    params.push(format!("only_files={}", indexes.join(",")));
+                }
+                let mut base = format!("{}/torrents", self.origin.as_str());
+                if !params.is_empty() {
+                    base.push_str(format!("?{}", params.join("&")).as_str());
+                }
+                base
+            };
+            tracing::info!("DOWNLOAD URL: {}", url);
+            let response = match client.post(url).body(magnet).send().await {
+                Ok(res) => match res.json::<Value>().await {
+                    Ok(body) => body,
+                    Err(err) => {
+                        tracing::error!("{:#?}", err);
+                        return Err("Error: Failed to get request body".to_string());
+                    }
+                },
+                Err(err) => {
+                    tracing::error!("{:#?}", err);
+                    return Err("Error: Failed to request torrent client".to_string());
+                }
+            };
+            tracing::info!("TORRENT CLIENT RESPONSE {:#?}", response);
+            let torrent_id = match response["id"].as_number() {
+                Some(id) => id.to_string(),
+                None => return Err("Error: No torrent id in response body".to_string()),
+            };
+            let included: Vec<TorrentFile> = parse_files(&response["details"]["files"])?
+                .into_iter()
+                .filter(|file| file.included)
+                .collect();
+            let video = match select_files(&included) {
+                Some(selection) => selection.video,
+                None => return Err("Error: Found no video file in torrent".to_string()),
+            };
+            let folder = output_folder.unwrap_or_else(|| self.download_path.clone());
+            let torrent_path = format!("{}/{}", folder, video.name);
+            let file_type = file_extension(video.name.as_str()).unwrap_or_default();
+            let subs = sidecar_subtitles(folder.as_str(), &included);
+            Ok(FileInfo::new(torrent_id, torrent_path, subs, file_type).with_file(video))
+        }
+        .boxed()
+    }
 
-            let mut base: String;
-            if output_folder.is_some() {
-                base = format!("{}/torrents", self.origin.as_str());
-                base.push_str(format!("?output_folder={}/{}", rqbit_base_path ,output_folder.clone().unwrap().as_str()).as_str());
-            }
-            else {
-                base = format!("{}/torrents", self.origin.as_str());
-            }
-            base
-        };
-        tracing::info!("DOWNLOAD URL: {}", url);
-        let response = match client.post(url).body(magnet.into()).send().await {
-            Ok(res) => match res.json::<Value>().await {
-                Ok(body) => body,
+    fn inspect_magnet(&self, magnet: String) -> BoxFuture<'_, Result<Vec<TorrentFile>, String>> {
+        async move {
+            let client = Client::new();
+            let url = format!("{}/torrents?list_only=true", self.origin.as_str());
+            let response = match client.post(url).body(magnet).send().await {
+                Ok(res) => match res.json::<Value>().await {
+                    Ok(body) => body,
+                    Err(err) => {
+                        tracing::error!("{:#?}", err);
+                        return Err("Error: Failed to get request body".to_string());
+                    }
+                },
                 Err(err) => {
                     tracing::error!("{:#?}", err);
-                    return Err("Error: Failed to get request body".to_string());
+                    return Err("Error: Failed to request torrent client".to_string());
                 }
-            },
-            Err(err) => {
-                tracing::error!("{:#?}", err);
-                return Err("Error: Failed to request torrent client".to_string());
+            };
+            parse_files(&response["details"]["files"])
+        }
+        .boxed()
+    }
+
+    fn download_path(&self) -> PathBuf {
+        PathBuf::from(self.download_path.as_str())
+    }
+
+    fn torrent_status(&self, torrent_id: i32) -> BoxFuture<'_, Result<TorrentStatus, String>> {
+        async move {
+            let stats = self
+                .get_json(format!("{}/torrents/{}/stats/v1", self.origin, torrent_id))
+                .await?;
+            Ok(TorrentStatus {
+                state: TorrentState::from(stats["state"].as_str().unwrap_or_default()),
+                progress_bytes: stats["progress_bytes"].as_u64().unwrap_or(0),
+                total_bytes: stats["total_bytes"].as_u64().unwrap_or(0),
+                finished: stats["finished"].as_bool().unwrap_or(false),
+                error: stats["error"].as_str().map(|e| e.to_string()),
+                // rqbit reports speed in MiB/s
+                download_speed: (stats["live"]["download_speed"]["mbps"]
+                    .as_f64()
+                    .unwrap_or(0.0)
+                    * 1024.0
+                    * 1024.0) as u64,
+                peers: stats["live"]["snapshot"]["peer_stats"]["live"]
+                    .as_u64()
+                    .unwrap_or(0) as u32,
+                eta_secs: stats["live"]["time_remaining"]["duration"]["secs"].as_u64(),
+            })
+        }
+        .boxed()
+    }
+
+    fn list_torrents(&self) -> BoxFuture<'_, Result<Vec<ClientTorrent>, String>> {
+        async move {
+            let body = self.get_json(format!("{}/torrents", self.origin)).await?;
+            let torrents = match body["torrents"].as_array() {
+                Some(torrents) => torrents,
+                None => return Err("Error: Found no torrents in response".to_string()),
+            };
+            Ok(torrents
+                .iter()
+                .filter_map(|torrent| {
+                    Some(ClientTorrent {
+                        id: torrent["id"].as_i64()? as i32,
+                        name: torrent["name"].as_str().map(|name| name.to_string()),
+                        output_folder: torrent["output_folder"].as_str().map(PathBuf::from),
+                    })
+                })
+                .collect())
+        }
+        .boxed()
+    }
+
+    fn pause_torrent(&self, torrent_id: i32) -> BoxFuture<'_, Result<(), String>> {
+        self.post_action(torrent_id, "pause").boxed()
+    }
+
+    fn resume_torrent(&self, torrent_id: i32) -> BoxFuture<'_, Result<(), String>> {
+        self.post_action(torrent_id, "start").boxed()
+    }
+
+    // rqbit has no re-announce endpoint, a torrent announces to its trackers
+    // whenever it starts
+    fn reannounce_torrent(&self, torrent_id: i32) -> BoxFuture<'_, Result<(), String>> {
+        async move {
+            self.post_action(torrent_id, "pause").await?;
+            self.post_action(torrent_id, "start").await
+        }
+        .boxed()
+    }
+
+    fn update_files(
+        &self,
+        torrent_id: i32,
+        only_files: Vec<usize>,
+    ) -> BoxFuture<'_, Result<(), String>> {
+        async move {
+            let url = format!("{}/torrents/{}/update_only_files", self.origin, torrent_id);
+            let response = match Client::new()
+                .post(url)
+                .json(&serde_json::json!({ "only_files": only_files }))
+                .send()
+                .await
+            {
+                Ok(res) => res,
+                Err(err) => {
+                    tracing::error!("{:#?}", err);
+                    return Err("Error: Failed to request torrent client".to_string());
+                }
+            };
+            if !response.status().is_success() {
+                return Err(format!(
+                    "Error: Torrent client responded with {}",
+                    response.status()
+                ));
             }
-        };
-        tracing::info!("TORRENT CLIENT RESPONSE {:#?}", response);
-        let torrent_id = match response["id"].as_number() {
-            Some(id) => id.to_string(),
-            None => return Err("Error: No torrent id in response body".to_string()),
-        };
-        let mut torrent_path = {
-            if let Some(output_path) = output_folder {
-                // let base = get_download_folder();
-                // let base = match get_download_folder() {
-                //     Ok(val) => val,
-                //     Err(err) => return Err(err)
-
-                // };
-                // base.join(output_path).to_str().unwrap().to_string()
-                output_path
-            } else {
-                self.download_path.clone()
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn list_files(&self, torrent_id: i32) -> BoxFuture<'_, Result<Vec<TorrentFile>, String>> {
+        async move {
+            let details = self
+                .get_json(format!("{}/torrents/{}", self.origin, torrent_id))
+                .await?;
+            parse_files(&details["files"])
+        }
+        .boxed()
+    }
+
+    // rqbit downloads pieces out of order, the ranges come from the pieces it
+    // verified
+    fn available_ranges(
+        &self,
+        torrent_id: i32,
+        file_index: usize,
+    ) -> BoxFuture<'_, Result<Vec<Range<u64>>, String>> {
+        async move {
+            let files = self.list_files(torrent_id).await?;
+            let file = match files.get(file_index) {
+                Some(file) => file,
+                None => return Err("Error: File not found in torrent".to_string()),
+            };
+            let file_offset: u64 = files[..file_index].iter().map(|file| file.length).sum();
+            let piece_length = self.piece_length(torrent_id).await?;
+            let bitfield = self
+                .get_bytes(format!("{}/torrents/{}/haves", self.origin, torrent_id))
+                .await?;
+            Ok(have_ranges(
+                bitfield.as_slice(),
+                piece_length,
+                file_offset,
+                file.length,
+            ))
+        }
+        .boxed()
+    }
+
+    fn prioritize_range(
+        &self,
+        torrent_id: i32,
+        file_index: usize,
+        range: Range<u64>,
+    ) -> BoxFuture<'_, Result<(), String>> {
+        // rqbit bumps the priority of the pieces an open stream is waiting on,
+        // so reading the range through its stream endpoint is enough.
+        let url = format!(
+            "{}/torrents/{}/stream/{}",
+            self.origin, torrent_id, file_index
+        );
+        async move {
+            if range.is_empty() {
+                return Ok(());
             }
-        };
-        // let movie_content_dir = torrent_path.clone();
-        let (torrent_subs, torrent_file_type) = {
-            let torrent_files_arr = match response["details"]["files"].as_array() {
-                Some(fields) => fields,
-                None => return Err("Error: Found no files in response".to_string()),
+            let read = async move {
+                let mut response = Client::new()
+                    .get(url)
+                    .header(
+                        reqwest::header::RANGE,
+                        format!("bytes={}-{}", range.start, range.end - 1),
+                    )
+                    .send()
+                    .await?;
+                // the bytes are read so rqbit fetches them, none are kept
+                while response.chunk().await?.is_some() {}
+                Ok::<(), reqwest::Error>(())
             };
-            let mut torrent_type = String::new();
-            // let mut torrent_sub_arr = Vec::<Value>::new();
-            for file in torrent_files_arr.iter() {
-                if !file["name"].is_string() {
-                    continue;
-                }
-                let file_as_str = file["name"].as_str().unwrap();
-                // if file_as_str.ends_with(".srt") || file_as_str.ends_with(".vtt") || file_as_str.ends_with(".ssa") || file_as_str.ends_with(".sub")  {
-                //     let path = file_as_str.to_string();
-                //     let language = match file_as_str.strip_prefix("Subs/") {
-                //         Some(file) => file.strip_suffix(".srt").unwrap(),
-                //         None => file_as_str.strip_suffix(".srt").unwrap(),
-                //     };
-                //     torrent_sub_arr.push(json!({
-                //         "path": format!("{}/{}", movie_content_dir, path),
-                //         "language": language.to_string()
-                //     }))
-                // }
-                if is_video_file(file_as_str) {
-                    let tab = file_as_str.trim().split('.');
-                    tab.last().unwrap().clone_into(&mut torrent_type);
-                    torrent_path.push_str(format!("/{}", file_as_str).as_str());
+            let task = tokio::spawn(async move {
+                match time::timeout(PRIORITIZE_TIMEOUT, read).await {
+                    Ok(Ok(())) => {}
+                    Ok(Err(err)) => {
+                        tracing::error!("Failed to prioritize torrent range {:#?}", err)
+                    }
+                    Err(_) => {
+                        tracing::warn!("Prioritizing a range of torrent {} timed out", torrent_id)
+                    }
                 }
+            });
+            // the playhead moved on, the read for where it was is dropped
+            if let Some(previous) = self
+                .read_aheads
+                .lock()
+                .unwrap()
+                .insert((torrent_id, file_index), task.abort_handle())
+            {
+                previous.abort();
             }
-            (None::<Vec<Value>>, torrent_type)
-            // if torrent_sub_arr.is_empty() {
-            //     (None::<Vec<Value>>, torrent_type)
-            // } else {
-            //     (Some(torrent_sub_arr), torrent_type)
-            // }
-        };
-        let torrent = FileInfo::new(torrent_id, torrent_path, torrent_subs, torrent_file_type);
-        Ok(torrent)
+            Ok(())
+        }
+        .boxed()
     }
 }
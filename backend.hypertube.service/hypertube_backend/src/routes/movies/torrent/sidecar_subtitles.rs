@@ -0,0 +1,109 @@
+use serde_json::Value;
+
+use super::{file_extension, is_subtitle_file, SubInfo, TorrentFile};
+
+// ISO 639-1 code, English name and the other spellings release groups use
+const LANGUAGES: [(&str, &str, &[&str]); 24] = [
+    ("en", "English", &["eng"]),
+    ("fr", "French", &["fre", "fra", "francais"]),
+    ("es", "Spanish", &["spa", "esp", "espanol", "castellano"]),
+    ("de", "German", &["ger", "deu", "deutsch"]),
+    ("it", "Italian", &["ita", "italiano"]),
+    ("pt", "Portuguese", &["por", "portugues", "brazilian"]),
+    ("nl", "Dutch", &["dut", "nld", "nederlands"]),
+    ("ru", "Russian", &["rus"]),
+    ("ar", "Arabic", &["ara"]),
+    ("zh", "Chinese", &["chi", "zho", "chs", "cht", "mandarin"]),
+    ("ja", "Japanese", &["jpn"]),
+    ("ko", "Korean", &["kor"]),
+    ("tr", "Turkish", &["tur"]),
+    ("pl", "Polish", &["pol"]),
+    ("sv", "Swedish", &["swe"]),
+    ("da", "Danish", &["dan"]),
+    ("fi", "Finnish", &["fin"]),
+    ("no", "Norwegian", &["nor", "nob"]),
+    ("el", "Greek", &["gre", "ell"]),
+    ("he", "Hebrew", &["heb"]),
+    ("hi", "Hindi", &["hin"]),
+    ("cs", "Czech", &["cze", "ces"]),
+    ("hu", "Hungarian", &["hun"]),
+    ("ro", "Romanian", &["rum", "ron"]),
+];
+pub const UNKNOWN_LANGUAGE: &str = "und";
+
+// ISO 639-1 code of a language name, 639-1 or 639-2 code
+pub fn language_code(word: &str) -> Option<&'static str> {
+    let word = word.to_ascii_lowercase();
+    LANGUAGES
+        .iter()
+        .find(|(code, name, aliases)| {
+            word == *code || word == name.to_ascii_lowercase() || aliases.contains(&word.as_str())
+        })
+        .map(|(code, _, _)| *code)
+}
+
+pub fn language_name(code: &str) -> &'static str {
+    LANGUAGES
+        .iter()
+        .find(|(language, _, _)| *language == code)
+        .map(|(_, name, _)| *name)
+        .unwrap_or("Unknown")
+}
+
+// Guesses the language from the file name first, then from the folders it is
+// in: `Subs/2_English.srt`, `Movie.2020.en.srt` and `Subs/French/1.srt` all
+// work. Two letter words only count when they stand alone next to the
+// extension, too many release names contain `it` or `no`.
+pub fn subtitle_language(name: &str) -> &'static str {
+    let mut segments = name.rsplit('/');
+    let file_name = segments.next().unwrap_or(name);
+    let stem = file_name
+        .rsplit_once('.')
+        .map(|(stem, _)| stem)
+        .unwrap_or(file_name);
+
+    let words: Vec<&str> = stem
+        .split(|c: char| !c.is_ascii_alphabetic())
+        .filter(|word| !word.is_empty())
+        .collect();
+    if let Some(last) = words.last() {
+        if let Some(code) = language_code(last) {
+            return code;
+        }
+    }
+    if let Some(code) = words
+        .iter()
+        .rev()
+        .filter(|word| word.len() > 2)
+        .find_map(|word| language_code(word))
+    {
+        return code;
+    }
+    segments
+        .flat_map(|folder| folder.split(|c: char| !c.is_ascii_alphabetic()))
+        .filter(|word| word.len() > 2)
+        .find_map(language_code)
+        .unwrap_or(UNKNOWN_LANGUAGE)
+}
+
+// The subtitle files shipped in the torrent, in the shape stored in
+// `movie_torrent.available_subs`
+pub fn sidecar_subtitles(folder: &str, files: &[TorrentFile]) -> Option<Vec<Value>> {
+    let subs: Vec<Value> = files
+        .iter()
+        .filter(|file| file.included && is_subtitle_file(file.name.as_str()))
+        .filter_map(|file| {
+            serde_json::to_value(SubInfo {
+                language: subtitle_language(file.name.as_str()).to_string(),
+                path: format!("{}/{}", folder, file.name),
+                format: file_extension(file.name.as_str()).unwrap_or_default(),
+            })
+            .ok()
+        })
+        .collect();
+    if subs.is_empty() {
+        None
+    } else {
+        Some(subs)
+    }
+}
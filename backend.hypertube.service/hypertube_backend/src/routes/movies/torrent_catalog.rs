@@ -0,0 +1,225 @@
+use serde_json::{json, Value};
+use sqlx::{PgPool, Row};
+
+use super::torrent::{Magnet, ReleaseName};
+use super::{MovieQuality, Source};
+
+// A torrent the movie catalogs list for a movie, the client never gets to
+// pick what the server downloads.
+#[derive(Debug, Clone)]
+pub struct CatalogTorrent {
+    pub info_hash: String,
+    pub name: Option<String>,
+    pub quality: Option<MovieQuality>,
+    // `None` when the catalog doesn't count them
+    pub seeds: Option<u32>,
+    pub peers: Option<u32>,
+    // `ReleaseName::rank` of the release, breaks ties between seeded torrents
+    pub rank: u32,
+}
+
+// enough seeds for the download to go at full speed
+const WELL_SEEDED: u32 = 5;
+
+impl CatalogTorrent {
+    // nobody seeds it, the download would never finish
+    pub fn is_dead(&self) -> bool {
+        self.seeds == Some(0)
+    }
+
+    fn health(&self) -> (u32, u32) {
+        (self.seeds.unwrap_or(0), self.peers.unwrap_or(0))
+    }
+
+    // anything seeded over a dead torrent, then the best release among the
+    // well seeded ones, then the healthiest
+    fn preference(&self) -> (bool, bool, u32, (u32, u32)) {
+        (
+            !self.is_dead(),
+            self.health().0 >= WELL_SEEDED,
+            self.rank,
+            self.health(),
+        )
+    }
+}
+
+#[derive(Debug)]
+pub enum CatalogError {
+    MovieNotFound,
+    QualityNotFound,
+    Upstream(String),
+    Database(sqlx::Error),
+}
+
+pub async fn find_catalog_torrent(
+    connection: &PgPool,
+    movie_id: &str,
+    source: &Source,
+    quality: MovieQuality,
+) -> Result<CatalogTorrent, CatalogError> {
+    let torrents = list_catalog_torrents(connection, movie_id, source).await?;
+    choose_torrent(&torrents, quality).ok_or(CatalogError::QualityNotFound)
+}
+
+pub async fn list_catalog_torrents(
+    connection: &PgPool,
+    movie_id: &str,
+    source: &Source,
+) -> Result<Vec<CatalogTorrent>, CatalogError> {
+    match source {
+        Source::YTS => list_yts_torrents(movie_id).await,
+        Source::MovieDb => list_movie_db_torrents(connection, movie_id).await,
+    }
+}
+
+// The best torrent of the requested quality. When nobody seeds it the
+// healthiest torrent of another quality is taken instead, as long as that
+// one has seeds.
+pub fn choose_torrent(
+    torrents: &[CatalogTorrent],
+    quality: MovieQuality,
+) -> Option<CatalogTorrent> {
+    let requested = torrents
+        .iter()
+        .filter(|torrent| torrent.quality == Some(quality))
+        .max_by_key(|torrent| torrent.preference())?;
+    if !requested.is_dead() {
+        return Some(requested.clone());
+    }
+    let fallback = torrents
+        .iter()
+        .filter(|torrent| torrent.health().0 > 0)
+        .max_by_key(|torrent| torrent.health());
+    Some(fallback.unwrap_or(requested).clone())
+}
+
+// Seeds and peers of the best torrent of every quality the movie has, in the
+// order of `MovieQuality::ALL`
+pub fn torrent_health(torrents: &[CatalogTorrent]) -> Value {
+    let qualities: Vec<Value> = MovieQuality::ALL
+        .iter()
+        .filter_map(|quality| {
+            let best = torrents
+                .iter()
+                .filter(|torrent| torrent.quality == Some(*quality))
+                .max_by_key(|torrent| torrent.health())?;
+            Some(json!({
+                "quality": quality.label(),
+                "seeds": best.seeds,
+                "peers": best.peers,
+                "available": !best.is_dead(),
+            }))
+        })
+        .collect();
+    json!(qualities)
+}
+
+pub fn yts_catalog_torrents(movie: &yts_api::Movie) -> Vec<CatalogTorrent> {
+    movie
+        .torrents
+        .iter()
+        .map(|torrent| CatalogTorrent {
+            info_hash: torrent.hash.clone(),
+            name: Some(movie.title_long.clone()),
+            quality: MovieQuality::from_label(torrent.quality.as_str()),
+            seeds: Some(torrent.seeds),
+            peers: Some(torrent.peers),
+            // yts names the source in `type`, `bluray` or `web`
+            rank: ReleaseName::parse(
+                format!("{} {} {}", movie.title_long, torrent.quality, torrent._type).as_str(),
+            )
+            .rank(),
+        })
+        .collect()
+}
+
+async fn list_yts_torrents(movie_id: &str) -> Result<Vec<CatalogTorrent>, CatalogError> {
+    let movie_id: u32 = movie_id.parse().map_err(|_| CatalogError::MovieNotFound)?;
+    let details = yts_api::MovieDetails::new(movie_id)
+        .execute()
+        .await
+        .map_err(|err| CatalogError::Upstream(err.to_string()))?;
+    Ok(yts_catalog_torrents(&details.movie))
+}
+
+// The torrents the search api found for a movie, as stored with its details.
+// Theater recordings are left out, they are never worth downloading.
+pub fn movie_db_catalog_torrents(torrents: &[Value], title: Option<String>) -> Vec<CatalogTorrent> {
+    torrents
+        .iter()
+        .filter_map(|torrent| {
+            let info_hash = movie_db_torrent_hash(torrent)?;
+            let release = torrent_name(torrent)
+                .map(|name| ReleaseName::parse(name.as_str()))
+                .unwrap_or_default();
+            if release.is_cam_rip() {
+                tracing::debug!("Skipping cam rip {:?}", torrent_name(torrent));
+                return None;
+            }
+            Some(CatalogTorrent {
+                info_hash,
+                name: torrent_name(torrent).or(title.clone()),
+                quality: movie_db_torrent_quality(torrent, &release),
+                seeds: torrent_count(torrent, &["seeds", "seeders"]),
+                peers: torrent_count(torrent, &["peers", "leechers"]),
+                rank: release.rank(),
+            })
+        })
+        .collect()
+}
+
+async fn list_movie_db_torrents(
+    connection: &PgPool,
+    movie_id: &str,
+) -> Result<Vec<CatalogTorrent>, CatalogError> {
+    let row = sqlx::query(
+        r#"
+            SELECT primary_title, torrents FROM imdb_movie_details WHERE id = $1
+        "#,
+    )
+    .bind(movie_id)
+    .fetch_optional(connection)
+    .await
+    .map_err(CatalogError::Database)?
+    .ok_or(CatalogError::MovieNotFound)?;
+
+    let title: Option<String> = row.get("primary_title");
+    let torrents: Vec<Value> = row
+        .get::<Option<Vec<Value>>, &str>("torrents")
+        .unwrap_or_default();
+    Ok(movie_db_catalog_torrents(&torrents, title))
+}
+
+// the search api sends counts as numbers or strings, under either name
+fn torrent_count(torrent: &Value, keys: &[&str]) -> Option<u32> {
+    keys.iter().find_map(|key| match &torrent[*key] {
+        Value::Number(count) => count.as_u64().map(|count| count as u32),
+        Value::String(count) => count.trim().parse().ok(),
+        _ => None,
+    })
+}
+
+fn torrent_name(torrent: &Value) -> Option<String> {
+    torrent["title"]
+        .as_str()
+        .or(torrent["name"].as_str())
+        .map(str::to_string)
+}
+
+// the search api either names the quality or only has it in the release name
+fn movie_db_torrent_quality(torrent: &Value, release: &ReleaseName) -> Option<MovieQuality> {
+    if let Some(torrent_quality) = torrent["quality"].as_str() {
+        return MovieQuality::from_label(torrent_quality);
+    }
+    release.quality()
+}
+
+fn movie_db_torrent_hash(torrent: &Value) -> Option<String> {
+    if let Some(hash) = torrent["hash"].as_str().or(torrent["info_hash"].as_str()) {
+        return Some(hash.to_string());
+    }
+    torrent["magnet"]
+        .as_str()
+        .and_then(|magnet| Magnet::parse(magnet).ok())
+        .map(|magnet| magnet.info_hash().to_string())
+}
@@ -0,0 +1,97 @@
+use futures_util::{future::BoxFuture, FutureExt};
+use serde_json::Value;
+use std::env;
+use std::sync::Arc;
+
+use super::TorznabIndexers;
+
+// Where the MovieDb torrents are searched. The torrents come back in the
+// shape the movie details store them in.
+pub trait TorrentIndexer: Send + Sync {
+    fn search_torrents<'a>(&'a self, query: &'a str) -> BoxFuture<'a, Result<Vec<Value>, String>>;
+}
+
+// The Torznab indexers when TORZNAB_INDEXERS lists any, the RapidAPI search
+// otherwise
+pub fn torrent_indexer_from_env() -> Arc<dyn TorrentIndexer> {
+    let torznab = TorznabIndexers::from_env();
+    if torznab.is_configured() {
+        return Arc::new(torznab);
+    }
+    tracing::info!("TORZNAB_INDEXERS not set, searching torrents on RapidAPI");
+    Arc::new(RapidApiIndexer::from_env())
+}
+
+// The RapidAPI torrent search, IMDB_SEARCH_TORRENT_HOST names the api
+pub struct RapidApiIndexer {
+    origin: String,
+    host: String,
+    token: String,
+    client: reqwest::Client,
+}
+
+impl RapidApiIndexer {
+    pub fn new(
+        origin: impl Into<String>,
+        host: impl Into<String>,
+        token: impl Into<String>,
+    ) -> Self {
+        Self {
+            origin: origin.into(),
+            host: host.into(),
+            token: token.into(),
+            client: reqwest::Client::new(),
+        }
+    }
+
+    pub fn from_env() -> Self {
+        let host = env::var("IMDB_SEARCH_TORRENT_HOST").unwrap_or_default();
+        let token = env::var("IMDB_SEARCH_TORRENT_TOKEN").unwrap_or_default();
+        Self::new(format!("https://{}", host), host, token)
+    }
+
+    async fn search(&self, query: &str) -> Result<Vec<Value>, String> {
+        if self.host.is_empty() {
+            return Err("No torrent indexer configured".to_string());
+        }
+        let response = match self
+            .client
+            .get(format!("{}/search/{}", self.origin, query))
+            .header("x-rapidapi-key", &self.token)
+            .header("x-rapidapi-host", &self.host)
+            .send()
+            .await
+        {
+            Ok(res) => {
+                tracing::info!("Got Movie db search response");
+                res
+            }
+            Err(err) => {
+                tracing::error!("MOVIE DB request error {:#?}", err);
+                return Err(err.to_string());
+            }
+        };
+
+        if response.status() == 429 {
+            return Err(String::from("you exceeded your daily QUOTA"));
+        }
+
+        let res = match response.json::<Value>().await {
+            Ok(val) => val,
+            Err(res_err) => {
+                tracing::error!("Parsing response body error {:#?}", res_err);
+                return Err("Failed to parse response body".to_string());
+            }
+        };
+        match res["data"].as_array() {
+            Some(torrents) => Ok(torrents.clone()),
+            None => Err(String::from("no movie data was provided")),
+        }
+    }
+}
+
+impl TorrentIndexer for RapidApiIndexer {
+    fn search_torrents<'a>(&'a self, query: &'a str) -> BoxFuture<'a, Result<Vec<Value>, String>> {
+        self.search(query).boxed()
+    }
+}
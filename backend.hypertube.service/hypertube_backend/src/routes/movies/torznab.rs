@@ -0,0 +1,389 @@
+use futures_util::future::{join_all, BoxFuture};
+use futures_util::FutureExt;
+use serde_json::{json, Value};
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::env;
+use std::time::Duration;
+use xmlparser::{ElementEnd, Token, Tokenizer};
+
+use super::torrent::{Magnet, ReleaseName};
+use super::torrent_indexer::TorrentIndexer;
+use super::MovieQuality;
+
+// the Torznab category of movies, subcategories included
+const MOVIES_CATEGORY: &str = "2000";
+const INDEXER_TIMEOUT: Duration = Duration::from_secs(20);
+
+// A release an indexer found. The same torrent listed by several indexers is
+// merged into one, see `merge_indexer_results`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct IndexerTorrent {
+    pub title: String,
+    pub info_hash: String,
+    pub magnet: Option<String>,
+    // `tt0133093`
+    pub imdb: Option<String>,
+    pub seeders: Option<u32>,
+    pub leechers: Option<u32>,
+    pub size: Option<u64>,
+    pub quality: Option<MovieQuality>,
+    pub indexers: Vec<String>,
+}
+
+impl IndexerTorrent {
+    // the shape the movie details store their torrents in
+    pub fn to_value(&self) -> Value {
+        json!({
+            "imdb": self.imdb,
+            "title": self.title,
+            "hash": self.info_hash,
+            "magnet": self.magnet,
+            "seeders": self.seeders,
+            "leechers": self.leechers,
+            "size": self.size,
+            "quality": self.quality.map(|quality| quality.label()),
+            "indexers": self.indexers,
+        })
+    }
+}
+
+#[derive(Default)]
+struct TorznabItem {
+    title: String,
+    link: Option<String>,
+    guid: Option<String>,
+    size: Option<String>,
+    enclosure: Option<String>,
+    attributes: HashMap<String, String>,
+}
+
+fn unescape(text: &str) -> String {
+    if !text.contains('&') {
+        return text.to_string();
+    }
+    let mut unescaped = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        unescaped.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let entity = rest.find(';').map(|end| (&rest[1..end], end));
+        let decoded = entity.and_then(|(entity, end)| {
+            let c = match entity {
+                "amp" => Some('&'),
+                "lt" => Some('<'),
+                "gt" => Some('>'),
+                "quot" => Some('"'),
+                "apos" => Some('\''),
+                _ => entity
+                    .strip_prefix("#x")
+                    .map(|hex| u32::from_str_radix(hex, 16))
+                    .or_else(|| entity.strip_prefix('#').map(str::parse::<u32>))
+                    .and_then(Result::ok)
+                    .and_then(char::from_u32),
+            };
+            c.map(|c| (c, end))
+        });
+        match decoded {
+            Some((c, end)) => {
+                unescaped.push(c);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                unescaped.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    unescaped.push_str(rest);
+    unescaped
+}
+
+// indexers send the imdb id with or without the `tt` and its leading zeros
+fn imdb_id(value: &str) -> Option<String> {
+    let digits = value.trim().trim_start_matches("tt");
+    if digits.is_empty() || !digits.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let id: u64 = digits.parse().ok()?;
+    (id > 0).then(|| format!("tt{:07}", id))
+}
+
+impl TorznabItem {
+    fn magnet(&self) -> Option<String> {
+        if let Some(magnet) = self.attributes.get("magneturl") {
+            return Some(magnet.clone());
+        }
+        [&self.link, &self.enclosure, &self.guid]
+            .into_iter()
+            .flatten()
+            .find(|link| link.starts_with("magnet:"))
+            .cloned()
+    }
+
+    // Items without an infohash or a magnet only link a .torrent file, they are
+    // skipped, the catalog downloads by hash
+    fn into_torrent(self, indexer: &str) -> Option<IndexerTorrent> {
+        let magnet = self.magnet();
+        let info_hash = match self.attributes.get("infohash") {
+            Some(hash) => Magnet::from_info_hash(hash, None, Vec::new()).ok(),
+            None => magnet
+                .as_deref()
+                .and_then(|magnet| Magnet::parse(magnet).ok()),
+        }?
+        .info_hash()
+        .to_string();
+        let count = |name: &str| {
+            self.attributes
+                .get(name)
+                .and_then(|count| count.trim().parse::<u32>().ok())
+        };
+        let seeders = count("seeders");
+        // torznab `peers` counts the seeders too
+        let leechers = count("leechers")
+            .or_else(|| count("peers").map(|peers| peers.saturating_sub(seeders.unwrap_or(0))));
+        let imdb = self
+            .attributes
+            .get("imdbid")
+            .or(self.attributes.get("imdb"))
+            .and_then(|id| imdb_id(id));
+        let size = self
+            .attributes
+            .get("size")
+            .or(self.size.as_ref())
+            .and_then(|size| size.trim().parse().ok());
+        Some(IndexerTorrent {
+            quality: ReleaseName::parse(self.title.as_str()).quality(),
+            title: self.title,
+            info_hash,
+            magnet,
+            imdb,
+            seeders,
+            leechers,
+            size,
+            indexers: vec![indexer.to_string()],
+        })
+    }
+}
+
+// The items of a Torznab search response, `indexer` names where they came from
+pub fn parse_torznab(xml: &str, indexer: &str) -> Result<Vec<IndexerTorrent>, String> {
+    let mut torrents = Vec::new();
+    let mut item: Option<TorznabItem> = None;
+    let mut element: Option<String> = None;
+    let mut attributes: Vec<(String, String)> = Vec::new();
+
+    for token in Tokenizer::from(xml) {
+        let token = token.map_err(|err| format!("Invalid torznab response: {}", err))?;
+        match token {
+            Token::ElementStart { local, .. } => {
+                element = Some(local.as_str().to_string());
+                attributes.clear();
+                if local.as_str() == "item" {
+                    item = Some(TorznabItem::default());
+                }
+            }
+            Token::Attribute { local, value, .. } => {
+                attributes.push((local.as_str().to_string(), unescape(value.as_str())));
+            }
+            Token::ElementEnd { end, .. } => {
+                let attribute = |name: &str| {
+                    attributes
+                        .iter()
+                        .find(|(key, _)| key == name)
+                        .map(|(_, value)| value.clone())
+                };
+                let opened = !matches!(end, ElementEnd::Close(..));
+                match element.as_deref().filter(|_| opened) {
+                    // `<error code="100" description="Invalid API Key"/>`
+                    Some("error") => {
+                        return Err(
+                            attribute("description").unwrap_or_else(|| "Indexer error".to_string())
+                        );
+                    }
+                    Some("enclosure") => {
+                        if let Some(item) = item.as_mut() {
+                            item.enclosure = attribute("url");
+                        }
+                    }
+                    // `<torznab:attr name="seeders" value="12"/>`
+                    Some("attr") => {
+                        if let (Some(item), Some(name), Some(value)) =
+                            (item.as_mut(), attribute("name"), attribute("value"))
+                        {
+                            item.attributes.insert(name.to_ascii_lowercase(), value);
+                        }
+                    }
+                    _ => {}
+                }
+                attributes.clear();
+                match end {
+                    ElementEnd::Open => {}
+                    ElementEnd::Close(_, local) => {
+                        element = None;
+                        if local.as_str() == "item" {
+                            if let Some(torrent) =
+                                item.take().and_then(|item| item.into_torrent(indexer))
+                            {
+                                torrents.push(torrent);
+                            }
+                        }
+                    }
+                    ElementEnd::Empty => element = None,
+                }
+            }
+            Token::Text { text } | Token::Cdata { text, .. } => {
+                let text = unescape(text.as_str().trim());
+                if let (Some(item), Some(element), false) =
+                    (item.as_mut(), element.as_deref(), text.is_empty())
+                {
+                    match element {
+                        "title" => item.title.push_str(text.as_str()),
+                        "link" => item.link = Some(text),
+                        "guid" => item.guid = Some(text),
+                        "size" => item.size = Some(text),
+                        _ => {}
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(torrents)
+}
+
+// One torrent per infohash, the best seeded listing wins and the others fill
+// in what it's missing. Sorted by seeders.
+pub fn merge_indexer_results(results: Vec<Vec<IndexerTorrent>>) -> Vec<IndexerTorrent> {
+    let mut merged: Vec<IndexerTorrent> = Vec::new();
+    for torrent in results.into_iter().flatten() {
+        let existing = merged
+            .iter_mut()
+            .find(|existing| existing.info_hash == torrent.info_hash);
+        let existing = match existing {
+            Some(existing) => existing,
+            None => {
+                merged.push(torrent);
+                continue;
+            }
+        };
+        let mut other = torrent;
+        if other.seeders > existing.seeders {
+            std::mem::swap(existing, &mut other);
+        }
+        existing.magnet = existing.magnet.take().or(other.magnet);
+        existing.imdb = existing.imdb.take().or(other.imdb);
+        existing.leechers = existing.leechers.max(other.leechers);
+        existing.size = existing.size.or(other.size);
+        existing.quality = existing.quality.or(other.quality);
+        for indexer in other.indexers {
+            if !existing.indexers.contains(&indexer) {
+                existing.indexers.push(indexer);
+            }
+        }
+    }
+    merged.sort_by_key(|torrent| Reverse(torrent.seeders));
+    merged
+}
+
+// The Torznab indexers (Jackett, Prowlarr...) the MovieDb torrents are found
+// on. They are all searched and their results merged.
+pub struct TorznabIndexers {
+    // api urls, with their `apikey` query
+    indexers: Vec<String>,
+    client: reqwest::Client,
+}
+
+impl TorznabIndexers {
+    pub fn new(indexers: Vec<String>) -> Self {
+        Self {
+            indexers,
+            client: reqwest::Client::builder()
+                .timeout(INDEXER_TIMEOUT)
+                .build()
+                .unwrap_or_default(),
+        }
+    }
+
+    // TORZNAB_INDEXERS is a comma separated list of api urls
+    pub fn from_env() -> Self {
+        let indexers = env::var("TORZNAB_INDEXERS")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|indexer| !indexer.is_empty())
+            .map(str::to_string)
+            .collect();
+        Self::new(indexers)
+    }
+
+    pub fn is_configured(&self) -> bool {
+        !self.indexers.is_empty()
+    }
+
+    // without the query, it holds the api key
+    fn indexer_name(indexer: &str) -> &str {
+        indexer.split('?').next().unwrap_or(indexer)
+    }
+
+    async fn search_indexer(
+        &self,
+        indexer: &str,
+        query: &str,
+    ) -> Result<Vec<IndexerTorrent>, String> {
+        let name = Self::indexer_name(indexer);
+        let response = self
+            .client
+            .get(indexer)
+            .query(&[("t", "search"), ("q", query), ("cat", MOVIES_CATEGORY)])
+            .send()
+            .await
+            .map_err(|err| format!("{}: {}", name, err))?;
+        if !response.status().is_success() {
+            return Err(format!("{}: responded {}", name, response.status()));
+        }
+        let body = response
+            .text()
+            .await
+            .map_err(|err| format!("{}: {}", name, err))?;
+        parse_torznab(body.as_str(), name).map_err(|err| format!("{}: {}", name, err))
+    }
+
+    // An indexer that fails is left out, the search only fails when they all do
+    pub async fn search(&self, query: &str) -> Result<Vec<IndexerTorrent>, String> {
+        if self.indexers.is_empty() {
+            return Err("No torrent indexer configured".to_string());
+        }
+        let responses = join_all(
+            self.indexers
+                .iter()
+                .map(|indexer| self.search_indexer(indexer, query)),
+        )
+        .await;
+        let mut results = Vec::new();
+        let mut errors = Vec::new();
+        for response in responses {
+            match response {
+                Ok(torrents) => results.push(torrents),
+                Err(err) => {
+                    tracing::warn!("Torznab search failed {}", err);
+                    errors.push(err);
+                }
+            }
+        }
+        if results.is_empty() {
+            return Err(format!("Torrent indexers failed: {}", errors.join(", ")));
+        }
+        Ok(merge_indexer_results(results))
+    }
+}
+
+impl TorrentIndexer for TorznabIndexers {
+    fn search_torrents<'a>(&'a self, query: &'a str) -> BoxFuture<'a, Result<Vec<Value>, String>> {
+        async move {
+            let torrents = self.search(query).await?;
+            Ok(torrents.iter().map(IndexerTorrent::to_value).collect())
+        }
+        .boxed()
+    }
+}
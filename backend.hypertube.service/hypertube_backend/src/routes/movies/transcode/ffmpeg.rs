@@ -0,0 +1,348 @@
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::process::Stdio;
+use tokio::process::{Child, Command};
+
+use super::hls::{self, HlsRendition};
+
+#[derive(Deserialize)]
+struct ProbeOutput {
+    #[serde(default)]
+    streams: Vec<ProbeStream>,
+    format: Option<ProbeFormat>,
+}
+
+#[derive(Deserialize)]
+struct ProbeStream {
+    index: u32,
+    codec_type: Option<String>,
+    codec_name: Option<String>,
+    profile: Option<String>,
+    pix_fmt: Option<String>,
+    width: Option<u32>,
+    height: Option<u32>,
+    channels: Option<u32>,
+    #[serde(default)]
+    tags: ProbeTags,
+}
+
+#[derive(Deserialize, Default)]
+struct ProbeTags {
+    language: Option<String>,
+    title: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ProbeFormat {
+    format_name: Option<String>,
+    duration: Option<String>,
+    bit_rate: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct SubtitleProbeOutput {
+    #[serde(default)]
+    streams: Vec<SubtitleProbeStream>,
+}
+
+#[derive(Deserialize)]
+struct SubtitleProbeStream {
+    index: u32,
+    codec_name: Option<String>,
+    #[serde(default)]
+    tags: ProbeTags,
+}
+
+// codecs that hold text, bitmap ones (PGS, VobSub) can't become WebVTT
+const TEXT_SUBTITLE_CODECS: [&str; 6] = ["subrip", "srt", "ass", "ssa", "webvtt", "mov_text"];
+
+#[derive(Debug, Clone)]
+pub struct SubtitleStream {
+    pub index: u32,
+    pub codec: String,
+    // ISO 639-2 tag from the container, when it has one
+    pub language: Option<String>,
+    pub title: Option<String>,
+}
+
+impl SubtitleStream {
+    pub fn is_text(&self) -> bool {
+        TEXT_SUBTITLE_CODECS.contains(&self.codec.as_str())
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AudioTrack {
+    pub index: u32,
+    pub codec: String,
+    pub channels: Option<u32>,
+    pub language: Option<String>,
+    pub title: Option<String>,
+}
+
+// What the browser needs to know to play a file, `container` is ffprobe's
+// format name, e.g. `matroska,webm` or `mov,mp4,m4a,3gp,3g2,mj2`.
+#[derive(Debug, Clone)]
+pub struct MediaProbe {
+    pub container: String,
+    pub duration: Option<f64>,
+    pub bit_rate: Option<i64>,
+    pub video_codec: String,
+    pub video_profile: Option<String>,
+    pub pixel_format: Option<String>,
+    pub width: u32,
+    pub height: u32,
+    pub audio_tracks: Vec<AudioTrack>,
+}
+
+#[derive(Debug, Clone)]
+pub struct VideoProbe {
+    pub duration: Option<f64>,
+    pub height: u32,
+    pub has_audio: bool,
+}
+
+pub async fn probe_media(input_path: &str) -> Result<MediaProbe, String> {
+    let output = Command::new("ffprobe")
+        .args(["-v", "error", "-print_format", "json"])
+        .args([
+            "-show_entries",
+            "stream=index,codec_type,codec_name,profile,pix_fmt,width,height,channels:stream_tags=language,title:format=format_name,duration,bit_rate",
+        ])
+        .arg(input_path)
+        .output()
+        .await
+        .map_err(|err| format!("Failed to run ffprobe: {}", err))?;
+    if !output.status.success() {
+        return Err(String::from_utf8_lossy(&output.stderr).trim().to_string());
+    }
+    let probe: ProbeOutput = serde_json::from_slice(&output.stdout)
+        .map_err(|err| format!("Invalid ffprobe output: {}", err))?;
+    let video = probe
+        .streams
+        .iter()
+        .find(|stream| stream.codec_type.as_deref() == Some("video") && stream.height.is_some())
+        .ok_or_else(|| format!("No video stream in {}", input_path))?;
+    let audio_tracks = probe
+        .streams
+        .iter()
+        .filter(|stream| stream.codec_type.as_deref() == Some("audio"))
+        .map(|stream| AudioTrack {
+            index: stream.index,
+            codec: stream.codec_name.clone().unwrap_or_default(),
+            channels: stream.channels,
+            language: stream.tags.language.clone(),
+            title: stream.tags.title.clone(),
+        })
+        .collect();
+    let format = probe.format.as_ref();
+    Ok(MediaProbe {
+        container: format
+            .and_then(|format| format.format_name.clone())
+            .unwrap_or_default(),
+        duration: format
+            .and_then(|format| format.duration.as_deref())
+            .and_then(|duration| duration.parse::<f64>().ok()),
+        bit_rate: format
+            .and_then(|format| format.bit_rate.as_deref())
+            .and_then(|bit_rate| bit_rate.parse::<i64>().ok()),
+        video_codec: video.codec_name.clone().unwrap_or_default(),
+        video_profile: video.profile.clone(),
+        pixel_format: video.pix_fmt.clone(),
+        width: video.width.unwrap_or_default(),
+        height: video.height.unwrap_or_default(),
+        audio_tracks,
+    })
+}
+
+pub async fn probe_video(input_path: &str) -> Result<VideoProbe, String> {
+    let probe = probe_media(input_path).await?;
+    Ok(VideoProbe {
+        duration: probe.duration,
+        height: probe.height,
+        has_audio: !probe.audio_tracks.is_empty(),
+    })
+}
+
+pub async fn probe_subtitle_streams(input_path: &str) -> Result<Vec<SubtitleStream>, String> {
+    let output = Command::new("ffprobe")
+        .args(["-v", "error", "-print_format", "json"])
+        .args(["-select_streams", "s"])
+        .args([
+            "-show_entries",
+            "stream=index,codec_name:stream_tags=language,title",
+        ])
+        .arg(input_path)
+        .output()
+        .await
+        .map_err(|err| format!("Failed to run ffprobe: {}", err))?;
+    if !output.status.success() {
+        return Err(String::from_utf8_lossy(&output.stderr).trim().to_string());
+    }
+    let probe: SubtitleProbeOutput = serde_json::from_slice(&output.stdout)
+        .map_err(|err| format!("Invalid ffprobe output: {}", err))?;
+    Ok(probe
+        .streams
+        .into_iter()
+        .map(|stream| SubtitleStream {
+            index: stream.index,
+            codec: stream.codec_name.unwrap_or_default(),
+            language: stream.tags.language,
+            title: stream.tags.title,
+        })
+        .collect())
+}
+
+pub async fn extract_subtitle_to_vtt(
+    input_path: &str,
+    stream_index: u32,
+    output_path: &Path,
+) -> Result<(), String> {
+    let output = Command::new("ffmpeg")
+        .args(["-y", "-v", "error", "-nostats"])
+        .arg("-i")
+        .arg(input_path)
+        .arg("-map")
+        .arg(format!("0:{}", stream_index))
+        .args(["-c:s", "webvtt", "-f", "webvtt"])
+        .arg(output_path)
+        .stdin(Stdio::null())
+        .output()
+        .await
+        .map_err(|err| format!("Failed to run ffmpeg: {}", err))?;
+    if !output.status.success() {
+        return Err(String::from_utf8_lossy(&output.stderr).trim().to_string());
+    }
+    Ok(())
+}
+
+// H.264/AAC mp4 every browser can play, progress is reported on stdout. The
+// fixed GOP puts a keyframe on every HLS segment boundary so the package can
+// copy the streams instead of encoding them again.
+pub fn spawn_mp4_transcode(input_path: &str, output_path: &str) -> Result<Child, String> {
+    let keyframe_interval = format!("expr:gte(t,n_forced*{})", hls::SEGMENT_SECONDS);
+    Command::new("ffmpeg")
+        .args(["-y", "-v", "error", "-nostats", "-progress", "pipe:1"])
+        .arg("-i")
+        .arg(input_path)
+        .args(["-c:v", "libx264", "-preset", "veryfast", "-crf", "23"])
+        .args(["-force_key_frames", keyframe_interval.as_str()])
+        .args(["-c:a", "aac", "-b:a", "160k"])
+        .args(["-movflags", "+faststart"])
+        .arg(output_path)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|err| format!("Failed to run ffmpeg: {}", err))
+}
+
+// Copies the video and first audio stream into a fragmented mp4 without
+// re-encoding, for sources whose codecs browsers already decode. Other audio
+// tracks and subtitles don't survive the container change.
+pub fn spawn_mp4_remux(input_path: &str, output_path: &str) -> Result<Child, String> {
+    Command::new("ffmpeg")
+        .args(["-y", "-v", "error", "-nostats", "-progress", "pipe:1"])
+        .arg("-i")
+        .arg(input_path)
+        .args(["-map", "0:v:0", "-map", "0:a:0?"])
+        .args(["-c", "copy"])
+        .args(["-movflags", "frag_keyframe+empty_moov+default_base_moof"])
+        .arg(output_path)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|err| format!("Failed to run ffmpeg: {}", err))
+}
+
+// Converts the source from `start` seconds and writes a fragmented mp4 the
+// player can start on while it grows. With an `input_path` ffmpeg seeks
+// through the container index, without one it reads stdin, which can't seek,
+// and decodes its way up to `start`. `copy_streams` remuxes instead of
+// re-encoding.
+pub fn spawn_live_transcode(
+    input_path: Option<&str>,
+    start: f64,
+    copy_streams: bool,
+    output_path: &Path,
+) -> Result<Child, String> {
+    let mut command = Command::new("ffmpeg");
+    command
+        .args(["-y", "-v", "error", "-nostats"])
+        .arg("-ss")
+        .arg(format!("{:.3}", start))
+        .arg("-i")
+        .arg(input_path.unwrap_or("pipe:0"))
+        .args(["-map", "0:v:0", "-map", "0:a:0?"]);
+    if copy_streams {
+        command.args(["-c", "copy"]);
+    } else {
+        command
+            .args(["-c:v", "libx264", "-preset", "veryfast", "-crf", "23"])
+            .args(["-c:a", "aac", "-b:a", "160k", "-ac", "2"]);
+    }
+    command
+        .args(["-movflags", "frag_keyframe+empty_moov+default_base_moof"])
+        .args(["-f", "mp4"])
+        .arg(output_path)
+        .stdin(if input_path.is_some() {
+            Stdio::null()
+        } else {
+            Stdio::piped()
+        })
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|err| format!("Failed to run ffmpeg: {}", err))
+}
+
+// Cuts the encoded mp4 into `{output_dir}/{rendition}/index.m3u8` plus the
+// master playlist pointing at it, the streams are copied as they are
+pub fn spawn_hls_package(
+    mp4_path: &str,
+    output_dir: &Path,
+    rendition: HlsRendition,
+    has_audio: bool,
+) -> Result<Child, String> {
+    let stream_map = if has_audio {
+        format!("v:0,a:0,name:{}", rendition.name())
+    } else {
+        format!("v:0,name:{}", rendition.name())
+    };
+    Command::new("ffmpeg")
+        .args(["-y", "-v", "error", "-nostats", "-progress", "pipe:1"])
+        .arg("-i")
+        .arg(mp4_path)
+        .args(["-map", "0:v:0", "-map", "0:a:0?"])
+        .args(["-c", "copy"])
+        .args(["-f", "hls", "-hls_playlist_type", "vod"])
+        .arg("-hls_time")
+        .arg(hls::SEGMENT_SECONDS.to_string())
+        .arg("-hls_segment_filename")
+        .arg(output_dir.join("%v").join("segment_%04d.ts"))
+        .args(["-master_pl_name", hls::MASTER_PLAYLIST])
+        .arg("-var_stream_map")
+        .arg(stream_map)
+        .arg(output_dir.join("%v").join(hls::VARIANT_PLAYLIST))
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|err| format!("Failed to run ffmpeg: {}", err))
+}
+
+// `-progress` prints key=value lines, `out_time_us` is the position reached so far
+pub fn parse_progress_line(line: &str) -> Option<f64> {
+    let (key, value) = line.trim().split_once('=')?;
+    match key {
+        "out_time_us" | "out_time_ms" => {
+            value.parse::<f64>().ok().map(|micros| micros / 1_000_000.0)
+        }
+        _ => None,
+    }
+}
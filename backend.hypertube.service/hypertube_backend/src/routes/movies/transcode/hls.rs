@@ -0,0 +1,110 @@
+use serde::Deserialize;
+
+pub const MASTER_PLAYLIST: &str = "master.m3u8";
+pub const VARIANT_PLAYLIST: &str = "index.m3u8";
+pub const SEGMENT_SECONDS: u32 = 6;
+
+// The rendition a movie is packaged in, the highest its height reaches. The path segment
+// accepts both the rendition name and the `MovieQuality` the rest of the API
+// uses, 3D releases are side by side 1080p and get the 1080p rendition.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum HlsRendition {
+    #[serde(rename = "480p")]
+    P480,
+    #[serde(rename = "720p", alias = "Q720p")]
+    P720,
+    #[serde(rename = "1080p", alias = "Q1080p", alias = "Q3D")]
+    P1080,
+    #[serde(rename = "2160p", alias = "Q2160p")]
+    P2160,
+}
+
+impl HlsRendition {
+    pub const ALL: [HlsRendition; 4] = [
+        HlsRendition::P480,
+        HlsRendition::P720,
+        HlsRendition::P1080,
+        HlsRendition::P2160,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            HlsRendition::P480 => "480p",
+            HlsRendition::P720 => "720p",
+            HlsRendition::P1080 => "1080p",
+            HlsRendition::P2160 => "2160p",
+        }
+    }
+
+    pub fn height(&self) -> u32 {
+        match self {
+            HlsRendition::P480 => 480,
+            HlsRendition::P720 => 720,
+            HlsRendition::P1080 => 1080,
+            HlsRendition::P2160 => 2160,
+        }
+    }
+
+    // no point in upscaling, a 720p source only gets 480p and 720p
+    pub fn for_source_height(height: u32) -> Vec<HlsRendition> {
+        let renditions: Vec<HlsRendition> = HlsRendition::ALL
+            .into_iter()
+            .filter(|rendition| rendition.height() <= height)
+            .collect();
+        if renditions.is_empty() {
+            vec![HlsRendition::P480]
+        } else {
+            renditions
+        }
+    }
+}
+
+// only playlists and segments ffmpeg wrote are ever served
+pub fn is_hls_file(name: &str) -> bool {
+    !name.contains('/')
+        && !name.contains("..")
+        && (name.ends_with(".m3u8") || name.ends_with(".ts"))
+}
+
+pub fn content_type(name: &str) -> &'static str {
+    if name.ends_with(".m3u8") {
+        "application/vnd.apple.mpegurl"
+    } else {
+        "video/mp2t"
+    }
+}
+
+// The playlist with `token` added to the query of every uri in it, players
+// that can't send cookies reach the variants and segments with it
+pub fn with_token(playlist: &str, token: &str) -> String {
+    let tokenized = |uri: &str| {
+        let separator = if uri.contains('?') { '&' } else { '?' };
+        format!("{}{}token={}", uri, separator, token)
+    };
+    let mut rewritten = String::with_capacity(playlist.len());
+    for line in playlist.split_inclusive('\n') {
+        let content = line.trim_end_matches(['\r', '\n']);
+        let ending = &line[content.len()..];
+        if content.trim().is_empty() {
+            rewritten.push_str(line);
+        } else if content.starts_with('#') {
+            // tags point at keys and media through `URI="..."`
+            match content.split_once("URI=\"").and_then(|(tag, rest)| {
+                rest.split_once('"')
+                    .map(|(uri, attributes)| (tag, uri, attributes))
+            }) {
+                Some((tag, uri, attributes)) => {
+                    rewritten.push_str(
+                        format!("{}URI=\"{}\"{}", tag, tokenized(uri), attributes).as_str(),
+                    );
+                    rewritten.push_str(ending);
+                }
+                None => rewritten.push_str(line),
+            }
+        } else {
+            rewritten.push_str(tokenized(content.trim()).as_str());
+            rewritten.push_str(ending);
+        }
+    }
+    rewritten
+}
@@ -0,0 +1,283 @@
+use actix_web::web::{Bytes, Data};
+use futures_util::{stream, Stream};
+use std::collections::HashMap;
+use std::env;
+use std::io;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::process::ChildStdin;
+use tokio::sync::Notify;
+use tokio::time::{self, Duration};
+
+use super::{ffmpeg, PlaybackMode};
+use crate::routes::movies::range_responder::ReadGate;
+
+const TAIL_POLL_INTERVAL: Duration = Duration::from_millis(250);
+const READ_CHUNK_BYTES: usize = 64 * 1024;
+const FEED_CHUNK_BYTES: u64 = 256 * 1024;
+// containers the stream handler serves without converting them
+const BROWSER_FILE_TYPES: [&str; 3] = ["mp4", "m4v", "webm"];
+
+// How a movie whose rendition isn't ready yet has to be converted while it
+// streams, `None` when the file plays as it is. Before the probe ran only the
+// extension is known.
+pub fn live_mode(mode: Option<PlaybackMode>, file_type: &str) -> Option<PlaybackMode> {
+    match mode {
+        Some(PlaybackMode::DirectPlay) => None,
+        Some(mode) => Some(mode),
+        None if BROWSER_FILE_TYPES.contains(&file_type.to_ascii_lowercase().as_str()) => None,
+        None => Some(PlaybackMode::Transcode),
+    }
+}
+
+// The file a live session converts, `gate` holds ffmpeg back until the pieces
+// it reads next are downloaded.
+pub struct LiveSource {
+    pub job_id: String,
+    pub input_path: String,
+    pub length: u64,
+    pub mode: PlaybackMode,
+    pub gate: Option<Arc<dyn ReadGate>>,
+}
+
+struct LiveSession {
+    output_path: PathBuf,
+    viewers: Mutex<usize>,
+    finished: AtomicBool,
+    stop: Notify,
+}
+
+// ffmpeg runs once per movie and start position, every viewer tails the
+// fragmented mp4 it writes. The session is torn down with its last viewer.
+pub struct LiveTranscodes {
+    output_root: PathBuf,
+    sessions: Mutex<HashMap<String, Arc<LiveSession>>>,
+}
+
+impl LiveTranscodes {
+    pub fn new(output_root: impl Into<PathBuf>) -> Self {
+        Self {
+            output_root: output_root.into(),
+            sessions: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // LIVE_TRANSCODE_PATH is optional
+    pub fn from_env() -> Self {
+        let output_root = env::var("LIVE_TRANSCODE_PATH")
+            .map(PathBuf::from)
+            .unwrap_or_else(|_| env::temp_dir().join("hypertube_live"));
+        Self::new(output_root)
+    }
+
+    // Joins the session converting `source` from `start` seconds, starting
+    // it when nobody watches from there yet
+    pub async fn join(
+        live: &Data<LiveTranscodes>,
+        source: LiveSource,
+        start: f64,
+    ) -> Result<LiveViewer, String> {
+        // players seek to fractions of a second, viewers a second apart share
+        let start = start.max(0.0).floor();
+        let key = format!("{}@{}", source.job_id, start);
+        if let Some(session) = live.sessions.lock().unwrap().get(&key) {
+            *session.viewers.lock().unwrap() += 1;
+            return Ok(LiveViewer {
+                live: live.clone(),
+                key,
+                session: session.clone(),
+            });
+        }
+
+        tokio::fs::create_dir_all(&live.output_root)
+            .await
+            .map_err(|err| err.to_string())?;
+        let output_path = live
+            .output_root
+            .join(format!("{}_{}.mp4", source.job_id, start));
+        // a file that is all on disk is read by ffmpeg itself, so seeking
+        // uses its index instead of decoding everything before `start`
+        let on_disk = match &source.gate {
+            Some(gate) => gate
+                .ready(0..source.length)
+                .await
+                .map(|available_end| available_end >= source.length)
+                .unwrap_or(false),
+            None => true,
+        };
+        let mut child = ffmpeg::spawn_live_transcode(
+            Some(source.input_path.as_str()).filter(|_| on_disk),
+            start,
+            source.mode == PlaybackMode::Remux,
+            output_path.as_path(),
+        )?;
+        let feeder = match child.stdin.take() {
+            Some(stdin) => Some(tokio::spawn(feed_source(
+                source.input_path,
+                source.length,
+                source.gate,
+                stdin,
+            ))),
+            None if on_disk => None,
+            None => return Err("ffmpeg has no stdin".to_string()),
+        };
+        let session = Arc::new(LiveSession {
+            output_path,
+            viewers: Mutex::new(1),
+            finished: AtomicBool::new(false),
+            stop: Notify::new(),
+        });
+
+        let driver_session = session.clone();
+        tokio::spawn(async move {
+            let stopped = tokio::select! {
+                status = child.wait() => {
+                    match status {
+                        Ok(status) if status.success() => {}
+                        Ok(status) => tracing::warn!("Live transcode exited with {}", status),
+                        Err(err) => tracing::error!("Live transcode failed {}", err),
+                    }
+                    false
+                }
+                _ = driver_session.stop.notified() => {
+                    let _ = child.kill().await;
+                    true
+                }
+            };
+            if let Some(feeder) = feeder {
+                feeder.abort();
+            }
+            driver_session.finished.store(true, Ordering::SeqCst);
+            // a finished session is still read by the viewers it has
+            if !stopped {
+                driver_session.stop.notified().await;
+            }
+            let _ = tokio::fs::remove_file(&driver_session.output_path).await;
+        });
+
+        let mut sessions = live.sessions.lock().unwrap();
+        // another viewer raced us here, theirs wins and ours is stopped
+        if let Some(existing) = sessions.get(&key) {
+            session.stop.notify_one();
+            *existing.viewers.lock().unwrap() += 1;
+            return Ok(LiveViewer {
+                live: live.clone(),
+                key,
+                session: existing.clone(),
+            });
+        }
+        sessions.insert(key.clone(), session.clone());
+        Ok(LiveViewer {
+            live: live.clone(),
+            key,
+            session,
+        })
+    }
+}
+
+// Copies the source into ffmpeg as fast as the torrent delivers it, for a
+// file still downloading
+async fn feed_source(
+    input_path: String,
+    length: u64,
+    gate: Option<Arc<dyn ReadGate>>,
+    mut stdin: ChildStdin,
+) -> io::Result<()> {
+    let mut file = File::open(input_path.as_str()).await?;
+    let mut buffer = vec![0; FEED_CHUNK_BYTES as usize];
+    let mut position = 0;
+    while position < length {
+        let available_end = match &gate {
+            Some(gate) => gate
+                .ready(position..length)
+                .await
+                .map_err(|err| io::Error::other(format!("{:?}", err)))?,
+            None => length,
+        };
+        let chunk =
+            std::cmp::min(available_end.saturating_sub(position), FEED_CHUNK_BYTES) as usize;
+        file.read_exact(&mut buffer[..chunk]).await?;
+        stdin.write_all(&buffer[..chunk]).await?;
+        position += chunk as u64;
+    }
+    stdin.shutdown().await
+}
+
+pub struct LiveViewer {
+    live: Data<LiveTranscodes>,
+    key: String,
+    session: Arc<LiveSession>,
+}
+
+impl LiveViewer {
+    // false when ffmpeg gave up before writing anything, the source isn't
+    // something it can read
+    pub async fn wait_for_output(&self) -> bool {
+        loop {
+            let finished = self.session.finished.load(Ordering::SeqCst);
+            let written = tokio::fs::metadata(&self.session.output_path)
+                .await
+                .map(|metadata| metadata.len())
+                .unwrap_or(0);
+            if written > 0 {
+                return true;
+            }
+            if finished {
+                return false;
+            }
+            time::sleep(TAIL_POLL_INTERVAL).await;
+        }
+    }
+
+    // The converted movie as ffmpeg writes it, ends once ffmpeg is done and
+    // everything it wrote was sent
+    pub fn into_stream(self) -> impl Stream<Item = Result<Bytes, io::Error>> {
+        stream::unfold((self, None::<File>), |(viewer, mut file)| async move {
+            let mut buffer = vec![0; READ_CHUNK_BYTES];
+            loop {
+                // read the flag first, the data written before it was set is
+                // then on disk for the last read
+                let finished = viewer.session.finished.load(Ordering::SeqCst);
+                if file.is_none() {
+                    file = File::open(&viewer.session.output_path).await.ok();
+                }
+                if let Some(reader) = file.as_mut() {
+                    match reader.read(&mut buffer).await {
+                        Ok(0) => {}
+                        Ok(read) => {
+                            buffer.truncate(read);
+                            return Some((Ok(Bytes::from(buffer)), (viewer, file)));
+                        }
+                        Err(err) => return Some((Err(err), (viewer, None))),
+                    }
+                }
+                if finished {
+                    return None;
+                }
+                time::sleep(TAIL_POLL_INTERVAL).await;
+            }
+        })
+    }
+}
+
+impl Drop for LiveViewer {
+    fn drop(&mut self) {
+        let mut sessions = self.live.sessions.lock().unwrap();
+        let mut viewers = self.session.viewers.lock().unwrap();
+        *viewers = viewers.saturating_sub(1);
+        if *viewers == 0 {
+            // a newer session may already sit under the key
+            if sessions
+                .get(&self.key)
+                .map(|session| Arc::ptr_eq(session, &self.session))
+                .unwrap_or(false)
+            {
+                sessions.remove(&self.key);
+            }
+            self.session.stop.notify_one();
+        }
+    }
+}
@@ -0,0 +1,134 @@
+use serde::Serialize;
+use serde_json::{json, Value};
+use sqlx::{PgPool, Row};
+
+use super::ffmpeg::{self, MediaProbe};
+use crate::routes::movies::Source;
+
+// containers the stream handler can hand to a <video> element as they are
+const MP4_FILE_TYPES: [&str; 2] = ["mp4", "m4v"];
+const WEBM_FILE_TYPES: [&str; 1] = ["webm"];
+// codecs every major browser decodes, per container
+const MP4_VIDEO_CODECS: [&str; 3] = ["h264", "av1", "vp9"];
+const MP4_AUDIO_CODECS: [&str; 3] = ["aac", "mp3", "opus"];
+const WEBM_VIDEO_CODECS: [&str; 3] = ["vp8", "vp9", "av1"];
+const WEBM_AUDIO_CODECS: [&str; 2] = ["opus", "vorbis"];
+// 10 bit and 4:2:2/4:4:4 H.264 only plays in some browsers
+const BROWSER_PIXEL_FORMATS: [&str; 2] = ["yuv420p", "yuvj420p"];
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, sqlx::Type)]
+#[sqlx(type_name = "playback_mode", rename_all = "SCREAMING_SNAKE_CASE")]
+#[serde(rename_all = "snake_case")]
+pub enum PlaybackMode {
+    // the downloaded file is served as is
+    DirectPlay,
+    // the streams are fine, only the container has to change
+    Remux,
+    Transcode,
+}
+
+fn codecs_supported(probe: &MediaProbe, video_codecs: &[&str], audio_codecs: &[&str]) -> bool {
+    let pixel_format_supported = probe
+        .pixel_format
+        .as_deref()
+        .map(|format| BROWSER_PIXEL_FORMATS.contains(&format))
+        .unwrap_or(true);
+    // the first track is the one the browser plays
+    let audio_supported = probe
+        .audio_tracks
+        .first()
+        .map(|track| audio_codecs.contains(&track.codec.as_str()))
+        .unwrap_or(true);
+    video_codecs.contains(&probe.video_codec.as_str()) && pixel_format_supported && audio_supported
+}
+
+// `file_type` is the extension of the downloaded file, ffprobe reports mkv
+// and webm files alike.
+pub fn playback_mode(probe: &MediaProbe, file_type: &str) -> PlaybackMode {
+    let file_type = file_type.to_ascii_lowercase();
+    if MP4_FILE_TYPES.contains(&file_type.as_str())
+        && codecs_supported(probe, &MP4_VIDEO_CODECS, &MP4_AUDIO_CODECS)
+    {
+        return PlaybackMode::DirectPlay;
+    }
+    if WEBM_FILE_TYPES.contains(&file_type.as_str())
+        && codecs_supported(probe, &WEBM_VIDEO_CODECS, &WEBM_AUDIO_CODECS)
+    {
+        return PlaybackMode::DirectPlay;
+    }
+    if codecs_supported(probe, &MP4_VIDEO_CODECS, &MP4_AUDIO_CODECS) {
+        PlaybackMode::Remux
+    } else {
+        PlaybackMode::Transcode
+    }
+}
+
+// Probes the downloaded file and stores what was found on the movie
+pub async fn probe_and_store(
+    connection: &PgPool,
+    movie_id: String,
+    source: Source,
+    input_path: &str,
+    file_type: &str,
+) -> Result<PlaybackMode, String> {
+    let probe = ffmpeg::probe_media(input_path).await?;
+    let mode = playback_mode(&probe, file_type);
+    let audio_tracks = serde_json::to_value(&probe.audio_tracks).map_err(|err| err.to_string())?;
+    sqlx::query(
+        r#"
+            UPDATE movie_torrent SET container = $3, video_codec = $4, audio_codec = $5,
+                duration_seconds = $6, width = $7, height = $8, bit_rate = $9,
+                audio_tracks = $10, playback_mode = $11, probed_at = NOW()
+            WHERE movie_id = $1 AND movie_source = $2
+        "#,
+    )
+    .bind(movie_id)
+    .bind(source as Source)
+    .bind(probe.container.as_str())
+    .bind(probe.video_codec.as_str())
+    .bind(probe.audio_tracks.first().map(|track| track.codec.clone()))
+    .bind(probe.duration)
+    .bind(probe.width as i32)
+    .bind(probe.height as i32)
+    .bind(probe.bit_rate)
+    .bind(audio_tracks)
+    .bind(mode)
+    .execute(connection)
+    .await
+    .map_err(|err| err.to_string())?;
+    Ok(mode)
+}
+
+// The probe of a downloaded movie as shown in the movie info, `None` until
+// the movie is downloaded and probed.
+pub async fn get_media_info(
+    connection: &PgPool,
+    movie_id: String,
+    source: Source,
+) -> Result<Option<Value>, sqlx::Error> {
+    let row = sqlx::query(
+        r#"
+            SELECT container, video_codec, audio_codec, duration_seconds, width, height,
+                bit_rate, audio_tracks, playback_mode
+            FROM movie_torrent
+            WHERE movie_id = $1 AND movie_source = $2 AND probed_at IS NOT NULL
+        "#,
+    )
+    .bind(movie_id)
+    .bind(source as Source)
+    .fetch_optional(connection)
+    .await?;
+    Ok(row.map(|row| {
+        json!({
+            "container": row.get::<Option<String>, &str>("container"),
+            "video_codec": row.get::<Option<String>, &str>("video_codec"),
+            "audio_codec": row.get::<Option<String>, &str>("audio_codec"),
+            "duration": row.get::<Option<f64>, &str>("duration_seconds"),
+            "width": row.get::<Option<i32>, &str>("width"),
+            "height": row.get::<Option<i32>, &str>("height"),
+            "bit_rate": row.get::<Option<i64>, &str>("bit_rate"),
+            "audio_tracks": row.get::<Option<Value>, &str>("audio_tracks"),
+            "playback_mode": row.get::<Option<PlaybackMode>, &str>("playback_mode"),
+        })
+    }))
+}
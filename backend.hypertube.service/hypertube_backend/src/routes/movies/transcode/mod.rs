@@ -0,0 +1,23 @@
+pub mod ffmpeg;
+pub mod hls;
+pub mod live;
+pub mod media_probe;
+pub mod queue;
+pub mod subtitles;
+
+pub use hls::HlsRendition;
+pub use live::LiveTranscodes;
+pub use media_probe::PlaybackMode;
+pub use queue::*;
+
+use serde::Serialize;
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, sqlx::Type)]
+#[sqlx(type_name = "transcode_job_status", rename_all = "UPPERCASE")]
+#[serde(rename_all = "lowercase")]
+pub enum TranscodeJobStatus {
+    Queued,
+    Running,
+    Done,
+    Failed,
+}
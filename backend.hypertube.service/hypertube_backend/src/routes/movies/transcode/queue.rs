@@ -0,0 +1,485 @@
+use actix_web::web::Data;
+use chrono::Utc;
+use futures_util::future::join_all;
+use sqlx::{PgPool, Row};
+use std::env;
+use std::path::{Path, PathBuf};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, BufReader};
+use tokio::process::Child;
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+use tokio::time::{self, Duration};
+use uuid::Uuid;
+
+use super::{ffmpeg, media_probe, subtitles, HlsRendition, PlaybackMode};
+use crate::routes::movies::torrent::{file_extension, TorrentClient};
+use crate::routes::movies::{CronJobScheduler, DownloadEventHub, MovieEvent, Source};
+
+const DEFAULT_WORKERS: usize = 2;
+const IDLE_POLL_INTERVAL: Duration = Duration::from_secs(10);
+// a job whose torrent is still downloading is put back for this long
+const DOWNLOAD_RETRY_SECS: i64 = 15;
+const RENDITION_FILE_NAME: &str = "video.mp4";
+const HLS_DIR_NAME: &str = "hls";
+
+struct ClaimedJob {
+    id: Uuid,
+    movie_id: String,
+    source: Source,
+    torrent_id: i32,
+    input_path: String,
+    output_dir: String,
+}
+
+// Transcode jobs live in `transcode_jobs`, a fixed pool of workers picks them
+// up so ffmpeg never runs on the request path and a restart loses nothing.
+pub struct TranscodeQueue {
+    output_root: PathBuf,
+    workers: usize,
+    wake_up: Notify,
+}
+
+impl TranscodeQueue {
+    pub fn new(output_root: impl Into<PathBuf>, workers: usize) -> Self {
+        Self {
+            output_root: output_root.into(),
+            workers: workers.max(1),
+            wake_up: Notify::new(),
+        }
+    }
+
+    // TRANSCODE_OUTPUT_PATH and TRANSCODE_WORKERS are optional
+    pub fn from_env() -> Self {
+        let output_root = match env::var("TRANSCODE_OUTPUT_PATH") {
+            Ok(path) => PathBuf::from(path),
+            Err(_) => env::current_dir()
+                .ok()
+                .and_then(|dir| dir.parent().map(|parent| parent.join("Downloads")))
+                .unwrap_or_else(env::temp_dir)
+                .join("transcoded"),
+        };
+        let workers = env::var("TRANSCODE_WORKERS")
+            .ok()
+            .and_then(|value| value.parse::<usize>().ok())
+            .unwrap_or(DEFAULT_WORKERS);
+        Self::new(output_root, workers)
+    }
+
+    pub fn output_dir(&self, movie_id: String, source: Source) -> PathBuf {
+        self.output_root
+            .join(CronJobScheduler::build_job_id(movie_id, source))
+    }
+
+    pub async fn enqueue(
+        &self,
+        connection: &PgPool,
+        movie_id: String,
+        source: Source,
+        torrent_id: i32,
+        input_path: String,
+    ) -> Result<Uuid, sqlx::Error> {
+        let job_id = Uuid::new_v4();
+        let output_dir = self.output_dir(movie_id.clone(), source.clone());
+        sqlx::query(
+            r#"
+                INSERT INTO transcode_jobs (id, movie_source, movie_id, torrent_id, input_path, output_dir, created_at, updated_at)
+                VALUES ($1, $2, $3, $4, $5, $6, $7, $7)
+            "#,
+        )
+        .bind(job_id)
+        .bind(source as Source)
+        .bind(movie_id)
+        .bind(torrent_id)
+        .bind(input_path)
+        .bind(output_dir.display().to_string())
+        .bind(Utc::now())
+        .execute(connection)
+        .await?;
+        self.wake_up.notify_one();
+        Ok(job_id)
+    }
+
+    pub fn start(
+        queue: Data<TranscodeQueue>,
+        connection: Data<PgPool>,
+        torrent_client: Data<dyn TorrentClient>,
+        event_hub: Data<DownloadEventHub>,
+    ) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            // whatever was running when the server went down starts over
+            if let Err(err) = sqlx::query(
+                r#"
+                    UPDATE transcode_jobs SET status = 'QUEUED', updated_at = NOW() WHERE status = 'RUNNING'
+                "#,
+            )
+            .execute(connection.as_ref())
+            .await
+            {
+                tracing::error!("Failed to requeue interrupted transcode jobs {}", err);
+            }
+            let workers = (0..queue.workers).map(|worker| {
+                tokio::spawn(run_worker(
+                    worker,
+                    queue.clone(),
+                    connection.clone(),
+                    torrent_client.clone(),
+                    event_hub.clone(),
+                ))
+            });
+            join_all(workers).await;
+        })
+    }
+}
+
+async fn run_worker(
+    worker: usize,
+    queue: Data<TranscodeQueue>,
+    connection: Data<PgPool>,
+    torrent_client: Data<dyn TorrentClient>,
+    event_hub: Data<DownloadEventHub>,
+) {
+    loop {
+        let job = match claim_job(connection.as_ref()).await {
+            Ok(Some(job)) => job,
+            Ok(None) => {
+                let _ = time::timeout(IDLE_POLL_INTERVAL, queue.wake_up.notified()).await;
+                continue;
+            }
+            Err(err) => {
+                tracing::error!("Transcode worker {} failed to claim a job {}", worker, err);
+                time::sleep(IDLE_POLL_INTERVAL).await;
+                continue;
+            }
+        };
+        tracing::info!("Transcode worker {} picked up job {}", worker, job.id);
+        run_job(
+            job,
+            connection.as_ref(),
+            torrent_client.get_ref(),
+            event_hub.get_ref(),
+        )
+        .await;
+    }
+}
+
+async fn claim_job(connection: &PgPool) -> Result<Option<ClaimedJob>, sqlx::Error> {
+    let row = sqlx::query(
+        r#"
+            UPDATE transcode_jobs SET status = 'RUNNING', updated_at = NOW()
+            WHERE id = (
+                SELECT id FROM transcode_jobs
+                WHERE status = 'QUEUED' AND run_after <= NOW()
+                ORDER BY created_at
+                FOR UPDATE SKIP LOCKED
+                LIMIT 1
+            )
+            RETURNING id, movie_id, movie_source, torrent_id, input_path, output_dir
+        "#,
+    )
+    .fetch_optional(connection)
+    .await?;
+    Ok(row.map(|row| ClaimedJob {
+        id: row.get("id"),
+        movie_id: row.get("movie_id"),
+        source: row.get("movie_source"),
+        torrent_id: row.get("torrent_id"),
+        input_path: row.get("input_path"),
+        output_dir: row.get("output_dir"),
+    }))
+}
+
+async fn run_job(
+    job: ClaimedJob,
+    connection: &PgPool,
+    torrent_client: &dyn TorrentClient,
+    event_hub: &DownloadEventHub,
+) {
+    let event_id = CronJobScheduler::build_job_id(job.movie_id.clone(), job.source.clone());
+
+    // ffmpeg needs the whole file, wait for the torrent to finish first
+    match torrent_client.torrent_status(job.torrent_id).await {
+        Ok(status) if status.finished => {}
+        Ok(_) => {
+            if let Err(err) = sqlx::query(
+                r#"
+                    UPDATE transcode_jobs SET status = 'QUEUED', run_after = $2, updated_at = NOW() WHERE id = $1
+                "#,
+            )
+            .bind(job.id)
+            .bind(Utc::now() + chrono::Duration::seconds(DOWNLOAD_RETRY_SECS))
+            .execute(connection)
+            .await
+            {
+                tracing::error!("Failed to requeue transcode job {} {}", job.id, err);
+            }
+            return;
+        }
+        Err(err) => {
+            fail_job(connection, job.id, err.as_str()).await;
+            event_hub.publish(&event_id, MovieEvent::Error { message: err });
+            return;
+        }
+    }
+
+    // the browser can't read the subtitle tracks inside the container
+    match subtitles::extract_embedded_subtitles(
+        connection,
+        job.movie_id.clone(),
+        job.source.clone(),
+        job.input_path.as_str(),
+        Path::new(job.output_dir.as_str()),
+    )
+    .await
+    {
+        Ok(count) if count > 0 => tracing::info!("Extracted {} subtitle tracks", count),
+        Ok(_) => {}
+        Err(err) => tracing::warn!("Failed to extract subtitles of job {}: {}", job.id, err),
+    }
+
+    // files browsers already play are streamed as they are
+    let mode = match media_probe::probe_and_store(
+        connection,
+        job.movie_id.clone(),
+        job.source.clone(),
+        job.input_path.as_str(),
+        file_extension(job.input_path.as_str())
+            .unwrap_or_default()
+            .as_str(),
+    )
+    .await
+    {
+        Ok(PlaybackMode::DirectPlay) => {
+            tracing::info!("Job {} plays directly, nothing to transcode", job.id);
+            finish_without_output(connection, job.id).await;
+            event_hub.publish(&event_id, MovieEvent::TranscodeFinished);
+            return;
+        }
+        Ok(mode) => mode,
+        Err(err) => {
+            tracing::warn!(
+                "Failed to probe job {}, transcoding anyway: {}",
+                job.id,
+                err
+            );
+            PlaybackMode::Transcode
+        }
+    };
+
+    match transcode(&job, mode, connection).await {
+        Ok(renditions) => {
+            let finished = sqlx::query(
+                r#"
+                    UPDATE transcode_jobs SET status = 'DONE', progress = 100, output_path = $2, updated_at = NOW()
+                    WHERE id = $1
+                "#,
+            )
+            .bind(job.id)
+            .bind(renditions.mp4_path.clone())
+            .execute(connection)
+            .await;
+            match finished {
+                // the movie got deleted while it was being transcoded
+                Ok(result) if result.rows_affected() == 0 => {
+                    let _ = tokio::fs::remove_dir_all(job.output_dir.as_str()).await;
+                    return;
+                }
+                Ok(_) => {}
+                Err(err) => {
+                    tracing::error!("Failed to finish transcode job {} {}", job.id, err);
+                    return;
+                }
+            }
+            if let Err(err) = sqlx::query(
+                r#"
+                    UPDATE movie_torrent SET transcoded_path = $1, hls_path = $2
+                    WHERE movie_id = $3 AND movie_source = $4
+                "#,
+            )
+            .bind(renditions.mp4_path)
+            .bind(renditions.hls_dir)
+            .bind(job.movie_id.clone())
+            .bind(job.source.clone() as Source)
+            .execute(connection)
+            .await
+            {
+                tracing::error!("Failed to point movie at its rendition {}", err);
+                return;
+            }
+            tracing::info!("Transcode job {} finished", job.id);
+            event_hub.publish(&event_id, MovieEvent::TranscodeFinished);
+        }
+        Err(err) => {
+            tracing::error!("Transcode job {} failed {}", job.id, err);
+            fail_job(connection, job.id, err.as_str()).await;
+            event_hub.publish(&event_id, MovieEvent::Error { message: err });
+        }
+    }
+}
+
+struct Renditions {
+    mp4_path: String,
+    // remuxed sources only get the mp4
+    hls_dir: Option<String>,
+}
+
+async fn transcode(
+    job: &ClaimedJob,
+    mode: PlaybackMode,
+    connection: &PgPool,
+) -> Result<Renditions, String> {
+    let output_dir = PathBuf::from(job.output_dir.as_str());
+    tokio::fs::create_dir_all(&output_dir)
+        .await
+        .map_err(|err| err.to_string())?;
+    let mp4_path = output_dir.join(RENDITION_FILE_NAME).display().to_string();
+    let probe = ffmpeg::probe_video(job.input_path.as_str()).await?;
+
+    // a remux takes seconds, re-encoding is kept for what browsers can't decode
+    if mode == PlaybackMode::Remux {
+        tracing::info!("Job {} only needs a remux", job.id);
+        let child = ffmpeg::spawn_mp4_remux(job.input_path.as_str(), mp4_path.as_str())?;
+        run_ffmpeg(child, job.id, connection, probe.duration, 0.0, 100.0).await?;
+        return Ok(Renditions {
+            mp4_path,
+            hls_dir: None,
+        });
+    }
+
+    let hls_dir = output_dir.join(HLS_DIR_NAME);
+    tokio::fs::create_dir_all(&hls_dir)
+        .await
+        .map_err(|err| err.to_string())?;
+    let child = ffmpeg::spawn_mp4_transcode(job.input_path.as_str(), mp4_path.as_str())?;
+    run_ffmpeg(child, job.id, connection, probe.duration, 0.0, 90.0).await?;
+
+    // the package copies the mp4 streams, it holds the one rendition the
+    // source was encoded at
+    let rendition = HlsRendition::for_source_height(probe.height)
+        .last()
+        .copied()
+        .unwrap_or(HlsRendition::P480);
+    let child = ffmpeg::spawn_hls_package(
+        mp4_path.as_str(),
+        hls_dir.as_path(),
+        rendition,
+        probe.has_audio,
+    )?;
+    run_ffmpeg(child, job.id, connection, probe.duration, 90.0, 10.0).await?;
+
+    Ok(Renditions {
+        mp4_path,
+        hls_dir: Some(hls_dir.display().to_string()),
+    })
+}
+
+// a pass moves the job progress from `base_progress` by up to `share`
+async fn run_ffmpeg(
+    mut child: Child,
+    job_id: Uuid,
+    connection: &PgPool,
+    duration: Option<f64>,
+    base_progress: f32,
+    share: f32,
+) -> Result<(), String> {
+    if let Some(stdout) = child.stdout.take() {
+        let mut lines = BufReader::new(stdout).lines();
+        let mut reported = base_progress;
+        while let Ok(Some(line)) = lines.next_line().await {
+            let (position, duration) = match (ffmpeg::parse_progress_line(line.as_str()), duration)
+            {
+                (Some(position), Some(duration)) => (position, duration),
+                _ => continue,
+            };
+            let progress = base_progress
+                + (position / duration * share as f64).clamp(0.0, share as f64 - 1.0) as f32;
+            if progress - reported < 1.0 {
+                continue;
+            }
+            reported = progress;
+            let _ = sqlx::query(
+                r#"
+                    UPDATE transcode_jobs SET progress = $2, updated_at = NOW() WHERE id = $1
+                "#,
+            )
+            .bind(job_id)
+            .bind(progress)
+            .execute(connection)
+            .await;
+        }
+    }
+
+    let mut stderr = String::new();
+    if let Some(mut pipe) = child.stderr.take() {
+        let _ = pipe.read_to_string(&mut stderr).await;
+    }
+    let status = child.wait().await.map_err(|err| err.to_string())?;
+    if !status.success() {
+        return Err(format!("ffmpeg exited with {}: {}", status, stderr.trim()));
+    }
+    Ok(())
+}
+
+async fn finish_without_output(connection: &PgPool, job_id: Uuid) {
+    if let Err(err) = sqlx::query(
+        r#"
+            UPDATE transcode_jobs SET status = 'DONE', progress = 100, updated_at = NOW() WHERE id = $1
+        "#,
+    )
+    .bind(job_id)
+    .execute(connection)
+    .await
+    {
+        tracing::error!("Failed to finish transcode job {} {}", job_id, err);
+    }
+}
+
+async fn fail_job(connection: &PgPool, job_id: Uuid, error: &str) {
+    if let Err(err) = sqlx::query(
+        r#"
+            UPDATE transcode_jobs SET status = 'FAILED', error = $2, updated_at = NOW() WHERE id = $1
+        "#,
+    )
+    .bind(job_id)
+    .bind(error)
+    .execute(connection)
+    .await
+    {
+        tracing::error!("Failed to mark transcode job {} as failed {}", job_id, err);
+    }
+}
+
+// drops the jobs of a movie and everything they wrote to disk
+pub async fn remove_transcoded_output(
+    connection: &PgPool,
+    movie_id: String,
+    source: Source,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        r#"
+            DELETE FROM embedded_subtitles WHERE movie_id = $1 AND movie_source = $2
+        "#,
+    )
+    .bind(movie_id.clone())
+    .bind(source.clone() as Source)
+    .execute(connection)
+    .await?;
+    let rows = sqlx::query(
+        r#"
+            DELETE FROM transcode_jobs WHERE movie_id = $1 AND movie_source = $2
+            RETURNING output_dir
+        "#,
+    )
+    .bind(movie_id)
+    .bind(source as Source)
+    .fetch_all(connection)
+    .await?;
+    for row in rows {
+        let output_dir: String = row.get("output_dir");
+        match tokio::fs::remove_dir_all(output_dir.as_str()).await {
+            Ok(_) => tracing::info!("Removed transcoded output {}", output_dir),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+            Err(err) => tracing::error!("Failed to remove {} {}", output_dir, err),
+        }
+    }
+    Ok(())
+}
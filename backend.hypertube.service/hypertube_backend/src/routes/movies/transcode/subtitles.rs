@@ -0,0 +1,74 @@
+use sqlx::PgPool;
+use std::path::Path;
+use uuid::Uuid;
+
+use super::ffmpeg;
+use crate::routes::movies::torrent::{language_code, UNKNOWN_LANGUAGE};
+use crate::routes::movies::Source;
+
+const SUBTITLES_DIR_NAME: &str = "subtitles";
+
+// Converts the text subtitle tracks of the downloaded video to WebVTT files in
+// `{output_dir}/subtitles` and registers them for the movie. A track that
+// fails to convert is skipped, returns how many were extracted.
+pub async fn extract_embedded_subtitles(
+    connection: &PgPool,
+    movie_id: String,
+    source: Source,
+    input_path: &str,
+    output_dir: &Path,
+) -> Result<usize, String> {
+    let streams = ffmpeg::probe_subtitle_streams(input_path).await?;
+    let text_streams: Vec<_> = streams
+        .into_iter()
+        .filter(|stream| stream.is_text())
+        .collect();
+    if text_streams.is_empty() {
+        return Ok(0);
+    }
+
+    let subtitles_dir = output_dir.join(SUBTITLES_DIR_NAME);
+    tokio::fs::create_dir_all(&subtitles_dir)
+        .await
+        .map_err(|err| err.to_string())?;
+    let mut extracted = 0;
+    for stream in text_streams {
+        let output_path = subtitles_dir.join(format!("{}.vtt", stream.index));
+        if let Err(err) =
+            ffmpeg::extract_subtitle_to_vtt(input_path, stream.index, output_path.as_path()).await
+        {
+            tracing::warn!(
+                "Failed to extract subtitle stream {}: {}",
+                stream.index,
+                err
+            );
+            continue;
+        }
+        let language = stream
+            .language
+            .as_deref()
+            .and_then(language_code)
+            .unwrap_or(UNKNOWN_LANGUAGE);
+        sqlx::query(
+            r#"
+                INSERT INTO embedded_subtitles (id, movie_source, movie_id, stream_index, language, title, codec, path)
+                VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+                ON CONFLICT (movie_source, movie_id, stream_index)
+                DO UPDATE SET language = $5, title = $6, codec = $7, path = $8
+            "#,
+        )
+        .bind(Uuid::new_v4())
+        .bind(source.clone() as Source)
+        .bind(movie_id.clone())
+        .bind(stream.index as i32)
+        .bind(language)
+        .bind(stream.title.clone())
+        .bind(stream.codec.clone())
+        .bind(output_path.display().to_string())
+        .execute(connection)
+        .await
+        .map_err(|err| err.to_string())?;
+        extracted += 1;
+    }
+    Ok(extracted)
+}
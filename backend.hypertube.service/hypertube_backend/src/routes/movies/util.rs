@@ -1,8 +1,9 @@
 use super::{
-    delete_torrent, get_user_favorite_movies, get_user_watched_movies, get_favorite_movies, get_movie_info, get_movie_subtitles, get_movies_search, get_watched_movies, get_yts_top_movies, get_yts_top_movies_in_genre, remove_favorite_movie, set_favorite_movie, set_watched_movie, stream_video_content
+    delete_torrent, get_user_favorite_movies, stream_download_events, get_user_watched_movies, get_favorite_movies, get_movie_info, get_movie_subtitles, get_movies_search, get_continue_watching, get_playback_progress, get_stream_token, get_torrent_status, get_transcode_status, get_watched_movies, get_yts_top_movies, get_yts_top_movies_in_genre, remove_favorite_movie, set_favorite_movie, set_playback_progress, set_watched_movie, stream_hls_file, stream_hls_master, stream_video_content
 };
-use crate::middleware::Authentication;
+use crate::middleware::{AdminOnly, Authentication};
 use crate::routes::download_torrent;
+use crate::routes::TorrentIndexer;
 use actix_web::web::Data;
 use actix_web::{http, web, Scope};
 use chrono::NaiveDate;
@@ -119,7 +120,7 @@ impl std::fmt::Display for Source {
     }
 }
 
-#[derive(Deserialize, Copy, Clone, Debug)]
+#[derive(Deserialize, Copy, Clone, Debug, PartialEq, Eq)]
 pub enum MovieQuality {
     Q720p,
     Q1080p,
@@ -127,6 +128,31 @@ pub enum MovieQuality {
     Q3D,
 }
 
+impl MovieQuality {
+    pub const ALL: [MovieQuality; 4] = [
+        MovieQuality::Q720p,
+        MovieQuality::Q1080p,
+        MovieQuality::Q2160p,
+        MovieQuality::Q3D,
+    ];
+
+    // how YTS and release names spell the quality
+    pub fn label(&self) -> &'static str {
+        match self {
+            MovieQuality::Q720p => "720p",
+            MovieQuality::Q1080p => "1080p",
+            MovieQuality::Q2160p => "2160p",
+            MovieQuality::Q3D => "3D",
+        }
+    }
+
+    pub fn from_label(label: &str) -> Option<MovieQuality> {
+        MovieQuality::ALL
+            .into_iter()
+            .find(|quality| quality.label().eq_ignore_ascii_case(label.trim()))
+    }
+}
+
 #[derive(Default, Deserialize, Debug, Clone)]
 pub enum SearchOrder {
     #[default]
@@ -176,15 +202,55 @@ pub fn movie_source(db_pool: &PgPool) -> Scope {
                 .to(download_torrent)
                 .wrap(Authentication::new(db_pool.clone())),
         )
+        .route(
+            "/torrent/{source}/{movie_id}/status",
+            web::get()
+                .to(get_torrent_status)
+                .wrap(Authentication::new(db_pool.clone())),
+        )
+        .route(
+            "/transcode/{source}/{movie_id}/status",
+            web::get()
+                .to(get_transcode_status)
+                .wrap(Authentication::new(db_pool.clone())),
+        )
+        .route(
+            "/events/{source}/{movie_id}",
+            web::get()
+                .to(stream_download_events)
+                .wrap(Authentication::new(db_pool.clone())),
+        )
+        // removing a movie takes it away from every user
         .route(
             "/delete/{movie_id}/{source}",
             web::delete()
                 .to(delete_torrent)
+                .wrap(AdminOnly::new(db_pool.clone()))
+                .wrap(Authentication::new(db_pool.clone())),
+        )
+        .route(
+            "/stream/{source}/{movie_id}/master.m3u8",
+            web::get().to(stream_hls_master),
+        )
+        .route(
+            "/stream/{source}/{movie_id}/{quality}/{file_name}",
+            web::get().to(stream_hls_file),
+        )
+        .route(
+            "/stream-token/{source}/{movie_id}/{quality}",
+            web::get()
+                .to(get_stream_token)
                 .wrap(Authentication::new(db_pool.clone())),
         )
+        // checks the signed token or the session cookie itself, a <video>
+        // element can't always send cookies
         .route(
             "/stream/{source}/{movie_id}/{quality}",
-            web::get().to(stream_video_content), // .wrap(Authentication::new(db_pool.clone())),
+            web::get().to(stream_video_content),
+        )
+        .route(
+            "/stream/{source}/{movie_id}/{quality}",
+            web::head().to(stream_video_content),
         )
         .route("/favorite/{id}",
             web::get()
@@ -196,6 +262,24 @@ pub fn movie_source(db_pool: &PgPool) -> Scope {
             .to(get_user_watched_movies)
             .wrap(Authentication::new(db_pool.clone())),
         )
+        .route(
+            "/continue",
+            web::get()
+                .to(get_continue_watching)
+                .wrap(Authentication::new(db_pool.clone())),
+        )
+        .route(
+            "/progress",
+            web::post()
+                .to(set_playback_progress)
+                .wrap(Authentication::new(db_pool.clone())),
+        )
+        .route(
+            "/progress/{source}/{movie_id}",
+            web::get()
+                .to(get_playback_progress)
+                .wrap(Authentication::new(db_pool.clone())),
+        )
         .route(
             "/{id}/{source}",
             web::get()
@@ -435,62 +519,15 @@ async fn get_movie_db_watched_ids(
     Ok(ids)
 }
 
-async fn find_torrents(search_params: &SearchQueryMetadata) -> Result<Vec<Value>, String> {
-
-    let client = reqwest::Client::new();
-
-    let imdb_search_torrent_host = std::env::var("IMDB_SEARCH_TORRENT_HOST").unwrap();
-    // let imdb_search_torrrent_endpoint = std::env::var("IMDB_SEARCH_TORRENT_ENDPOINT").unwrap();
-    let imdb_search_torrent_token = std::env::var("IMDB_SEARCH_TORRENT_TOKEN").unwrap();
-
-    let search_url = format!(
-        "https://{}/search/{}",
-        imdb_search_torrent_host,
-        search_params.query_term,
-        // search_params.page
-    );
-    
-    let search_torrent_res = client
-        .get(search_url)
-        .header(
-            "x-rapidapi-key",
-            &imdb_search_torrent_token,
-        ).header("x-rapidapi-host", &imdb_search_torrent_host)
-        .send()
-        .await;
-
-    let response = match search_torrent_res {
-        Ok(res) => {
-            tracing::info!("RESPONSE ERROR ::: {:#?}", &res);
-            tracing::info!("Got Movie db search response");
-            res
-        }
-        Err(err) => {
-            tracing::error!("MOVIE DB request error {:#?}", err);
-            return Err(err.to_string());
-        }
-    };
-
-    if response.status() == 429{
-        return Err(String::from("you exceeded your daily QUOTA"));
-    }
-
-    let response_body = response.json::<serde_json::Value>().await;
-    let res = match response_body {
-        Ok(val) => {val}
-        Err(res_err) =>{
-            tracing::error!("Parsing response body error {:#?}", res_err);
-            return Err("Failed to parse response body".to_string());
-        }
-    };
-
-    if res["data"].as_array().is_none(){
-        return  Err(String::from("no movie data was provided"));
-    }
-
-    let movie_torrent_arr = res["data"].as_array().unwrap();
-
-    return Ok(movie_torrent_arr.clone());
+async fn find_torrents(
+    torrent_indexer: &dyn TorrentIndexer,
+    search_params: &SearchQueryMetadata,
+) -> Result<Vec<Value>, String> {
+    let torrents = torrent_indexer
+        .search_torrents(search_params.query_term.as_str())
+        .await?;
+    tracing::info!("Got {} torrents from the indexer", torrents.len());
+    Ok(torrents)
 }
 
 
@@ -835,13 +872,14 @@ async fn get_movies_list(
 
 pub async fn movie_db_handler(
     connection: &Data<PgPool>,
+    torrent_indexer: &dyn TorrentIndexer,
     query_span: Span,
     search_params: &SearchQueryMetadata,
 ) -> Result<serde_json::Value, String> {
 
     ////////// SEARCH TORRENT ////////////////
     
-    let find_torrent_res = find_torrents(search_params).await;
+    let find_torrent_res = find_torrents(torrent_indexer, search_params).await;
     let movie_torrent_arr = match find_torrent_res {
         Ok(val) => val,
         Err(err) => {return Err(err);}
@@ -0,0 +1,200 @@
+use actix_web::{
+    http::header,
+    web::{Data, Path},
+    HttpResponse,
+};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use sqlx::{PgPool, Row};
+
+use crate::routes::movies::torrent::{language_name, SubInfo};
+use crate::routes::Source;
+
+#[derive(Deserialize)]
+pub struct BundledSubtitlesQuery {
+    pub source: Option<Source>,
+    pub movie_id: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct BundledSubtitleInfo {
+    pub source: Source,
+    pub movie_id: String,
+    pub index: usize,
+}
+
+#[derive(Deserialize)]
+pub struct EmbeddedSubtitleInfo {
+    pub source: Source,
+    pub movie_id: String,
+    pub stream_index: i32,
+}
+
+async fn get_available_subs(
+    connection: &PgPool,
+    movie_id: String,
+    source: Source,
+) -> Result<Vec<SubInfo>, sqlx::Error> {
+    let row = sqlx::query(
+        r#"
+            SELECT available_subs FROM movie_torrent WHERE movie_id = $1 AND movie_source = $2
+        "#,
+    )
+    .bind(movie_id)
+    .bind(source as Source)
+    .fetch_optional(connection)
+    .await?;
+    let subs = row
+        .and_then(|row| row.get::<Option<Vec<Value>>, &str>("available_subs"))
+        .unwrap_or_default();
+    Ok(subs
+        .into_iter()
+        .filter_map(|sub| serde_json::from_value::<SubInfo>(sub).ok())
+        .collect())
+}
+
+// The subtitles that came with the torrent, sidecar files and the tracks
+// extracted from the video, listed next to the OpenSubtitles results. `url`
+// serves them as WebVTT when they are SubRip or WebVTT.
+pub async fn get_bundled_subtitles(
+    connection: &PgPool,
+    movie_id: String,
+    source: Source,
+) -> Result<Vec<Value>, sqlx::Error> {
+    let subs = get_available_subs(connection, movie_id.clone(), source.clone()).await?;
+    let mut bundled: Vec<Value> = subs
+        .iter()
+        .enumerate()
+        .map(|(index, sub)| {
+            json!({
+                "kind": "sidecar",
+                "language": sub.language,
+                "language_name": language_name(sub.language.as_str()),
+                "format": sub.format,
+                "url": format!("/subtitles/bundled/{}/{}/{}", source, movie_id, index),
+            })
+        })
+        .collect();
+
+    let embedded = sqlx::query(
+        r#"
+            SELECT stream_index, language, title FROM embedded_subtitles
+            WHERE movie_id = $1 AND movie_source = $2
+            ORDER BY stream_index
+        "#,
+    )
+    .bind(movie_id.clone())
+    .bind(source.clone() as Source)
+    .fetch_all(connection)
+    .await?;
+    bundled.extend(embedded.iter().map(|row| {
+        let language: String = row.get("language");
+        json!({
+            "kind": "embedded",
+            "language": language,
+            "language_name": language_name(language.as_str()),
+            "title": row.get::<Option<String>, &str>("title"),
+            "format": "vtt",
+            "url": format!(
+                "/subtitles/embedded/{}/{}/{}",
+                source,
+                movie_id,
+                row.get::<i32, &str>("stream_index")
+            ),
+        })
+    }));
+    Ok(bundled)
+}
+
+// SubRip and WebVTT only differ in the header and the millisecond separator
+pub fn srt_to_vtt(srt: &str) -> String {
+    let mut vtt = String::from("WEBVTT\n\n");
+    for line in srt.trim_start_matches('\u{feff}').lines() {
+        if line.contains("-->") {
+            vtt.push_str(line.replace(',', ".").as_str());
+        } else {
+            vtt.push_str(line);
+        }
+        vtt.push('\n');
+    }
+    vtt
+}
+
+pub async fn serve_bundled_subtitle(
+    connection: Data<PgPool>,
+    path: Path<BundledSubtitleInfo>,
+) -> HttpResponse {
+    let info = path.into_inner();
+    let subs = match get_available_subs(connection.as_ref(), info.movie_id, info.source).await {
+        Ok(subs) => subs,
+        Err(err) => {
+            tracing::error!("Database Error {:#?}", err);
+            return HttpResponse::BadRequest().json(json!({
+                "error": "Database error"
+            }));
+        }
+    };
+    let sub = match subs.get(info.index) {
+        Some(sub) => sub,
+        None => return HttpResponse::NotFound().finish(),
+    };
+    let content = match tokio::fs::read(sub.path.as_str()).await {
+        Ok(content) => String::from_utf8_lossy(&content).into_owned(),
+        Err(err) => {
+            tracing::error!("Can't read subtitle {} {}", sub.path, err);
+            return HttpResponse::NotFound().json(json!({
+                "error": "File not found"
+            }));
+        }
+    };
+    match sub.format.as_str() {
+        "srt" => HttpResponse::Ok()
+            .insert_header((header::CONTENT_TYPE, "text/vtt; charset=utf-8"))
+            .body(srt_to_vtt(content.as_str())),
+        "vtt" => HttpResponse::Ok()
+            .insert_header((header::CONTENT_TYPE, "text/vtt; charset=utf-8"))
+            .body(content),
+        _ => HttpResponse::Ok()
+            .insert_header((header::CONTENT_TYPE, "text/plain; charset=utf-8"))
+            .body(content),
+    }
+}
+
+pub async fn serve_embedded_subtitle(
+    connection: Data<PgPool>,
+    path: Path<EmbeddedSubtitleInfo>,
+) -> HttpResponse {
+    let info = path.into_inner();
+    let subtitle_path = match sqlx::query(
+        r#"
+            SELECT path FROM embedded_subtitles
+            WHERE movie_id = $1 AND movie_source = $2 AND stream_index = $3
+        "#,
+    )
+    .bind(info.movie_id)
+    .bind(info.source as Source)
+    .bind(info.stream_index)
+    .fetch_optional(connection.as_ref())
+    .await
+    {
+        Ok(Some(row)) => row.get::<String, &str>("path"),
+        Ok(None) => return HttpResponse::NotFound().finish(),
+        Err(err) => {
+            tracing::error!("Database Error {:#?}", err);
+            return HttpResponse::BadRequest().json(json!({
+                "error": "Database error"
+            }));
+        }
+    };
+    match tokio::fs::read(subtitle_path.as_str()).await {
+        Ok(content) => HttpResponse::Ok()
+            .insert_header((header::CONTENT_TYPE, "text/vtt; charset=utf-8"))
+            .body(content),
+        Err(err) => {
+            tracing::error!("Can't read subtitle {} {}", subtitle_path, err);
+            HttpResponse::NotFound().json(json!({
+                "error": "File not found"
+            }))
+        }
+    }
+}
@@ -1,6 +1,8 @@
 mod util;
+mod bundled_subtitles;
 mod search_subtitles;
 mod download_subtitle;
 pub use util::*;
 use search_subtitles::*;
-use download_subtitle::*; 
\ No newline at end of file
+use download_subtitle::*;
+pub use bundled_subtitles::*; 
\ No newline at end of file
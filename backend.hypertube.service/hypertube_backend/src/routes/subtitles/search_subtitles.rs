@@ -1,9 +1,11 @@
-use actix_web::{web::{Data, Path}, HttpRequest, HttpResponse};
+use actix_web::{web::{Data, Path, Query}, HttpRequest, HttpResponse};
 use serde_json::json;
 use serde::Deserialize;
 use sqlx::PgPool;
 use validator::Validate;
 
+use super::bundled_subtitles::{get_bundled_subtitles, BundledSubtitlesQuery};
+
 async fn fetch_subtitles_search(imdb_id: &String) -> Result<serde_json::Value, String> {
     let client = reqwest::Client::new();
 
@@ -65,7 +67,7 @@ pub async fn get_subtiles_search(
     req: HttpRequest,
     connection: Data<PgPool>,
     path: Path<RequestParam>,
-
+    query: Query<BundledSubtitlesQuery>,
 ) -> HttpResponse {
     let parsed_imdb_id = match path.imdb_id.parse::<String>(){
         Ok(imdb_id) => imdb_id,
@@ -77,8 +79,35 @@ pub async fn get_subtiles_search(
         }
     };
 
+    // subtitles that came with the downloaded torrent
+    let query = query.into_inner();
+    let bundled = match (query.movie_id, query.source) {
+        (Some(movie_id), Some(source)) => {
+            match get_bundled_subtitles(connection.as_ref(), movie_id, source).await {
+                Ok(bundled) => bundled,
+                Err(err) => {
+                    tracing::error!("Failed to get bundled subtitles {:#?}", err);
+                    Vec::new()
+                }
+            }
+        }
+        _ => Vec::new(),
+    };
+
     match fetch_subtitles_search(&parsed_imdb_id).await {
-        Ok(result) => HttpResponse::Ok().json(result),
+        Ok(mut result) => {
+            if let Some(result) = result.as_object_mut() {
+                result.insert("bundled".to_string(), json!(bundled));
+            }
+            HttpResponse::Ok().json(result)
+        }
+        Err(err) if !bundled.is_empty() => {
+            tracing::warn!("Serving bundled subtitles only: {:#?}", err);
+            HttpResponse::Ok().json(json!({
+                "data": [],
+                "bundled": bundled
+            }))
+        }
         Err(err) => {
             tracing::error!("Error fetching subtitles: {:#?}", err);
             HttpResponse::InternalServerError().json(json!({
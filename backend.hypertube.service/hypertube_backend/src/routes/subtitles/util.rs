@@ -4,6 +4,7 @@ use crate::middleware::Authentication;
 use sqlx::{PgPool, Row};
 use crate::routes::subtitles::search_subtitles::get_subtiles_search;
 use crate::routes::subtitles::download_subtitle::download_subtile_file;
+use crate::routes::subtitles::bundled_subtitles::{serve_bundled_subtitle, serve_embedded_subtitle};
 use crate::routes::subtitles::search_subtitles::RequestParam;
 // use super::{
 //     get_subtiles_search,
@@ -23,4 +24,16 @@ pub fn subtitle_source(db_pool: &PgPool) -> Scope {
                 .to(download_subtile_file)
                 .wrap(Authentication::new(db_pool.clone())),
         )
+        .route(
+            "/bundled/{source}/{movie_id}/{index}",
+            web::get()
+                .to(serve_bundled_subtitle)
+                .wrap(Authentication::new(db_pool.clone())),
+        )
+        .route(
+            "/embedded/{source}/{movie_id}/{stream_index}",
+            web::get()
+                .to(serve_embedded_subtitle)
+                .wrap(Authentication::new(db_pool.clone())),
+        )
 }
\ No newline at end of file
@@ -1,10 +1,16 @@
 use crate::passport::{generate_passports, passport_route_redirect, passport_oauth};
 use crate::routes::hello_world::handler;
 use crate::routes::movies::movie_source;
+use crate::routes::movies::torrent::{MagnetTrackers, TorrentClient};
+use crate::routes::movies::transcode::{LiveTranscodes, TranscodeQueue};
 use crate::routes::subtitles::subtitle_source;
 use crate::routes::password_rest::password_source;
 use crate::routes::user::user_source;
-use crate::routes::{comment_source, CronJobScheduler};
+use crate::routes::{
+    admin_source, comment_source, reconcile_on_startup, ActiveStreams, CronJobScheduler, DiskQuota,
+    torrent_indexer_from_env, DownloadEventHub, InFlightDownloads, StreamLimits, StreamTokens,
+    TorrentIndexer, WatchedThreshold,
+};
 
 use actix_web::{
     dev::Server,
@@ -13,6 +19,7 @@ use actix_web::{
 };
 use sqlx::PgPool;
 use std::net::TcpListener;
+use std::sync::Arc;
 use tokio::sync::RwLock;
 use tracing_actix_web::TracingLogger;
 
@@ -35,12 +42,45 @@ fn configure_cors(frontend_url: &str) -> Cors {
         .supports_credentials()
 }
 
-pub fn run_server(listener: TcpListener, db_pool: PgPool) -> Result<Server, std::io::Error> {
+pub fn run_server(
+    listener: TcpListener,
+    db_pool: PgPool,
+    torrent_client: Arc<dyn TorrentClient>,
+) -> Result<Server, std::io::Error> {
     dotenv().ok();
     let db_pool = Data::new(db_pool);
+    let torrent_client: Data<dyn TorrentClient> = Data::from(torrent_client);
     let passport_state =
         Data::new(RwLock::new(generate_passports()?));
-    let cron_task_handler = Data::new(CronJobScheduler::new());
+    let cron_task_handler = Data::new(CronJobScheduler::from_env());
+    CronJobScheduler::start(
+        cron_task_handler.clone(),
+        db_pool.clone(),
+        torrent_client.clone(),
+    );
+    let download_event_hub = Data::new(DownloadEventHub::new());
+    let transcode_queue = Data::new(TranscodeQueue::from_env());
+    TranscodeQueue::start(
+        transcode_queue.clone(),
+        db_pool.clone(),
+        torrent_client.clone(),
+        download_event_hub.clone(),
+    );
+    let live_transcodes = Data::new(LiveTranscodes::from_env());
+    let disk_quota = Data::new(DiskQuota::from_env());
+    let active_streams = Data::new(ActiveStreams::new());
+    let in_flight_downloads = Data::new(InFlightDownloads::new());
+    reconcile_on_startup(
+        db_pool.clone(),
+        torrent_client.clone(),
+        in_flight_downloads.clone(),
+        transcode_queue.clone(),
+    );
+    let magnet_trackers = Data::new(MagnetTrackers::from_env());
+    let watched_threshold = Data::new(WatchedThreshold::from_env());
+    let stream_tokens = Data::new(StreamTokens::from_env());
+    let stream_limits = Data::new(StreamLimits::from_env());
+    let torrent_indexer: Data<dyn TorrentIndexer> = Data::from(torrent_indexer_from_env());
     let frontend_url = env::var("FRONTEND_URL").expect("FRONTEND_URL must be set");
 
     let server: Server = HttpServer::new(move || {
@@ -49,6 +89,18 @@ pub fn run_server(listener: TcpListener, db_pool: PgPool) -> Result<Server, std:
             .wrap(cors)
             .wrap(TracingLogger::default())
             .app_data(cron_task_handler.clone())
+            .app_data(torrent_client.clone())
+            .app_data(download_event_hub.clone())
+            .app_data(transcode_queue.clone())
+            .app_data(live_transcodes.clone())
+            .app_data(disk_quota.clone())
+            .app_data(active_streams.clone())
+            .app_data(in_flight_downloads.clone())
+            .app_data(magnet_trackers.clone())
+            .app_data(watched_threshold.clone())
+            .app_data(stream_tokens.clone())
+            .app_data(stream_limits.clone())
+            .app_data(torrent_indexer.clone())
             .app_data(passport_state.clone())
             .service(passport_oauth())
             .service(passport_route_redirect())
@@ -57,6 +109,7 @@ pub fn run_server(listener: TcpListener, db_pool: PgPool) -> Result<Server, std:
             .service(password_source())
             .service(movie_source(&db_pool))
             .service(subtitle_source(&db_pool))
+            .service(admin_source(&db_pool))
             .route("/", web::get().to(handler))
             .app_data(db_pool.clone())
     })
@@ -105,5 +105,8 @@ pub fn check_for_necessary_env() -> Result<(), std::io::Error> {
             format!("MOVIE_DB_AUTH_TOKEN {}.", e),
         )
     })?;
+    env::var("RQBIT_HOST").map_err(|e| {
+        std::io::Error::new(std::io::ErrorKind::Other, format!("RQBIT_HOST {}.", e))
+    })?;
     Ok(())
 }
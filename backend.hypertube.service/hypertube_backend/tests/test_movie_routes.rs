@@ -0,0 +1,2241 @@
+mod test_startup;
+
+use actix_web::{http, web, App, HttpResponse, HttpServer};
+use hypertube_backend::routes::movies::torrent::{
+    have_ranges, Magnet, ReleaseName, ReleaseSource, RqbitWrapper, TorrentClient,
+};
+use hypertube_backend::routes::movies::transcode::ffmpeg::{AudioTrack, MediaProbe};
+use hypertube_backend::routes::movies::transcode::live::live_mode;
+use hypertube_backend::routes::movies::transcode::media_probe::playback_mode;
+use hypertube_backend::routes::movies::transcode::{HlsRendition, PlaybackMode};
+use hypertube_backend::routes::{
+    choose_torrent, movie_db_catalog_torrents, throttle, ActiveStreams, CatalogTorrent,
+    CronJobScheduler, DiskQuota, MovieQuality, QuotaError, RapidApiIndexer, Source,
+    StreamLimitError, StreamLimits, StreamTokenError, StreamTokens, TokenBucket, TorrentIndexer,
+    TorznabIndexers,
+};
+use serde_json::json;
+use sqlx::Row;
+use std::collections::HashMap;
+use std::net::TcpListener;
+use test_startup::*;
+
+const TEST_MAGNET: &str =
+    "magnet:?xt=urn:btih:c9e15763f722f23e98a29decdfae341b98d53056&dn=Test+Movie";
+
+async fn create_session(address: &str) -> String {
+    let client = reqwest::Client::new();
+    let res = client
+        .post(format!("{}/users/sign-up", address))
+        .json(&json!({
+            "email": "movie@gmail.com",
+            "first_name": "test first name",
+            "last_name": "test last name",
+            "username": "movieuser123",
+            "password": "Password@123",
+        }))
+        .send()
+        .await
+        .expect("Failed to execute request");
+
+    assert!(res.status().is_success());
+    let mut session_id = String::new();
+    for cookie in res.cookies() {
+        if cookie.name() == "session" {
+            cookie.value().clone_into(&mut session_id);
+        }
+    }
+    assert!(!session_id.is_empty());
+    session_id
+}
+
+async fn make_admin(app: &TestApp) {
+    sqlx::query("UPDATE users SET is_admin = TRUE WHERE username = 'movieuser123'")
+        .execute(&app.db_pool)
+        .await
+        .expect("Failed to promote the user");
+}
+
+async fn start_download(app: &TestApp, session_id: &str) {
+    start_movie_download(app, session_id, "42", TEST_MAGNET).await;
+}
+
+// lists the magnet's torrent in the catalog the server picks torrents from
+async fn add_catalog_torrent(app: &TestApp, movie_id: &str, magnet: &str) {
+    let magnet = Magnet::parse(magnet).expect("Invalid magnet");
+    sqlx::query(
+        r#"
+            INSERT INTO imdb_movie_details (id, primary_title, torrents)
+            VALUES ($1, $2, ARRAY[$3::json])
+        "#,
+    )
+    .bind(movie_id)
+    .bind("Test Movie")
+    .bind(json!({
+        "title": "Test.Movie.2024.720p.WEBRip",
+        "quality": "720p",
+        "hash": magnet.info_hash(),
+    }))
+    .execute(&app.db_pool)
+    .await
+    .expect("Failed to add catalog torrent");
+}
+
+async fn start_movie_download(app: &TestApp, session_id: &str, movie_id: &str, magnet: &str) {
+    add_catalog_torrent(app, movie_id, magnet).await;
+    let response = reqwest::Client::new()
+        .post(format!("{}/movies/torrent", app.address))
+        .header(http::header::COOKIE, format!("session={}", session_id))
+        .json(&json!({
+            "movie_id": movie_id,
+            "source": "MovieDb",
+            "quality": "Q720p",
+        }))
+        .send()
+        .await
+        .expect("Failed to send request");
+    assert!(response.status().is_success());
+}
+
+#[actix_rt::test]
+async fn download_stream_and_delete_movie() {
+    let app = spawn_app().await;
+    let content: Vec<u8> = (0..=255u8).cycle().take(4096).collect();
+    app.torrent_client.register_magnet(
+        TEST_MAGNET,
+        vec![("movie.mp4".to_string(), content.clone())],
+    );
+    let session_id = create_session(app.address.as_str()).await;
+    let client = reqwest::Client::new();
+
+    start_download(&app, session_id.as_str()).await;
+    assert_eq!(app.torrent_client.torrent_ids().len(), 1);
+
+    let response = client
+        .get(format!("{}/movies/stream/MovieDb/42/Q720p", app.address))
+        .header(http::header::COOKIE, format!("session={}", session_id))
+        .header(http::header::RANGE, "bytes=100-199")
+        .send()
+        .await
+        .expect("Failed to send request");
+    assert_eq!(response.status().as_u16(), 206);
+    let body = response.bytes().await.expect("Failed to read body");
+    assert_eq!(body.as_ref(), &content[100..200]);
+
+    // only admins remove movies
+    let response = client
+        .delete(format!("{}/movies/delete/42/MovieDb", app.address))
+        .header(http::header::COOKIE, format!("session={}", session_id))
+        .send()
+        .await
+        .expect("Failed to send request");
+    assert_eq!(response.status().as_u16(), 403);
+    assert_eq!(app.torrent_client.torrent_ids().len(), 1);
+
+    make_admin(&app).await;
+    let response = client
+        .delete(format!("{}/movies/delete/42/MovieDb", app.address))
+        .header(http::header::COOKIE, format!("session={}", session_id))
+        .send()
+        .await
+        .expect("Failed to send request");
+    assert!(response.status().is_success());
+    assert!(app.torrent_client.torrent_ids().is_empty());
+}
+
+#[actix_rt::test]
+async fn torrent_status_reports_download_progress() {
+    let app = spawn_app().await;
+    let content: Vec<u8> = vec![0; 4096];
+    app.torrent_client
+        .register_magnet(TEST_MAGNET, vec![("movie.mp4".to_string(), content)]);
+    let session_id = create_session(app.address.as_str()).await;
+    let client = reqwest::Client::new();
+    let status_address = format!("{}/movies/torrent/MovieDb/42/status", app.address);
+
+    let response = client
+        .get(status_address.as_str())
+        .header(http::header::COOKIE, format!("session={}", session_id))
+        .send()
+        .await
+        .expect("Failed to send request");
+    assert_eq!(response.status().as_u16(), 404);
+
+    start_download(&app, session_id.as_str()).await;
+    let torrent_id = app.torrent_client.torrent_ids()[0];
+    app.torrent_client.set_progress(torrent_id, 1024);
+
+    let body = client
+        .get(status_address.as_str())
+        .header(http::header::COOKIE, format!("session={}", session_id))
+        .send()
+        .await
+        .expect("Failed to send request")
+        .json::<serde_json::Value>()
+        .await
+        .expect("Failed to parse response body");
+    assert_eq!(body["data"]["state"], "downloading");
+    assert_eq!(body["data"]["bytes_downloaded"], 1024);
+    assert_eq!(body["data"]["total_size"], 4096);
+
+    app.torrent_client.set_progress(torrent_id, 4096);
+    let body = client
+        .get(status_address.as_str())
+        .header(http::header::COOKIE, format!("session={}", session_id))
+        .send()
+        .await
+        .expect("Failed to send request")
+        .json::<serde_json::Value>()
+        .await
+        .expect("Failed to parse response body");
+    assert_eq!(body["data"]["state"], "seeding");
+}
+
+#[actix_rt::test]
+async fn download_events_push_progress_and_ready_to_stream() {
+    let app = spawn_app().await;
+    app.torrent_client
+        .register_magnet(TEST_MAGNET, vec![("movie.mp4".to_string(), vec![0; 4096])]);
+    let session_id = create_session(app.address.as_str()).await;
+    start_download(&app, session_id.as_str()).await;
+
+    let mut response = reqwest::Client::new()
+        .get(format!("{}/movies/events/MovieDb/42", app.address))
+        .header(http::header::COOKIE, format!("session={}", session_id))
+        .send()
+        .await
+        .expect("Failed to send request");
+    assert!(response.status().is_success());
+    assert_eq!(
+        response.headers()[http::header::CONTENT_TYPE],
+        "text/event-stream"
+    );
+
+    let mut received = String::new();
+    while !received.contains("event: ready_to_stream") {
+        let chunk = tokio::time::timeout(std::time::Duration::from_secs(10), response.chunk())
+            .await
+            .expect("Timed out waiting for events")
+            .expect("Failed to read event stream")
+            .expect("Event stream closed");
+        received.push_str(String::from_utf8_lossy(&chunk).as_ref());
+    }
+    assert!(received.contains("event: progress"));
+}
+
+#[actix_rt::test]
+async fn download_events_catch_up_a_viewer_that_joins_late() {
+    let app = spawn_app().await;
+    app.torrent_client
+        .register_magnet(TEST_MAGNET, vec![("movie.mp4".to_string(), vec![0; 4096])]);
+    let session_id = create_session(app.address.as_str()).await;
+    start_download(&app, session_id.as_str()).await;
+    let client = reqwest::Client::new();
+    let events_address = format!("{}/movies/events/MovieDb/42", app.address);
+    let read_until = |mut response: reqwest::Response, event: &'static str| async move {
+        let mut received = String::new();
+        while !received.contains(event) {
+            let chunk = tokio::time::timeout(std::time::Duration::from_secs(10), response.chunk())
+                .await
+                .expect("Timed out waiting for events")
+                .expect("Failed to read event stream")
+                .expect("Event stream closed");
+            received.push_str(String::from_utf8_lossy(&chunk).as_ref());
+        }
+        (response, received)
+    };
+
+    let first = client
+        .get(events_address.as_str())
+        .header(http::header::COOKIE, format!("session={}", session_id))
+        .send()
+        .await
+        .expect("Failed to send request");
+    let (_first, _) = read_until(first, "event: ready_to_stream").await;
+
+    // the transcode finished while nobody new was watching
+    sqlx::query(
+        r#"
+            INSERT INTO transcode_jobs (id, movie_source, movie_id, torrent_id, input_path, output_dir, status, created_at, updated_at)
+            VALUES ($1, 'MOVIEDB', '42', 1, 'movie.mp4', 'out', 'DONE', NOW(), NOW())
+        "#,
+    )
+    .bind(uuid::Uuid::new_v4())
+    .execute(&app.db_pool)
+    .await
+    .expect("Failed to add transcode job");
+
+    // the poller already sent ready_to_stream, the late viewer still gets it
+    let late = client
+        .get(events_address.as_str())
+        .header(http::header::COOKIE, format!("session={}", session_id))
+        .send()
+        .await
+        .expect("Failed to send request");
+    let (_late, received) = read_until(late, "event: transcode_finished").await;
+    assert!(received.starts_with("event: progress"));
+    assert!(received.contains("event: ready_to_stream"));
+}
+
+#[actix_rt::test]
+async fn stream_waits_for_range_still_downloading() {
+    let app = spawn_app().await;
+    let content: Vec<u8> = (0..=255u8).cycle().take(4096).collect();
+    app.torrent_client.register_magnet(
+        TEST_MAGNET,
+        vec![("movie.mp4".to_string(), content.clone())],
+    );
+    let session_id = create_session(app.address.as_str()).await;
+    start_download(&app, session_id.as_str()).await;
+    let torrent_id = app.torrent_client.torrent_ids()[0];
+    app.torrent_client.set_progress(torrent_id, 1024);
+
+    let client = reqwest::Client::new();
+    // only the downloaded part of the requested range is served
+    let response = client
+        .get(format!("{}/movies/stream/MovieDb/42/Q720p", app.address))
+        .header(http::header::COOKIE, format!("session={}", session_id))
+        .header(http::header::RANGE, "bytes=1000-1999")
+        .send()
+        .await
+        .expect("Failed to send request");
+    assert_eq!(response.status().as_u16(), 206);
+    assert_eq!(
+        response.headers()[http::header::CONTENT_RANGE],
+        "bytes 1000-1023/4096"
+    );
+
+    // a range past the download watermark is prioritized and waited for
+    let torrent_client = app.torrent_client.clone();
+    tokio::spawn(async move {
+        tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+        torrent_client.set_progress(torrent_id, 4096);
+    });
+    let response = client
+        .get(format!("{}/movies/stream/MovieDb/42/Q720p", app.address))
+        .header(http::header::COOKIE, format!("session={}", session_id))
+        .header(http::header::RANGE, "bytes=3000-3099")
+        .send()
+        .await
+        .expect("Failed to send request");
+    assert_eq!(response.status().as_u16(), 206);
+    let body = response.bytes().await.expect("Failed to read body");
+    assert_eq!(body.as_ref(), &content[3000..3100]);
+    assert!(app
+        .torrent_client
+        .prioritized_ranges()
+        .iter()
+        .any(|(id, _, range)| *id == torrent_id && range.contains(&3000)));
+}
+
+#[actix_rt::test]
+async fn stream_prioritizes_only_the_read_ahead_past_the_playhead() {
+    let app = spawn_app().await;
+    let length = 40 * 1024 * 1024;
+    app.torrent_client.register_magnet(
+        TEST_MAGNET,
+        vec![("movie.mp4".to_string(), vec![0; length])],
+    );
+    let session_id = create_session(app.address.as_str()).await;
+    start_download(&app, session_id.as_str()).await;
+    let torrent_id = app.torrent_client.torrent_ids()[0];
+    app.torrent_client.set_progress(torrent_id, 0);
+
+    let torrent_client = app.torrent_client.clone();
+    tokio::spawn(async move {
+        tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+        torrent_client.set_progress(torrent_id, length as u64);
+    });
+    // players ask for the rest of the file
+    let response = reqwest::Client::new()
+        .get(format!("{}/movies/stream/MovieDb/42/Q720p", app.address))
+        .header(http::header::COOKIE, format!("session={}", session_id))
+        .header(http::header::RANGE, "bytes=0-")
+        .send()
+        .await
+        .expect("Failed to send request");
+    assert_eq!(response.status().as_u16(), 206);
+    drop(response);
+    let prioritized = app.torrent_client.prioritized_ranges();
+    assert!(prioritized
+        .iter()
+        .any(|(id, _, range)| *id == torrent_id && range.start == 0));
+    assert!(prioritized
+        .iter()
+        .all(|(_, _, range)| range.end - range.start <= 16 * 1024 * 1024));
+}
+
+#[actix_rt::test]
+async fn stream_asks_the_torrent_client_again_only_past_the_downloaded_part() {
+    let app = spawn_app().await;
+    let content: Vec<u8> = (0..=255u8).cycle().take(1024 * 1024).collect();
+    app.torrent_client.register_magnet(
+        TEST_MAGNET,
+        vec![("movie.mp4".to_string(), content.clone())],
+    );
+    let session_id = create_session(app.address.as_str()).await;
+    start_download(&app, session_id.as_str()).await;
+    let torrent_id = app.torrent_client.torrent_ids()[0];
+    app.torrent_client.set_progress(torrent_id, 512 * 1024);
+    let torrent_client = app.torrent_client.clone();
+    tokio::spawn(async move {
+        tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+        torrent_client.set_progress(torrent_id, 1024 * 1024);
+    });
+
+    let response = reqwest::Client::new()
+        .get(format!("{}/movies/stream/MovieDb/42/Q720p", app.address))
+        .header(http::header::COOKIE, format!("session={}", session_id))
+        .send()
+        .await
+        .expect("Failed to send request");
+    assert!(response.status().is_success());
+    assert_eq!(response.bytes().await.unwrap().as_ref(), content.as_slice());
+    // 16 chunks of the body but once for each half of the file, plus the
+    // polls while the second half downloads
+    let checks = app.torrent_client.availability_checks();
+    assert!((2..=5).contains(&checks), "{}", checks);
+}
+
+#[actix_rt::test]
+async fn rqbit_available_ranges_follow_the_verified_pieces() {
+    // 1100 bytes in 256 byte pieces, the movie starts 100 bytes in
+    let listener = TcpListener::bind("127.0.0.1:0").expect("Failed to bind");
+    let port = listener.local_addr().unwrap().port();
+    let server = HttpServer::new(|| {
+        App::new()
+            .route(
+                "/torrents/1",
+                web::get().to(|| async {
+                    HttpResponse::Ok().json(json!({
+                        "files": [
+                            { "name": "movie.nfo", "length": 100 },
+                            { "name": "movie.mkv", "length": 1000 },
+                        ]
+                    }))
+                }),
+            )
+            .route(
+                "/torrents/1/metadata",
+                web::get().to(|| async {
+                    HttpResponse::Ok().body(&b"d4:infod6:lengthi1100e12:piece lengthi256eee"[..])
+                }),
+            )
+            // pieces 0, 1 and 3 are verified
+            .route(
+                "/torrents/1/haves",
+                web::get().to(|| async { HttpResponse::Ok().body(vec![0b1101_0000u8]) }),
+            )
+    })
+    .workers(1)
+    .listen(listener)
+    .expect("Failed to listen")
+    .run();
+    tokio::spawn(server);
+
+    let client = RqbitWrapper::new(format!("http://127.0.0.1:{}", port), "/downloads");
+    assert_eq!(
+        client.available_ranges(1, 1).await.unwrap(),
+        vec![0..412, 668..924]
+    );
+    assert_eq!(client.available_ranges(1, 0).await.unwrap(), vec![0..100]);
+    assert_eq!(have_ranges(&[0b0100_0000], 256, 0, 1100), vec![256..512]);
+    assert_eq!(
+        have_ranges(&[], 256, 0, 1100),
+        Vec::<std::ops::Range<u64>>::new()
+    );
+}
+
+#[actix_rt::test]
+async fn expiry_sweep_deletes_only_unwatched_movies() {
+    let app = spawn_app().await;
+    app.torrent_client
+        .register_magnet(TEST_MAGNET, vec![("movie.mp4".to_string(), vec![0; 4096])]);
+    let session_id = create_session(app.address.as_str()).await;
+    start_download(&app, session_id.as_str()).await;
+    let scheduler = CronJobScheduler::new(
+        std::time::Duration::from_secs(7 * 24 * 3600),
+        std::time::Duration::from_secs(3600),
+    );
+    let expire_movie = || async {
+        sqlx::query(
+            "UPDATE movie_torrent SET last_watched_at = NOW() - INTERVAL '10 days' WHERE movie_id = '42'",
+        )
+        .execute(&app.db_pool)
+        .await
+        .expect("Failed to update movie");
+    };
+
+    // watching the movie pushes its expiry back
+    expire_movie().await;
+    let response = reqwest::Client::new()
+        .get(format!("{}/movies/stream/MovieDb/42/Q720p", app.address))
+        .header(http::header::COOKIE, format!("session={}", session_id))
+        .header(http::header::RANGE, "bytes=0-99")
+        .send()
+        .await
+        .expect("Failed to send request");
+    assert_eq!(response.status().as_u16(), 206);
+    let report = scheduler
+        .sweep(&app.db_pool, app.torrent_client.as_ref())
+        .await;
+    assert_eq!(report.deleted, 0);
+    assert_eq!(app.torrent_client.torrent_ids().len(), 1);
+
+    expire_movie().await;
+    let report = scheduler
+        .sweep(&app.db_pool, app.torrent_client.as_ref())
+        .await;
+    assert_eq!(report.deleted, 1);
+    assert!(report.errors.is_empty());
+    assert!(app.torrent_client.torrent_ids().is_empty());
+
+    let row = sqlx::query("SELECT COUNT(*) AS count FROM movie_torrent")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to count movies");
+    assert_eq!(row.get::<i64, &str>("count"), 0);
+    let row = sqlx::query("SELECT SUM(deleted_count) AS deleted FROM movie_expiry_sweeps")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to read sweep results");
+    assert_eq!(row.get::<i64, &str>("deleted"), 1);
+}
+
+#[actix_rt::test]
+async fn transcode_job_runs_off_the_request_path() {
+    let app = spawn_app().await;
+    let content: Vec<u8> = (0..=255u8).cycle().take(4096).collect();
+    app.torrent_client.register_magnet(
+        TEST_MAGNET,
+        vec![("movie.mkv".to_string(), content.clone())],
+    );
+    let session_id = create_session(app.address.as_str()).await;
+    let client = reqwest::Client::new();
+    let status_address = format!("{}/movies/transcode/MovieDb/42/status", app.address);
+
+    let response = client
+        .get(status_address.as_str())
+        .header(http::header::COOKIE, format!("session={}", session_id))
+        .send()
+        .await
+        .expect("Failed to send request");
+    assert_eq!(response.status().as_u16(), 404);
+
+    start_download(&app, session_id.as_str()).await;
+
+    // the file isn't a real video, so the worker ends up failing the job
+    let mut status = serde_json::Value::Null;
+    for _ in 0..40 {
+        status = client
+            .get(status_address.as_str())
+            .header(http::header::COOKIE, format!("session={}", session_id))
+            .send()
+            .await
+            .expect("Failed to send request")
+            .json::<serde_json::Value>()
+            .await
+            .expect("Failed to parse response body");
+        if status["data"]["status"] == "failed" {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(250)).await;
+    }
+    assert_eq!(status["data"]["status"], "failed");
+    assert!(status["data"]["error"].is_string());
+
+    // without a rendition, and with a file ffmpeg can't convert live, the
+    // original file keeps being served
+    let response = client
+        .get(format!("{}/movies/stream/MovieDb/42/Q720p", app.address))
+        .header(http::header::COOKIE, format!("session={}", session_id))
+        .header(http::header::RANGE, "bytes=0-99")
+        .send()
+        .await
+        .expect("Failed to send request");
+    assert_eq!(response.status().as_u16(), 206);
+    let body = response.bytes().await.expect("Failed to read body");
+    assert_eq!(body.as_ref(), &content[..100]);
+}
+
+#[actix_rt::test]
+async fn hls_package_is_served_per_quality() {
+    let app = spawn_app().await;
+    app.torrent_client
+        .register_magnet(TEST_MAGNET, vec![("movie.mp4".to_string(), vec![0; 4096])]);
+    let session_id = create_session(app.address.as_str()).await;
+    start_download(&app, session_id.as_str()).await;
+    let client = reqwest::Client::new();
+    let cookie = format!("session={}", session_id);
+    let master_address = format!("{}/movies/stream/MovieDb/42/master.m3u8", app.address);
+
+    let response = client
+        .get(master_address.as_str())
+        .header(http::header::COOKIE, cookie.as_str())
+        .send()
+        .await
+        .expect("Failed to send request");
+    assert_eq!(response.status().as_u16(), 404);
+
+    // lay out a package the way the transcode worker writes it
+    let hls_dir = std::env::temp_dir().join(format!("hls_{}", app.database_settings.db_name));
+    let master = "#EXTM3U\n#EXT-X-STREAM-INF:BANDWIDTH=1400000\n480p/index.m3u8\n#EXT-X-STREAM-INF:BANDWIDTH=2800000\n720p/index.m3u8\n";
+    std::fs::create_dir_all(hls_dir.join("720p")).unwrap();
+    std::fs::write(hls_dir.join("master.m3u8"), master).unwrap();
+    std::fs::write(
+        hls_dir.join("720p/index.m3u8"),
+        "#EXTM3U\nsegment_0000.ts\n",
+    )
+    .unwrap();
+    std::fs::write(hls_dir.join("720p/segment_0000.ts"), vec![7u8; 188]).unwrap();
+    sqlx::query("UPDATE movie_torrent SET hls_path = $1 WHERE movie_id = '42'")
+        .bind(hls_dir.display().to_string())
+        .execute(&app.db_pool)
+        .await
+        .expect("Failed to update movie");
+
+    // the package is no more public than the progressive stream
+    for path in ["master.m3u8", "720p/index.m3u8", "720p/segment_0000.ts"] {
+        let response = client
+            .get(format!("{}/movies/stream/MovieDb/42/{}", app.address, path))
+            .send()
+            .await
+            .expect("Failed to send request");
+        assert_eq!(response.status().as_u16(), 401, "{}", path);
+    }
+
+    let response = client
+        .get(master_address.as_str())
+        .header(http::header::COOKIE, cookie.as_str())
+        .send()
+        .await
+        .expect("Failed to send request");
+    assert!(response.status().is_success());
+    assert_eq!(
+        response.headers()[http::header::CONTENT_TYPE],
+        "application/vnd.apple.mpegurl"
+    );
+    assert_eq!(response.text().await.unwrap(), master);
+
+    // the MovieQuality name maps onto the matching rendition
+    let response = client
+        .get(format!(
+            "{}/movies/stream/MovieDb/42/Q720p/index.m3u8",
+            app.address
+        ))
+        .header(http::header::COOKIE, cookie.as_str())
+        .send()
+        .await
+        .expect("Failed to send request");
+    assert!(response.status().is_success());
+    assert!(response.text().await.unwrap().contains("segment_0000.ts"));
+
+    let response = client
+        .get(format!(
+            "{}/movies/stream/MovieDb/42/720p/segment_0000.ts",
+            app.address
+        ))
+        .header(http::header::COOKIE, cookie.as_str())
+        .send()
+        .await
+        .expect("Failed to send request");
+    assert!(response.status().is_success());
+    assert_eq!(response.headers()[http::header::CONTENT_TYPE], "video/mp2t");
+    assert_eq!(response.bytes().await.unwrap().len(), 188);
+
+    for path in ["480p/segment_0000.ts", "720p/video.mp4", "4K/index.m3u8"] {
+        let response = client
+            .get(format!("{}/movies/stream/MovieDb/42/{}", app.address, path))
+            .header(http::header::COOKIE, cookie.as_str())
+            .send()
+            .await
+            .expect("Failed to send request");
+        assert_eq!(response.status().as_u16(), 404, "{}", path);
+    }
+
+    // a player without cookies follows the token through the playlists
+    let response = client
+        .get(format!(
+            "{}/movies/stream-token/MovieDb/42/Q720p",
+            app.address
+        ))
+        .header(http::header::COOKIE, cookie.as_str())
+        .send()
+        .await
+        .expect("Failed to send request");
+    let body: serde_json::Value = response.json().await.expect("Failed to read body");
+    let token = body["data"]["token"].as_str().unwrap().to_string();
+    let response = client
+        .get(format!("{}?token={}", master_address, token))
+        .send()
+        .await
+        .expect("Failed to send request");
+    assert!(response.status().is_success());
+    let playlist = response.text().await.unwrap();
+    assert!(playlist.contains(format!("\n480p/index.m3u8?token={}\n", token).as_str()));
+    assert!(playlist.contains(format!("\n720p/index.m3u8?token={}\n", token).as_str()));
+    let response = client
+        .get(format!(
+            "{}/movies/stream/MovieDb/42/720p/index.m3u8?token={}",
+            app.address, token
+        ))
+        .send()
+        .await
+        .expect("Failed to send request");
+    assert_eq!(
+        response.text().await.unwrap(),
+        format!("#EXTM3U\nsegment_0000.ts?token={}\n", token)
+    );
+    let response = client
+        .get(format!(
+            "{}/movies/stream/MovieDb/42/720p/segment_0000.ts?token={}",
+            app.address, token
+        ))
+        .send()
+        .await
+        .expect("Failed to send request");
+    assert!(response.status().is_success());
+    assert_eq!(response.bytes().await.unwrap().len(), 188);
+
+    // the token opens this movie only
+    let response = client
+        .get(format!(
+            "{}/movies/stream/MovieDb/43/720p/segment_0000.ts?token={}",
+            app.address, token
+        ))
+        .send()
+        .await
+        .expect("Failed to send request");
+    assert_eq!(response.status().as_u16(), 403);
+
+    // the package counts as one of the user's streams
+    for movie_id in ["43", "44", "45"] {
+        let magnet = format!(
+            "magnet:?xt=urn:btih:{}&dn=Movie+{}",
+            movie_id.repeat(20),
+            movie_id
+        );
+        app.torrent_client.register_magnet(
+            magnet.as_str(),
+            vec![("movie.mp4".to_string(), vec![0; 4096])],
+        );
+        start_movie_download(&app, session_id.as_str(), movie_id, magnet.as_str()).await;
+        let response = client
+            .get(format!(
+                "{}/movies/stream/MovieDb/{}/Q720p",
+                app.address, movie_id
+            ))
+            .header(http::header::COOKIE, cookie.as_str())
+            .send()
+            .await
+            .expect("Failed to send request");
+        let expected = if movie_id == "45" { 429 } else { 200 };
+        assert_eq!(response.status().as_u16(), expected, "{}", movie_id);
+    }
+    std::fs::remove_dir_all(hls_dir).unwrap();
+}
+
+#[actix_rt::test]
+async fn hls_master_sends_direct_play_and_remux_movies_to_the_progressive_stream() {
+    let app = spawn_app().await;
+    let content: Vec<u8> = (0..=255u8).cycle().take(4096).collect();
+    app.torrent_client.register_magnet(
+        TEST_MAGNET,
+        vec![("movie.mp4".to_string(), content.clone())],
+    );
+    let session_id = create_session(app.address.as_str()).await;
+    start_download(&app, session_id.as_str()).await;
+    let cookie = format!("session={}", session_id);
+    let client = reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+        .unwrap();
+    let master_address = format!("{}/movies/stream/MovieDb/42/master.m3u8", app.address);
+    let response = client
+        .get(format!(
+            "{}/movies/stream-token/MovieDb/42/Q1080p",
+            app.address
+        ))
+        .header(http::header::COOKIE, cookie.as_str())
+        .send()
+        .await
+        .expect("Failed to send request");
+    let body: serde_json::Value = response.json().await.expect("Failed to read body");
+    let token = body["data"]["token"].as_str().unwrap().to_string();
+
+    for mode in ["DIRECT_PLAY", "REMUX"] {
+        sqlx::query(
+            "UPDATE movie_torrent SET playback_mode = $1::playback_mode, hls_path = NULL WHERE movie_id = '42'",
+        )
+        .bind(mode)
+        .execute(&app.db_pool)
+        .await
+        .expect("Failed to update movie");
+
+        let response = client
+            .get(master_address.as_str())
+            .header(http::header::COOKIE, cookie.as_str())
+            .send()
+            .await
+            .expect("Failed to send request");
+        assert_eq!(response.status().as_u16(), 307, "{}", mode);
+        let location = response.headers()[http::header::LOCATION]
+            .to_str()
+            .unwrap()
+            .to_string();
+        assert_eq!(location, "/movies/stream/MovieDb/42/Q720p", "{}", mode);
+        let response = client
+            .get(format!("{}{}", app.address, location))
+            .header(http::header::COOKIE, cookie.as_str())
+            .send()
+            .await
+            .expect("Failed to send request");
+        assert!(response.status().is_success(), "{}", mode);
+        assert_eq!(response.bytes().await.unwrap().as_ref(), content.as_slice());
+
+        // the token stays good for the quality it was issued for
+        let response = client
+            .get(format!("{}?token={}", master_address, token))
+            .send()
+            .await
+            .expect("Failed to send request");
+        assert_eq!(response.status().as_u16(), 307, "{}", mode);
+        let location = response.headers()[http::header::LOCATION]
+            .to_str()
+            .unwrap()
+            .to_string();
+        assert_eq!(
+            location,
+            format!("/movies/stream/MovieDb/42/Q1080p?token={}", token)
+        );
+        let response = client
+            .get(format!("{}{}", app.address, location))
+            .send()
+            .await
+            .expect("Failed to send request");
+        assert!(response.status().is_success(), "{}", mode);
+
+        let response = client
+            .get(format!(
+                "{}/movies/stream/MovieDb/42/720p/index.m3u8",
+                app.address
+            ))
+            .header(http::header::COOKIE, cookie.as_str())
+            .send()
+            .await
+            .expect("Failed to send request");
+        assert_eq!(response.status().as_u16(), 404, "{}", mode);
+    }
+
+    // a transcoded movie waits for its package
+    sqlx::query("UPDATE movie_torrent SET playback_mode = 'TRANSCODE' WHERE movie_id = '42'")
+        .execute(&app.db_pool)
+        .await
+        .expect("Failed to update movie");
+    let response = client
+        .get(master_address.as_str())
+        .header(http::header::COOKIE, cookie.as_str())
+        .send()
+        .await
+        .expect("Failed to send request");
+    assert_eq!(response.status().as_u16(), 404);
+}
+
+#[actix_rt::test]
+async fn hls_renditions_go_up_to_2160p() {
+    let rendition = |quality: &str| serde_json::from_value::<HlsRendition>(json!(quality)).unwrap();
+    assert_eq!(rendition("Q2160p"), HlsRendition::P2160);
+    assert_eq!(rendition("2160p"), HlsRendition::P2160);
+    assert_eq!(rendition("Q1080p"), HlsRendition::P1080);
+    assert_eq!(rendition("Q3D"), HlsRendition::P1080);
+
+    let names = |height| {
+        HlsRendition::for_source_height(height)
+            .iter()
+            .map(|rendition| rendition.name())
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(names(2160), vec!["480p", "720p", "1080p", "2160p"]);
+    assert_eq!(names(1080), vec!["480p", "720p", "1080p"]);
+}
+
+#[actix_rt::test]
+async fn stream_handles_standard_range_requests() {
+    let app = spawn_app().await;
+    let content: Vec<u8> = (0..=255u8).cycle().take(4096).collect();
+    app.torrent_client.register_magnet(
+        TEST_MAGNET,
+        vec![("movie.mp4".to_string(), content.clone())],
+    );
+    let session_id = create_session(app.address.as_str()).await;
+    start_download(&app, session_id.as_str()).await;
+    let client = reqwest::Client::new();
+    let stream_address = format!("{}/movies/stream/MovieDb/42/Q720p", app.address);
+
+    // no Range header gets the whole file
+    let response = client
+        .get(stream_address.as_str())
+        .header(http::header::COOKIE, format!("session={}", session_id))
+        .send()
+        .await
+        .expect("Failed to send request");
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(response.headers()[http::header::ACCEPT_RANGES], "bytes");
+    let etag = response.headers()[http::header::ETAG]
+        .to_str()
+        .unwrap()
+        .to_string();
+    let body = response.bytes().await.expect("Failed to read body");
+    assert_eq!(body.as_ref(), content.as_slice());
+
+    let response = client
+        .head(stream_address.as_str())
+        .header(http::header::COOKIE, format!("session={}", session_id))
+        .send()
+        .await
+        .expect("Failed to send request");
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(response.headers()[http::header::CONTENT_LENGTH], "4096");
+    assert!(response.bytes().await.unwrap().is_empty());
+
+    // suffix range
+    let response = client
+        .get(stream_address.as_str())
+        .header(http::header::COOKIE, format!("session={}", session_id))
+        .header(http::header::RANGE, "bytes=-96")
+        .send()
+        .await
+        .expect("Failed to send request");
+    assert_eq!(response.status().as_u16(), 206);
+    assert_eq!(
+        response.headers()[http::header::CONTENT_RANGE],
+        "bytes 4000-4095/4096"
+    );
+    assert_eq!(response.bytes().await.unwrap().as_ref(), &content[4000..]);
+
+    let response = client
+        .get(stream_address.as_str())
+        .header(http::header::COOKIE, format!("session={}", session_id))
+        .header(http::header::RANGE, "bytes=0-9,100-109")
+        .send()
+        .await
+        .expect("Failed to send request");
+    assert_eq!(response.status().as_u16(), 206);
+    let content_type = response.headers()[http::header::CONTENT_TYPE]
+        .to_str()
+        .unwrap()
+        .to_string();
+    assert!(content_type.starts_with("multipart/byteranges; boundary="));
+    let body = response.bytes().await.unwrap();
+    let body = String::from_utf8_lossy(&body);
+    assert!(body.contains("Content-Range: bytes 0-9/4096"));
+    assert!(body.contains("Content-Range: bytes 100-109/4096"));
+    assert!(body.ends_with(&format!(
+        "--{}--\r\n",
+        content_type.split("boundary=").nth(1).unwrap()
+    )));
+
+    let response = client
+        .get(stream_address.as_str())
+        .header(http::header::COOKIE, format!("session={}", session_id))
+        .header(http::header::RANGE, "bytes=5000-6000")
+        .send()
+        .await
+        .expect("Failed to send request");
+    assert_eq!(response.status().as_u16(), 416);
+    assert_eq!(
+        response.headers()[http::header::CONTENT_RANGE],
+        "bytes */4096"
+    );
+
+    // If-Range only honors the range while the validator still matches
+    let response = client
+        .get(stream_address.as_str())
+        .header(http::header::COOKIE, format!("session={}", session_id))
+        .header(http::header::RANGE, "bytes=0-9")
+        .header(http::header::IF_RANGE, etag.as_str())
+        .send()
+        .await
+        .expect("Failed to send request");
+    assert_eq!(response.status().as_u16(), 206);
+    let response = client
+        .get(stream_address.as_str())
+        .header(http::header::COOKIE, format!("session={}", session_id))
+        .header(http::header::RANGE, "bytes=0-9")
+        .header(http::header::IF_RANGE, "\"stale\"")
+        .send()
+        .await
+        .expect("Failed to send request");
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(response.bytes().await.unwrap().len(), 4096);
+}
+
+#[actix_rt::test]
+async fn disk_quota_evicts_least_recently_watched_movies() {
+    let other_magnet =
+        "magnet:?xt=urn:btih:0b2e4d3bc8e8e5b7c4f1f3f0f36c1d2a8d0e9a11&dn=Other+Movie";
+    let app = spawn_app().await;
+    app.torrent_client
+        .register_magnet(TEST_MAGNET, vec![("movie.mp4".to_string(), vec![0; 4096])]);
+    app.torrent_client
+        .register_magnet(other_magnet, vec![("other.mp4".to_string(), vec![0; 4096])]);
+    let session_id = create_session(app.address.as_str()).await;
+    start_movie_download(&app, session_id.as_str(), "42", TEST_MAGNET).await;
+    start_movie_download(&app, session_id.as_str(), "43", other_magnet).await;
+    sqlx::query(
+        "UPDATE movie_torrent SET last_watched_at = NOW() - INTERVAL '1 day' WHERE movie_id = '42'",
+    )
+    .execute(&app.db_pool)
+    .await
+    .expect("Failed to update movie");
+
+    let quota = DiskQuota::new(Some(10_000));
+    let active_streams = ActiveStreams::new();
+    quota
+        .make_room(
+            4096,
+            &app.db_pool,
+            app.torrent_client.as_ref(),
+            &active_streams,
+        )
+        .await
+        .expect("Failed to make room");
+    let movies: Vec<String> = sqlx::query("SELECT movie_id FROM movie_torrent")
+        .fetch_all(&app.db_pool)
+        .await
+        .expect("Failed to list movies")
+        .iter()
+        .map(|row| row.get("movie_id"))
+        .collect();
+    assert_eq!(movies, vec!["43".to_string()]);
+    assert_eq!(app.torrent_client.torrent_ids().len(), 1);
+
+    // a movie that is being watched stays even if the download can't fit
+    active_streams
+        .touch(CronJobScheduler::build_job_id("43".to_string(), Source::MovieDb).as_str());
+    let res = quota
+        .make_room(
+            8192,
+            &app.db_pool,
+            app.torrent_client.as_ref(),
+            &active_streams,
+        )
+        .await;
+    assert!(matches!(res, Err(QuotaError::InsufficientSpace { .. })));
+    assert_eq!(app.torrent_client.torrent_ids().len(), 1);
+}
+
+#[actix_rt::test]
+async fn concurrent_downloads_of_a_movie_start_one_torrent() {
+    let app = spawn_app().await;
+    app.torrent_client
+        .register_magnet(TEST_MAGNET, vec![("movie.mp4".to_string(), vec![0; 4096])]);
+    add_catalog_torrent(&app, "42", TEST_MAGNET).await;
+    let session_id = create_session(app.address.as_str()).await;
+    let client = reqwest::Client::new();
+    let start = || {
+        client
+            .post(format!("{}/movies/torrent", app.address))
+            .header(http::header::COOKIE, format!("session={}", session_id))
+            .json(&json!({
+                "movie_id": "42",
+                "source": "MovieDb",
+                "quality": "Q720p",
+            }))
+            .send()
+    };
+
+    let (first, second) = futures_util::join!(start(), start());
+    let mut statuses = vec![
+        first.expect("Failed to send request").status().as_u16(),
+        second.expect("Failed to send request").status().as_u16(),
+    ];
+    statuses.sort();
+    // the second click waits on the first, or finds its torrent already done
+    assert_eq!(statuses[0], 200);
+    assert!([200, 202].contains(&statuses[1]), "{:?}", statuses);
+
+    let torrent_id = app.torrent_client.torrent_ids()[0];
+    app.torrent_client.set_progress(torrent_id, 1024);
+    let response = start().await.expect("Failed to send request");
+    assert_eq!(response.status().as_u16(), 202);
+    let body: serde_json::Value = response.json().await.expect("Failed to read body");
+    assert_eq!(body["message"], "Download already in progress");
+
+    app.torrent_client.set_progress(torrent_id, 4096);
+    let response = start().await.expect("Failed to send request");
+    assert_eq!(response.status().as_u16(), 200);
+    let body: serde_json::Value = response.json().await.expect("Failed to read body");
+    assert_eq!(body["message"], "Movie already downloaded");
+
+    let row = sqlx::query("SELECT COUNT(*) AS count FROM movie_torrent")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to count movies");
+    assert_eq!(row.get::<i64, &str>("count"), 1);
+    assert_eq!(app.torrent_client.torrent_ids().len(), 1);
+}
+
+#[actix_rt::test]
+async fn download_builds_the_magnet_from_the_catalog() {
+    let app = spawn_app().await;
+    app.torrent_client
+        .register_magnet(TEST_MAGNET, vec![("movie.mp4".to_string(), vec![0; 4096])]);
+    let session_id = create_session(app.address.as_str()).await;
+    let client = reqwest::Client::new();
+    let start = |movie_id: &'static str, quality: &'static str| {
+        client
+            .post(format!("{}/movies/torrent", app.address))
+            .header(http::header::COOKIE, format!("session={}", session_id))
+            .json(&json!({
+                "movie_id": movie_id,
+                "source": "MovieDb",
+                "quality": quality,
+                "magnet_url": "magnet:?xt=urn:btih:0b2e4d3bc8e8e5b7c4f1f3f0f36c1d2a8d0e9a11",
+            }))
+            .send()
+    };
+
+    // client supplied magnets are ignored, the movie has to be in the catalog
+    let response = start("42", "Q720p").await.expect("Failed to send request");
+    assert_eq!(response.status().as_u16(), 404);
+
+    add_catalog_torrent(&app, "42", TEST_MAGNET).await;
+    let response = start("42", "Q1080p").await.expect("Failed to send request");
+    assert_eq!(response.status().as_u16(), 404);
+    assert!(app.torrent_client.torrent_ids().is_empty());
+
+    let response = start("42", "Q720p").await.expect("Failed to send request");
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(app.torrent_client.torrent_ids().len(), 1);
+}
+
+#[actix_rt::test]
+async fn multi_file_torrent_downloads_only_the_feature_and_subtitles() {
+    let app = spawn_app().await;
+    let feature: Vec<u8> = (0..=255u8).cycle().take(8192).collect();
+    app.torrent_client.register_magnet(
+        TEST_MAGNET,
+        vec![
+            ("Sample/sample.mkv".to_string(), vec![1; 1024]),
+            ("Extras/making.of.mkv".to_string(), vec![2; 16384]),
+            ("Test.Movie.2024.720p.mkv".to_string(), feature.clone()),
+            ("Subs/2_English.srt".to_string(), b"1\n".to_vec()),
+            ("notes.txt".to_string(), b"notes".to_vec()),
+        ],
+    );
+    let session_id = create_session(app.address.as_str()).await;
+    start_download(&app, session_id.as_str()).await;
+
+    let row = sqlx::query("SELECT movie_path, file_index, file_name, file_size FROM movie_torrent")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to get movie");
+    let movie_path: String = row.get("movie_path");
+    assert!(movie_path.ends_with("/Test.Movie.2024.720p.mkv"));
+    assert_eq!(row.get::<i32, &str>("file_index"), 2);
+    assert_eq!(
+        row.get::<String, &str>("file_name"),
+        "Test.Movie.2024.720p.mkv"
+    );
+    assert_eq!(row.get::<i64, &str>("file_size"), 8192);
+
+    let folder = std::path::Path::new(movie_path.as_str()).parent().unwrap();
+    assert!(folder.join("Subs/2_English.srt").exists());
+    assert!(!folder.join("Sample/sample.mkv").exists());
+    assert!(!folder.join("Extras/making.of.mkv").exists());
+    assert!(!folder.join("notes.txt").exists());
+
+    let response = reqwest::Client::new()
+        .get(format!("{}/movies/stream/MovieDb/42/Q720p", app.address))
+        .header(http::header::COOKIE, format!("session={}", session_id))
+        .header(http::header::RANGE, "bytes=0-99")
+        .send()
+        .await
+        .expect("Failed to send request");
+    assert_eq!(response.status().as_u16(), 206);
+    let body = response.bytes().await.expect("Failed to read body");
+    assert_eq!(body.as_ref(), &feature[..100]);
+}
+
+#[actix_rt::test]
+async fn sidecar_subtitles_are_listed_with_their_language() {
+    let app = spawn_app().await;
+    let srt = "1\n00:00:01,000 --> 00:00:02,500\nHello\n";
+    app.torrent_client.register_magnet(
+        TEST_MAGNET,
+        vec![
+            ("Test.Movie.2024.720p.mp4".to_string(), vec![0; 4096]),
+            ("Subs/2_English.srt".to_string(), srt.as_bytes().to_vec()),
+            (
+                "Test.Movie.2024.720p.fr.srt".to_string(),
+                srt.as_bytes().to_vec(),
+            ),
+        ],
+    );
+    let session_id = create_session(app.address.as_str()).await;
+    start_download(&app, session_id.as_str()).await;
+
+    let client = reqwest::Client::new();
+    let response = client
+        .get(format!(
+            "{}/subtitles/search/tt42?source=MovieDb&movie_id=42",
+            app.address
+        ))
+        .header(http::header::COOKIE, format!("session={}", session_id))
+        .send()
+        .await
+        .expect("Failed to send request");
+    assert!(response.status().is_success());
+    let body: serde_json::Value = response.json().await.expect("Failed to read body");
+    let bundled = body["bundled"].as_array().expect("No bundled subtitles");
+    let mut languages: Vec<&str> = bundled
+        .iter()
+        .map(|sub| sub["language"].as_str().unwrap())
+        .collect();
+    languages.sort();
+    assert_eq!(languages, vec!["en", "fr"]);
+
+    let english = bundled.iter().find(|sub| sub["language"] == "en").unwrap();
+    assert_eq!(english["language_name"], "English");
+    let response = client
+        .get(format!(
+            "{}{}",
+            app.address,
+            english["url"].as_str().unwrap()
+        ))
+        .header(http::header::COOKIE, format!("session={}", session_id))
+        .send()
+        .await
+        .expect("Failed to send request");
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(
+        response.headers()[http::header::CONTENT_TYPE],
+        "text/vtt; charset=utf-8"
+    );
+    let vtt = response.text().await.expect("Failed to read body");
+    assert!(vtt.starts_with("WEBVTT"));
+    assert!(vtt.contains("00:00:01.000 --> 00:00:02.500"));
+}
+
+#[actix_rt::test]
+async fn embedded_subtitle_tracks_are_served_as_webvtt() {
+    let app = spawn_app().await;
+    let session_id = create_session(app.address.as_str()).await;
+
+    // lay out a track the way the transcode worker extracts it
+    let subtitles_dir = std::env::temp_dir()
+        .join(format!("subs_{}", app.database_settings.db_name))
+        .join("subtitles");
+    std::fs::create_dir_all(&subtitles_dir).expect("Failed to create subtitles dir");
+    let track = subtitles_dir.join("2.vtt");
+    std::fs::write(&track, "WEBVTT\n\n00:00:01.000 --> 00:00:02.500\nBonjour\n")
+        .expect("Failed to write track");
+    sqlx::query(
+        r#"
+            INSERT INTO embedded_subtitles (id, movie_source, movie_id, stream_index, language, title, codec, path)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+        "#,
+    )
+    .bind(uuid::Uuid::new_v4())
+    .bind(Source::MovieDb)
+    .bind("42")
+    .bind(2)
+    .bind("fr")
+    .bind("Forced")
+    .bind("subrip")
+    .bind(track.to_str().unwrap())
+    .execute(&app.db_pool)
+    .await
+    .expect("Failed to add embedded subtitle");
+
+    let client = reqwest::Client::new();
+    let response = client
+        .get(format!(
+            "{}/subtitles/search/tt42?source=MovieDb&movie_id=42",
+            app.address
+        ))
+        .header(http::header::COOKIE, format!("session={}", session_id))
+        .send()
+        .await
+        .expect("Failed to send request");
+    assert!(response.status().is_success());
+    let body: serde_json::Value = response.json().await.expect("Failed to read body");
+    let bundled = body["bundled"].as_array().expect("No bundled subtitles");
+    assert_eq!(bundled.len(), 1);
+    let french = &bundled[0];
+    assert_eq!(french["kind"], "embedded");
+    assert_eq!(french["language_name"], "French");
+    assert_eq!(french["title"], "Forced");
+    assert_eq!(french["url"], "/subtitles/embedded/MovieDb/42/2");
+
+    let response = client
+        .get(format!("{}/subtitles/embedded/MovieDb/42/2", app.address))
+        .header(http::header::COOKIE, format!("session={}", session_id))
+        .send()
+        .await
+        .expect("Failed to send request");
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(
+        response.headers()[http::header::CONTENT_TYPE],
+        "text/vtt; charset=utf-8"
+    );
+    let vtt = response.text().await.expect("Failed to read body");
+    assert!(vtt.contains("Bonjour"));
+
+    let response = client
+        .get(format!("{}/subtitles/embedded/MovieDb/42/3", app.address))
+        .header(http::header::COOKIE, format!("session={}", session_id))
+        .send()
+        .await
+        .expect("Failed to send request");
+    assert_eq!(response.status().as_u16(), 404);
+}
+
+fn media_probe(video_codec: &str, audio_codec: &str) -> MediaProbe {
+    MediaProbe {
+        container: "matroska,webm".to_string(),
+        duration: Some(5400.0),
+        bit_rate: Some(2_500_000),
+        video_codec: video_codec.to_string(),
+        video_profile: None,
+        pixel_format: Some("yuv420p".to_string()),
+        width: 1280,
+        height: 720,
+        audio_tracks: vec![AudioTrack {
+            index: 1,
+            codec: audio_codec.to_string(),
+            channels: Some(2),
+            language: Some("eng".to_string()),
+            title: None,
+        }],
+    }
+}
+
+#[actix_rt::test]
+async fn playback_mode_follows_browser_compatibility() {
+    assert_eq!(
+        playback_mode(&media_probe("h264", "aac"), "mp4"),
+        PlaybackMode::DirectPlay
+    );
+    assert_eq!(
+        playback_mode(&media_probe("vp9", "opus"), "webm"),
+        PlaybackMode::DirectPlay
+    );
+    // browser codecs in a container browsers don't open
+    assert_eq!(
+        playback_mode(&media_probe("h264", "aac"), "mkv"),
+        PlaybackMode::Remux
+    );
+    assert_eq!(
+        playback_mode(&media_probe("hevc", "aac"), "mp4"),
+        PlaybackMode::Transcode
+    );
+    assert_eq!(
+        playback_mode(&media_probe("h264", "dts"), "mkv"),
+        PlaybackMode::Transcode
+    );
+    let mut ten_bit = media_probe("h264", "aac");
+    ten_bit.pixel_format = Some("yuv420p10le".to_string());
+    assert_eq!(playback_mode(&ten_bit, "mp4"), PlaybackMode::Transcode);
+
+    // before the probe ran the extension decides whether to convert live
+    assert_eq!(live_mode(None, "mp4"), None);
+    assert_eq!(live_mode(None, "mkv"), Some(PlaybackMode::Transcode));
+    assert_eq!(
+        live_mode(Some(PlaybackMode::Remux), "mkv"),
+        Some(PlaybackMode::Remux)
+    );
+    assert_eq!(live_mode(Some(PlaybackMode::DirectPlay), "mkv"), None);
+}
+
+#[actix_rt::test]
+async fn movie_info_exposes_the_media_probe() {
+    let app = spawn_app().await;
+    app.torrent_client
+        .register_magnet(TEST_MAGNET, vec![("movie.mkv".to_string(), vec![0; 4096])]);
+    let session_id = create_session(app.address.as_str()).await;
+    start_download(&app, session_id.as_str()).await;
+
+    let client = reqwest::Client::new();
+    let movie_info = || async {
+        let response = client
+            .get(format!("{}/movies/42/MovieDb", app.address))
+            .header(http::header::COOKIE, format!("session={}", session_id))
+            .send()
+            .await
+            .expect("Failed to send request");
+        assert!(response.status().is_success());
+        response
+            .json::<serde_json::Value>()
+            .await
+            .expect("Failed to read body")
+    };
+    // not probed until the download finishes
+    assert!(movie_info().await["media"].is_null());
+
+    sqlx::query(
+        r#"
+            UPDATE movie_torrent SET container = 'matroska,webm', video_codec = 'h264', audio_codec = 'aac',
+                duration_seconds = 5400, width = 1280, height = 720, bit_rate = 2500000,
+                audio_tracks = '[{"index": 1, "codec": "aac", "channels": 2, "language": "eng", "title": null}]',
+                playback_mode = 'REMUX', probed_at = NOW()
+            WHERE movie_id = '42'
+        "#,
+    )
+    .execute(&app.db_pool)
+    .await
+    .expect("Failed to store probe");
+
+    let body = movie_info().await;
+    let media = &body["media"];
+    assert_eq!(media["video_codec"], "h264");
+    assert_eq!(media["height"], 720);
+    assert_eq!(media["duration"], 5400.0);
+    assert_eq!(media["playback_mode"], "remux");
+    assert_eq!(media["audio_tracks"][0]["language"], "eng");
+}
+
+#[actix_rt::test]
+async fn playback_progress_feeds_continue_watching_then_history() {
+    let app = spawn_app().await;
+    let session_id = create_session(app.address.as_str()).await;
+    let client = reqwest::Client::new();
+    let report = |position: f64| {
+        client
+            .post(format!("{}/movies/progress", app.address))
+            .header(http::header::COOKIE, format!("session={}", session_id))
+            .json(&json!({
+                "movie_id": "42",
+                "source": "MovieDb",
+                "position": position,
+                "duration": 6000.0,
+                "title": "Test Movie",
+                "poster_src": "https://example.com/poster.jpg",
+                "movie_imdb_code": "tt42",
+            }))
+            .send()
+    };
+    let continue_watching = || async {
+        client
+            .get(format!("{}/movies/continue", app.address))
+            .header(http::header::COOKIE, format!("session={}", session_id))
+            .send()
+            .await
+            .expect("Failed to send request")
+            .json::<serde_json::Value>()
+            .await
+            .expect("Failed to read body")
+    };
+
+    let response = report(600.0).await.expect("Failed to send request");
+    assert!(response.status().is_success());
+    let body: serde_json::Value = response.json().await.expect("Failed to read body");
+    assert_eq!(body["data"]["watched"], false);
+
+    let movies = continue_watching().await;
+    assert_eq!(movies["data"].as_array().unwrap().len(), 1);
+    assert_eq!(movies["data"][0]["movie_id"], "42");
+    assert_eq!(movies["data"][0]["source"], "MovieDb");
+    assert_eq!(movies["data"][0]["progress"], 10.0);
+
+    let response = client
+        .get(format!("{}/movies/progress/MovieDb/42", app.address))
+        .header(http::header::COOKIE, format!("session={}", session_id))
+        .send()
+        .await
+        .expect("Failed to send request");
+    assert!(response.status().is_success());
+    let body: serde_json::Value = response.json().await.expect("Failed to read body");
+    assert_eq!(body["data"]["position"], 600.0);
+
+    // past 90% the movie counts as watched
+    let response = report(5500.0).await.expect("Failed to send request");
+    let body: serde_json::Value = response.json().await.expect("Failed to read body");
+    assert_eq!(body["data"]["watched"], true);
+    assert!(continue_watching().await["data"]
+        .as_array()
+        .unwrap()
+        .is_empty());
+
+    let history: serde_json::Value = client
+        .get(format!("{}/movies/history", app.address))
+        .header(http::header::COOKIE, format!("session={}", session_id))
+        .send()
+        .await
+        .expect("Failed to send request")
+        .json()
+        .await
+        .expect("Failed to read body");
+    assert_eq!(history.as_array().unwrap().len(), 1);
+    assert_eq!(history[0]["movie_id"], "42");
+}
+
+#[actix_rt::test]
+async fn stream_requires_a_signed_token_or_session() {
+    let app = spawn_app().await;
+    let content: Vec<u8> = (0..=255u8).cycle().take(4096).collect();
+    app.torrent_client.register_magnet(
+        TEST_MAGNET,
+        vec![("movie.mp4".to_string(), content.clone())],
+    );
+    let session_id = create_session(app.address.as_str()).await;
+    start_download(&app, session_id.as_str()).await;
+    let client = reqwest::Client::new();
+    let stream_address = format!("{}/movies/stream/MovieDb/42/Q720p", app.address);
+
+    let response = client
+        .get(stream_address.as_str())
+        .send()
+        .await
+        .expect("Failed to send request");
+    assert_eq!(response.status().as_u16(), 401);
+
+    let response = client
+        .get(format!(
+            "{}/movies/stream-token/MovieDb/42/Q720p",
+            app.address
+        ))
+        .header(http::header::COOKIE, format!("session={}", session_id))
+        .send()
+        .await
+        .expect("Failed to send request");
+    assert!(response.status().is_success());
+    let body: serde_json::Value = response.json().await.expect("Failed to read body");
+    let url = body["data"]["url"].as_str().unwrap().to_string();
+    let token = body["data"]["token"].as_str().unwrap().to_string();
+
+    // the signed link works without cookies
+    let response = client
+        .get(format!("{}{}", app.address, url))
+        .header(http::header::RANGE, "bytes=0-99")
+        .send()
+        .await
+        .expect("Failed to send request");
+    assert_eq!(response.status().as_u16(), 206);
+    assert_eq!(response.bytes().await.unwrap().as_ref(), &content[..100]);
+
+    // the token only opens the quality it was issued for
+    let response = client
+        .get(format!(
+            "{}/movies/stream/MovieDb/42/Q1080p?token={}",
+            app.address, token
+        ))
+        .send()
+        .await
+        .expect("Failed to send request");
+    assert_eq!(response.status().as_u16(), 403);
+
+    let mut tampered = token.clone();
+    let last = tampered.pop().unwrap();
+    tampered.push(if last == '0' { '1' } else { '0' });
+    let response = client
+        .get(format!("{}?token={}", stream_address, tampered))
+        .send()
+        .await
+        .expect("Failed to send request");
+    assert_eq!(response.status().as_u16(), 403);
+
+    let user_id = uuid::Uuid::new_v4();
+    let expired = StreamTokens::new("secret", chrono::Duration::seconds(-1));
+    let (token, _) = expired.issue(user_id, &Source::MovieDb, "42", MovieQuality::Q720p);
+    assert_eq!(
+        expired.verify(token.as_str(), &Source::MovieDb, "42", MovieQuality::Q720p),
+        Err(StreamTokenError::Expired)
+    );
+    let valid = StreamTokens::new("secret", chrono::Duration::hours(1));
+    let (token, _) = valid.issue(user_id, &Source::MovieDb, "42", MovieQuality::Q720p);
+    assert_eq!(
+        valid.verify(token.as_str(), &Source::MovieDb, "42", MovieQuality::Q720p),
+        Ok(user_id)
+    );
+    assert_eq!(
+        valid.verify(token.as_str(), &Source::MovieDb, "43", MovieQuality::Q720p),
+        Err(StreamTokenError::InvalidSignature)
+    );
+}
+
+#[actix_rt::test]
+async fn stream_limits_cap_the_movies_a_user_streams_at_once() {
+    let limits = actix_web::web::Data::new(StreamLimits::new(2, None));
+    let user = uuid::Uuid::new_v4();
+    let first = StreamLimits::acquire(&limits, user, "1_YTS".to_string()).unwrap();
+    let _second = StreamLimits::acquire(&limits, user, "2_YTS".to_string()).unwrap();
+    // more range requests for a movie already playing are the same stream
+    let _seek = StreamLimits::acquire(&limits, user, "1_YTS".to_string()).unwrap();
+    assert_eq!(
+        StreamLimits::acquire(&limits, user, "3_YTS".to_string()).err(),
+        Some(StreamLimitError::TooManyStreams { max: 2 })
+    );
+    // other users have their own streams
+    assert!(StreamLimits::acquire(&limits, uuid::Uuid::new_v4(), "3_YTS".to_string()).is_ok());
+    // a movie stays counted for a while after its last response
+    drop(first);
+    assert!(StreamLimits::acquire(&limits, user, "3_YTS".to_string()).is_err());
+}
+
+#[actix_rt::test]
+async fn token_bucket_throttles_the_response_body() {
+    use futures_util::StreamExt;
+
+    let chunks: Vec<Result<actix_web::web::Bytes, std::io::Error>> = (0..8)
+        .map(|_| Ok(actix_web::web::Bytes::from(vec![0u8; 64 * 1024])))
+        .collect();
+    let started = std::time::Instant::now();
+    let body = throttle(
+        futures_util::stream::iter(chunks),
+        Some(TokenBucket::new(256 * 1024)),
+    );
+    let sent: usize = body
+        .map(|chunk| chunk.unwrap().len())
+        .collect::<Vec<_>>()
+        .await
+        .iter()
+        .sum();
+
+    assert_eq!(sent, 512 * 1024);
+    // the first second of bandwidth is a burst, the rest is paced
+    assert!(started.elapsed() >= std::time::Duration::from_millis(900));
+}
+
+#[actix_rt::test]
+async fn admin_torrents_scope_manages_the_seedbox() {
+    let app = spawn_app().await;
+    app.torrent_client.register_magnet(
+        TEST_MAGNET,
+        vec![
+            ("movie.mkv".to_string(), vec![0; 4096]),
+            ("sample.mkv".to_string(), vec![0; 64]),
+        ],
+    );
+    let session_id = create_session(app.address.as_str()).await;
+    let cookie = format!("session={}", session_id);
+    let client = reqwest::Client::new();
+    start_download(&app, session_id.as_str()).await;
+    let torrent_id = app.torrent_client.torrent_ids()[0];
+
+    let response = client
+        .get(format!("{}/admin/torrents", app.address))
+        .header(http::header::COOKIE, cookie.as_str())
+        .send()
+        .await
+        .expect("Failed to send request");
+    assert_eq!(response.status().as_u16(), 403);
+
+    make_admin(&app).await;
+    let base = format!("{}/admin/torrents/MovieDb/42", app.address);
+    for action in ["pause", "resume", "reannounce"] {
+        let response = client
+            .post(format!("{}/{}", base, action))
+            .header(http::header::COOKIE, cookie.as_str())
+            .send()
+            .await
+            .expect("Failed to send request");
+        assert!(response.status().is_success(), "{} failed", action);
+    }
+    assert_eq!(app.torrent_client.reannounced(), vec![torrent_id]);
+
+    let response = client
+        .post(format!("{}/pause", base))
+        .header(http::header::COOKIE, cookie.as_str())
+        .send()
+        .await
+        .expect("Failed to send request");
+    assert!(response.status().is_success());
+    let body: serde_json::Value = client
+        .get(format!("{}/admin/torrents", app.address))
+        .header(http::header::COOKIE, cookie.as_str())
+        .send()
+        .await
+        .expect("Failed to send request")
+        .json()
+        .await
+        .expect("Failed to read body");
+    let torrents = body["data"].as_array().unwrap();
+    assert_eq!(torrents.len(), 1);
+    assert_eq!(torrents[0]["movie_id"], "42");
+    assert_eq!(torrents[0]["torrent_id"], torrent_id);
+    assert_eq!(torrents[0]["status"]["state"], "paused");
+
+    // the movie file can't be dropped from the download
+    let files: serde_json::Value = client
+        .get(format!("{}/files", base))
+        .header(http::header::COOKIE, cookie.as_str())
+        .send()
+        .await
+        .expect("Failed to send request")
+        .json()
+        .await
+        .expect("Failed to read body");
+    let movie_index = files["data"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|file| file["name"] == "movie.mkv")
+        .unwrap()["index"]
+        .as_u64()
+        .unwrap();
+    let response = client
+        .put(format!("{}/files", base))
+        .header(http::header::COOKIE, cookie.as_str())
+        .json(&json!({ "only_files": [1 - movie_index] }))
+        .send()
+        .await
+        .expect("Failed to send request");
+    assert_eq!(response.status().as_u16(), 400);
+    let response = client
+        .put(format!("{}/files", base))
+        .header(http::header::COOKIE, cookie.as_str())
+        .json(&json!({ "only_files": [movie_index] }))
+        .send()
+        .await
+        .expect("Failed to send request");
+    assert!(response.status().is_success());
+
+    // gone from rqbit already, the row and the files still go
+    app.torrent_client
+        .delete_torrent(torrent_id, String::new())
+        .await
+        .unwrap();
+    // but never a folder outside the download root
+    let download_root = app.torrent_client.download_path();
+    let outside = download_root.with_file_name(format!(
+        "{}_outside",
+        download_root.file_name().unwrap().to_string_lossy()
+    ));
+    std::fs::create_dir_all(&outside).unwrap();
+    sqlx::query("UPDATE movie_torrent SET movie_path = $1")
+        .bind(format!(
+            "../{}/movie.mkv",
+            outside.file_name().unwrap().to_string_lossy()
+        ))
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+    let response = client
+        .delete(base.as_str())
+        .header(http::header::COOKIE, cookie.as_str())
+        .send()
+        .await
+        .expect("Failed to send request");
+    assert!(response.status().is_success());
+    let remaining = sqlx::query("SELECT COUNT(*) AS count FROM movie_torrent")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(remaining.get::<i64, &str>("count"), 0);
+    assert!(outside.exists());
+    std::fs::remove_dir_all(&outside).unwrap();
+}
+
+#[actix_rt::test]
+async fn reconciliation_fixes_rows_torrents_and_folders_that_drifted_apart() {
+    let app = spawn_app().await;
+    let orphan_magnet = "magnet:?xt=urn:btih:1111111111111111111111111111111111111111&dn=Orphan";
+    let stray_magnet = "magnet:?xt=urn:btih:2222222222222222222222222222222222222222&dn=Stray";
+    for magnet in [TEST_MAGNET, orphan_magnet, stray_magnet] {
+        app.torrent_client
+            .register_magnet(magnet, vec![("movie.mp4".to_string(), vec![0; 1024])]);
+    }
+    let session_id = create_session(app.address.as_str()).await;
+    make_admin(&app).await;
+    start_download(&app, session_id.as_str()).await;
+    let root = app.torrent_client.download_path();
+
+    // the server died between starting the torrent and inserting its row
+    let orphan = app
+        .torrent_client
+        .add_torrent(
+            orphan_magnet.to_string(),
+            Some("43_MovieDb_2026-10-17".to_string()),
+            None,
+        )
+        .await
+        .unwrap();
+    let stray = app
+        .torrent_client
+        .add_torrent(stray_magnet.to_string(), Some("by_hand".to_string()), None)
+        .await
+        .unwrap();
+    // a deletion that removed the row but not the folder
+    std::fs::create_dir_all(root.join("7_YTS_2026-01-01")).unwrap();
+    std::fs::write(root.join("7_YTS_2026-01-01/movie.mp4"), b"left over").unwrap();
+    std::fs::create_dir_all(root.join("notes")).unwrap();
+    // the movie's torrent and files are gone but its row isn't
+    let torrent_id = sqlx::query("SELECT torrent_id FROM movie_torrent WHERE movie_id = '42'")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+        .get::<i32, &str>("torrent_id");
+    app.torrent_client
+        .delete_torrent(torrent_id, String::new())
+        .await
+        .unwrap();
+
+    let body: serde_json::Value = reqwest::Client::new()
+        .post(format!("{}/admin/torrents/reconcile", app.address))
+        .header(http::header::COOKIE, format!("session={}", session_id))
+        .send()
+        .await
+        .expect("Failed to send request")
+        .json()
+        .await
+        .expect("Failed to read body");
+    let report = &body["data"];
+    assert_eq!(report["adopted"], json!(["43_MovieDb"]));
+    assert_eq!(
+        report["removed_torrents"],
+        json!([stray.id.parse::<i32>().unwrap()])
+    );
+    assert_eq!(report["missing"], json!(["42_MovieDb"]));
+    assert_eq!(report["errors"], json!([]));
+    assert!(!root.join("7_YTS_2026-01-01").exists());
+    assert!(root.join("notes").exists());
+    assert!(root.join("43_MovieDb_2026-10-17").exists());
+
+    let adopted =
+        sqlx::query("SELECT torrent_id, file_name FROM movie_torrent WHERE movie_id = '43'")
+            .fetch_one(&app.db_pool)
+            .await
+            .unwrap();
+    assert_eq!(
+        adopted.get::<i32, &str>("torrent_id"),
+        orphan.id.parse::<i32>().unwrap()
+    );
+    assert_eq!(adopted.get::<String, &str>("file_name"), "movie.mp4");
+    // probed and packaged like a movie started from the site
+    let jobs = sqlx::query("SELECT id FROM transcode_jobs WHERE movie_id = '43'")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(jobs.len(), 1);
+    let missing = sqlx::query("SELECT missing_since FROM movie_torrent WHERE movie_id = '42'")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert!(missing
+        .get::<Option<chrono::DateTime<chrono::Utc>>, &str>("missing_since")
+        .is_some());
+
+    // nothing left to fix the second time
+    let body: serde_json::Value = reqwest::Client::new()
+        .post(format!("{}/admin/torrents/reconcile", app.address))
+        .header(http::header::COOKIE, format!("session={}", session_id))
+        .send()
+        .await
+        .expect("Failed to send request")
+        .json()
+        .await
+        .expect("Failed to read body");
+    assert_eq!(body["data"]["adopted"], json!([]));
+    assert_eq!(body["data"]["removed_dirs"], json!([]));
+    assert_eq!(body["data"]["missing"], json!([]));
+}
+
+fn catalog_torrent(hash: &str, quality: MovieQuality, seeds: Option<u32>) -> CatalogTorrent {
+    CatalogTorrent {
+        info_hash: hash.to_string(),
+        name: None,
+        quality: Some(quality),
+        seeds,
+        peers: seeds.map(|seeds| seeds * 2),
+        rank: 0,
+    }
+}
+
+#[actix_rt::test]
+async fn torrent_choice_prefers_the_healthiest_seeded_torrent() {
+    let torrents = vec![
+        catalog_torrent("a", MovieQuality::Q720p, Some(3)),
+        catalog_torrent("b", MovieQuality::Q720p, Some(40)),
+        catalog_torrent("c", MovieQuality::Q1080p, Some(0)),
+        catalog_torrent("d", MovieQuality::Q2160p, Some(12)),
+        catalog_torrent("e", MovieQuality::Q3D, None),
+    ];
+    let pick = |quality| choose_torrent(&torrents, quality).map(|torrent| torrent.info_hash);
+
+    assert_eq!(pick(MovieQuality::Q720p), Some("b".to_string()));
+    // nobody seeds the 1080p release
+    assert_eq!(pick(MovieQuality::Q1080p), Some("b".to_string()));
+    // without counts there's nothing to fall back on
+    assert_eq!(pick(MovieQuality::Q3D), Some("e".to_string()));
+
+    let dead = vec![catalog_torrent("c", MovieQuality::Q1080p, Some(0))];
+    assert_eq!(
+        choose_torrent(&dead, MovieQuality::Q1080p).map(|torrent| torrent.info_hash),
+        Some("c".to_string())
+    );
+    assert!(choose_torrent(&dead, MovieQuality::Q720p).is_none());
+}
+
+#[actix_rt::test]
+async fn release_names_are_parsed_into_their_parts() {
+    let release = ReleaseName::parse(
+        "Blade.Runner.2049.2017.2160p.UHD.BluRay.HDR.x265.DDP5.1.MULTI-GROUP.mkv",
+    );
+    assert_eq!(release.title, "Blade Runner 2049");
+    assert_eq!(release.year, Some(2017));
+    assert_eq!(release.resolution.as_deref(), Some("2160p"));
+    assert_eq!(release.source, Some(ReleaseSource::BluRay));
+    assert_eq!(release.codec.as_deref(), Some("h265"));
+    assert_eq!(release.audio.as_deref(), Some("DDP5.1"));
+    assert_eq!(release.language.as_deref(), Some("MULTI"));
+    assert_eq!(release.group.as_deref(), Some("GROUP"));
+    assert!(release.hdr);
+    assert_eq!(release.quality(), Some(MovieQuality::Q2160p));
+
+    let release = ReleaseName::parse(
+        "[YTS.MX] Spider-Man Into the Spider-Verse (2018) [720p] [WEB-DL] H.264 AAC",
+    );
+    assert_eq!(release.title, "Spider-Man Into the Spider-Verse");
+    assert_eq!(release.year, Some(2018));
+    assert_eq!(release.source, Some(ReleaseSource::Web));
+    assert_eq!(release.codec.as_deref(), Some("h264"));
+    assert_eq!(release.group, None);
+    assert_eq!(release.quality(), Some(MovieQuality::Q720p));
+
+    let release = ReleaseName::parse("1917.2019.1080p.HDCAM.x264-RIPPER");
+    assert_eq!(release.title, "1917");
+    assert_eq!(release.year, Some(2019));
+    assert!(release.is_cam_rip());
+    assert!(ReleaseName::parse("Some Movie 2024 HD-TS 720p").is_cam_rip());
+    assert!(!ReleaseName::parse("Cam.2018.1080p.WEBRip").is_cam_rip());
+
+    let release = ReleaseName::parse("Avatar.2009.1080p.3D.HSBS.BluRay");
+    assert!(release.three_d);
+    assert_eq!(release.quality(), Some(MovieQuality::Q3D));
+    assert_eq!(ReleaseName::parse("Some Movie").quality(), None);
+}
+
+#[actix_rt::test]
+async fn movie_db_torrents_are_labeled_ranked_and_cam_rips_dropped() {
+    let torrents = vec![
+        json!({ "title": "Test.Movie.2024.1080p.HDCAM.x264", "hash": "a", "seeders": 900 }),
+        json!({ "title": "Test.Movie.2024.1080p.WEBRip.x265", "hash": "b", "seeders": 60 }),
+        json!({ "title": "Test.Movie.2024.1080p.BluRay.x264-GRP", "hash": "c", "seeders": 8 }),
+        json!({ "title": "Test.Movie.2024.1080p.Remux.x264", "hash": "d", "seeders": 2 }),
+        json!({ "title": "Test Movie 2024 720p WEB", "hash": "e", "seeders": 3 }),
+    ];
+    let catalog = movie_db_catalog_torrents(&torrents, None);
+    let hashes: Vec<&str> = catalog
+        .iter()
+        .map(|torrent| torrent.info_hash.as_str())
+        .collect();
+    assert_eq!(hashes, vec!["b", "c", "d", "e"]);
+    assert_eq!(catalog[3].quality, Some(MovieQuality::Q720p));
+
+    // the better release wins among the well seeded ones, the barely seeded
+    // remux doesn't
+    let pick = |quality| choose_torrent(&catalog, quality).map(|torrent| torrent.info_hash);
+    assert_eq!(pick(MovieQuality::Q1080p), Some("c".to_string()));
+    assert_eq!(pick(MovieQuality::Q720p), Some("e".to_string()));
+}
+
+#[actix_rt::test]
+async fn torrent_choice_never_prefers_a_dead_release_of_the_requested_quality() {
+    let mut bluray = catalog_torrent("bluray", MovieQuality::Q1080p, Some(0));
+    bluray.rank = 50;
+    let mut web = catalog_torrent("web", MovieQuality::Q1080p, Some(3));
+    web.rank = 40;
+    let torrents = vec![
+        bluray,
+        web,
+        catalog_torrent("other", MovieQuality::Q720p, Some(90)),
+    ];
+    assert_eq!(
+        choose_torrent(&torrents, MovieQuality::Q1080p).map(|torrent| torrent.info_hash),
+        Some("web".to_string())
+    );
+}
+
+#[actix_rt::test]
+async fn download_falls_back_from_a_dead_quality_and_movie_info_shows_health() {
+    let app = spawn_app().await;
+    let dead_magnet = "magnet:?xt=urn:btih:3333333333333333333333333333333333333333&dn=Dead";
+    let dead = Magnet::parse(dead_magnet).unwrap();
+    let alive = Magnet::parse(TEST_MAGNET).unwrap();
+    app.torrent_client
+        .register_magnet(TEST_MAGNET, vec![("movie.mp4".to_string(), vec![0; 1024])]);
+    sqlx::query(
+        r#"
+            INSERT INTO imdb_movie_details (id, primary_title, torrents)
+            VALUES ($1, $2, ARRAY[$3::json, $4::json])
+        "#,
+    )
+    .bind("42")
+    .bind("Test Movie")
+    .bind(json!({
+        "title": "Test.Movie.2024.720p.WEBRip",
+        "quality": "720p",
+        "hash": dead.info_hash(),
+        "seeders": "0",
+        "leechers": "4",
+    }))
+    .bind(json!({
+        "title": "Test.Movie.2024.1080p.BluRay",
+        "hash": alive.info_hash(),
+        "seeders": 25,
+        "leechers": 7,
+    }))
+    .execute(&app.db_pool)
+    .await
+    .expect("Failed to add catalog torrents");
+    let session_id = create_session(app.address.as_str()).await;
+    let client = reqwest::Client::new();
+
+    let response = client
+        .post(format!("{}/movies/torrent", app.address))
+        .header(http::header::COOKIE, format!("session={}", session_id))
+        .json(&json!({
+            "movie_id": "42",
+            "source": "MovieDb",
+            "quality": "Q720p",
+        }))
+        .send()
+        .await
+        .expect("Failed to send request");
+    assert!(response.status().is_success());
+    let body: serde_json::Value = response.json().await.expect("Failed to read body");
+    assert_eq!(body["data"]["quality"], "1080p");
+    assert_eq!(body["data"]["requested_quality"], "720p");
+    assert_eq!(app.torrent_client.torrent_ids().len(), 1);
+
+    let body: serde_json::Value = client
+        .get(format!("{}/movies/42/MovieDb", app.address))
+        .header(http::header::COOKIE, format!("session={}", session_id))
+        .send()
+        .await
+        .expect("Failed to send request")
+        .json()
+        .await
+        .expect("Failed to read body");
+    assert_eq!(
+        body["torrent_health"],
+        json!([
+            { "quality": "720p", "seeds": 0, "peers": 4, "available": false },
+            { "quality": "1080p", "seeds": 25, "peers": 7, "available": true },
+        ])
+    );
+}
+
+// a Torznab api serving `feed` to searches made with `api_key`
+fn spawn_torznab_indexer(api_key: &'static str, feed: &str) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").expect("Failed to bind");
+    let port = listener.local_addr().unwrap().port();
+    let feed = feed.to_string();
+    let server = HttpServer::new(move || {
+        let feed = feed.clone();
+        App::new().route(
+            "/api",
+            web::get().to(move |query: web::Query<HashMap<String, String>>| {
+                let feed = feed.clone();
+                async move {
+                    let param = |name: &str| query.get(name).map(String::as_str);
+                    if param("apikey") != Some(api_key) {
+                        return HttpResponse::Ok().body(
+                            r#"<?xml version="1.0" encoding="UTF-8"?><error code="100" description="Invalid API Key"/>"#,
+                        );
+                    }
+                    assert_eq!(param("t"), Some("search"));
+                    assert_eq!(param("q"), Some("The Matrix"));
+                    assert_eq!(param("cat"), Some("2000"));
+                    HttpResponse::Ok()
+                        .content_type("application/rss+xml")
+                        .body(feed)
+                }
+            }),
+        )
+    })
+    .workers(1)
+    .listen(listener)
+    .expect("Failed to listen")
+    .run();
+    tokio::spawn(server);
+    format!("http://127.0.0.1:{}/api?apikey={}", port, api_key)
+}
+
+fn torznab_feed(items: &str) -> String {
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<rss version="2.0" xmlns:torznab="http://torznab.com/schemas/2015/feed">
+  <channel>
+    <title>Stub indexer</title>
+    {}
+  </channel>
+</rss>"#,
+        items
+    )
+}
+
+#[actix_rt::test]
+async fn torznab_indexers_are_searched_merged_and_deduplicated() {
+    let first = spawn_torznab_indexer(
+        "first-key",
+        torznab_feed(
+            r#"
+    <item>
+      <title>The.Matrix.1999.1080p.BluRay.x264-GRP</title>
+      <guid>https://indexer/details/1</guid>
+      <link>https://indexer/download/1.torrent</link>
+      <size>2147483648</size>
+      <torznab:attr name="seeders" value="50"/>
+      <torznab:attr name="peers" value="60"/>
+      <torznab:attr name="infohash" value="1111111111111111111111111111111111111111"/>
+      <torznab:attr name="imdbid" value="0133093"/>
+    </item>
+    <item>
+      <title><![CDATA[The Matrix 1999 720p WEBRip]]></title>
+      <link>magnet:?xt=urn:btih:2222222222222222222222222222222222222222&amp;dn=The+Matrix</link>
+      <torznab:attr name="seeders" value="10"/>
+      <torznab:attr name="peers" value="12"/>
+    </item>
+    <item>
+      <title>The.Matrix.1999.480p.DVDRip</title>
+      <enclosure url="https://indexer/download/3.torrent" type="application/x-bittorrent"/>
+    </item>"#,
+        )
+        .as_str(),
+    );
+    let second = spawn_torznab_indexer(
+        "second-key",
+        torznab_feed(
+            r#"
+    <item>
+      <title>The Matrix (1999) [1080p]</title>
+      <enclosure url="https://other/download/9.torrent" length="2147483648" type="application/x-bittorrent"></enclosure>
+      <torznab:attr name="seeders" value="80"/>
+      <torznab:attr name="peers" value="85"/>
+      <torznab:attr name="infohash" value="1111111111111111111111111111111111111111"/>
+    </item>
+    <item>
+      <title>The.Matrix.1999.2160p.UHD.BluRay.HDR.x265-GRP</title>
+      <torznab:attr name="seeders" value="5"/>
+      <torznab:attr name="peers" value="5"/>
+      <torznab:attr name="infohash" value="3333333333333333333333333333333333333333"/>
+      <torznab:attr name="imdbid" value="tt0133093"/>
+    </item>"#,
+        )
+        .as_str(),
+    );
+    let broken = spawn_torznab_indexer("right-key", "").replace("right-key", "wrong-key");
+
+    let indexers = TorznabIndexers::new(vec![first, second, broken.clone()]);
+    let torrents = indexers.search("The Matrix").await.expect("Search failed");
+    let hashes: Vec<&str> = torrents
+        .iter()
+        .map(|torrent| torrent.info_hash.as_str())
+        .collect();
+    assert_eq!(
+        hashes,
+        vec![
+            "1111111111111111111111111111111111111111",
+            "2222222222222222222222222222222222222222",
+            "3333333333333333333333333333333333333333",
+        ]
+    );
+
+    // the best seeded listing wins, the other fills in the imdb id
+    let merged = &torrents[0];
+    assert_eq!(merged.title, "The Matrix (1999) [1080p]");
+    assert_eq!(merged.seeders, Some(80));
+    assert_eq!(merged.leechers, Some(10));
+    assert_eq!(merged.imdb.as_deref(), Some("tt0133093"));
+    assert_eq!(merged.size, Some(2147483648));
+    assert_eq!(merged.quality, Some(MovieQuality::Q1080p));
+    assert_eq!(merged.indexers.len(), 2);
+
+    assert_eq!(torrents[1].title, "The Matrix 1999 720p WEBRip");
+    assert_eq!(
+        torrents[1].magnet.as_deref(),
+        Some("magnet:?xt=urn:btih:2222222222222222222222222222222222222222&dn=The+Matrix")
+    );
+    assert_eq!(torrents[1].quality, Some(MovieQuality::Q720p));
+    assert_eq!(torrents[2].quality, Some(MovieQuality::Q2160p));
+
+    // stored with the movie details, the catalog reads them back
+    let values: Vec<serde_json::Value> =
+        torrents.iter().map(|torrent| torrent.to_value()).collect();
+    assert_eq!(
+        values[0]["hash"],
+        "1111111111111111111111111111111111111111"
+    );
+    assert_eq!(values[0]["imdb"], "tt0133093");
+    let catalog = movie_db_catalog_torrents(&values, None);
+    assert_eq!(catalog[0].quality, Some(MovieQuality::Q1080p));
+    assert_eq!(catalog[0].seeds, Some(80));
+    assert_eq!(catalog[1].peers, Some(2));
+
+    let err = TorznabIndexers::new(vec![broken])
+        .search("The Matrix")
+        .await
+        .unwrap_err();
+    assert!(err.contains("Invalid API Key"), "{}", err);
+    assert!(!err.contains("wrong-key"), "{}", err);
+    assert_eq!(
+        TorznabIndexers::new(Vec::new()).search("The Matrix").await,
+        Err("No torrent indexer configured".to_string())
+    );
+}
+
+#[actix_rt::test]
+async fn rapidapi_indexer_searches_torrents_when_no_torznab_indexer_is_set() {
+    let listener = TcpListener::bind("127.0.0.1:0").expect("Failed to bind");
+    let port = listener.local_addr().unwrap().port();
+    let server = HttpServer::new(|| {
+        App::new().route(
+            "/search/{query}",
+            web::get().to(
+                |query: web::Path<String>, req: actix_web::HttpRequest| async move {
+                    if req
+                        .headers()
+                        .get("x-rapidapi-key")
+                        .map(|key| key.as_bytes())
+                        != Some(&b"token"[..])
+                    {
+                        return HttpResponse::TooManyRequests().finish();
+                    }
+                    assert_eq!(query.as_str(), "The Matrix");
+                    HttpResponse::Ok().json(json!({
+                        "data": [{
+                            "title": "The Matrix 1999 1080p BluRay",
+                            "hash": "1111111111111111111111111111111111111111",
+                            "seeders": 12,
+                        }]
+                    }))
+                },
+            ),
+        )
+    })
+    .workers(1)
+    .listen(listener)
+    .expect("Failed to listen")
+    .run();
+    tokio::spawn(server);
+    let origin = format!("http://127.0.0.1:{}", port);
+
+    assert!(!TorznabIndexers::new(Vec::new()).is_configured());
+    let indexer: std::sync::Arc<dyn TorrentIndexer> = std::sync::Arc::new(RapidApiIndexer::new(
+        origin.as_str(),
+        "torrents.p.rapidapi.com",
+        "token",
+    ));
+    let torrents = indexer
+        .search_torrents("The Matrix")
+        .await
+        .expect("Search failed");
+    assert_eq!(torrents.len(), 1);
+    assert_eq!(
+        torrents[0]["hash"],
+        "1111111111111111111111111111111111111111"
+    );
+    assert_eq!(
+        movie_db_catalog_torrents(&torrents, None)[0].quality,
+        Some(MovieQuality::Q1080p)
+    );
+
+    let over_quota = RapidApiIndexer::new(origin.as_str(), "torrents.p.rapidapi.com", "wrong");
+    assert_eq!(
+        over_quota.search_torrents("The Matrix").await,
+        Err("you exceeded your daily QUOTA".to_string())
+    );
+}
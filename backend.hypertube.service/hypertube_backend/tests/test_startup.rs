@@ -1,6 +1,8 @@
 use hypertube_backend::configuration::{get_configuration, Settings};
+use hypertube_backend::routes::movies::torrent::InMemoryTorrentClient;
 use sqlx::{Connection, Executor, PgConnection, PgPool};
 use std::net::TcpListener;
+use std::sync::Arc;
 use tokio::task;
 use uuid::Uuid;
 
@@ -17,6 +19,7 @@ pub struct TestApp {
     pub address: String,
     pub db_pool: PgPool,
     pub database_settings: TestDatabaseSettings,
+    pub torrent_client: Arc<InMemoryTorrentClient>,
 }
 
 impl Drop for TestApp {
@@ -96,8 +99,15 @@ pub async fn spawn_app() -> TestApp {
         .run(&connection_pool)
         .await
         .unwrap();
-    let server = hypertube_backend::startup::run_server(listener, connection_pool.clone())
-        .expect("Failed to bind address");
+    let torrent_client = Arc::new(InMemoryTorrentClient::new(std::env::temp_dir().join(
+        format!("hypertube_{}", configuration.database.database_name),
+    )));
+    let server = hypertube_backend::startup::run_server(
+        listener,
+        connection_pool.clone(),
+        torrent_client.clone(),
+    )
+    .expect("Failed to bind address");
     let _ = tokio::spawn(server);
 
     TestApp {
@@ -110,5 +120,6 @@ pub async fn spawn_app() -> TestApp {
             password: configuration.database.password,
             parent_db_name,
         },
+        torrent_client,
     }
 }